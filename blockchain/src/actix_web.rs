@@ -1,52 +1,105 @@
+use crate::block_templates::{BlockTemplates, Submission, TemplateError};
+use crate::dev_miner::{DevMiner, DevMinerError};
 use crate::execution::Runnable;
+use crate::p2p::light_client::{LightClient, LightClientError};
+use crate::p2p::network::Network;
 use crate::types::block::Block;
+use crate::types::blockchain::BlockStatus;
 use crate::types::transaction::Transaction;
 use crate::{Blockchain, Context, TransactionPool};
-use actix_web::{web, App, HttpResponse, HttpServer, Responder};
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer, Responder};
+use log::warn;
+use serde::{Deserialize, Serialize};
 
 use anyhow::Result;
 
 struct ServerData {
     blockchain: Blockchain,
     pool: TransactionPool,
+    network: Network,
+    templates: BlockTemplates,
+    // Only set in dev mode
+    dev_miner: Option<DevMiner>,
+    admin_token: Option<String>,
+}
+
+#[derive(Serialize)]
+struct AccountInfo {
+    account: String,
+    balance: u64,
+    nonce: u64,
 }
 
 pub struct Server {
     port: u16,
+    admin_token: Option<String>,
     blockchain: Blockchain,
     pool: TransactionPool,
+    network: Network,
+    templates: BlockTemplates,
+    dev_miner: Option<DevMiner>,
+}
+
+// Optional body of `POST /dev/mine`
+#[derive(Deserialize)]
+struct DevMineRequest {
+    timestamp: Option<i64>,
 }
 
 impl Runnable for Server {
     fn run(&self) -> Result<()> {
-        let server_blockchain = self.blockchain.clone();
-        let server_transaction_pool = self.pool.clone();
+        // These variables are really "Arc" pointers to a shared memory value
+        // So when we clone them, we are only cloning the pointers and not the actual data
+        let server_data = ServerData {
+            blockchain: self.blockchain.clone(),
+            pool: self.pool.clone(),
+            network: self.network.clone(),
+            templates: self.templates.clone(),
+            dev_miner: self.dev_miner.clone(),
+            admin_token: self.admin_token.clone(),
+        };
 
-        start_blockchain_server(self.port, server_blockchain, server_transaction_pool)
+        start_blockchain_server(self.port, server_data)
     }
 }
 
 impl Server {
-    pub fn new(context: &Context) -> Server {
+    // The dev miner, given in dev mode only, enables `POST /dev/mine`
+    pub fn new(context: &Context, dev_miner: Option<DevMiner>) -> Server {
         Server {
             port: context.config.port,
+            admin_token: context.config.admin_token.clone(),
             blockchain: context.blockchain.clone(),
             pool: context.pool.clone(),
+            network: context.network.clone(),
+            templates: BlockTemplates::new(context),
+            dev_miner,
         }
     }
 }
 
+// API of a light client, which only knows the headers of the chain
+pub struct LightServer {
+    port: u16,
+    client: LightClient,
+}
+
+impl Runnable for LightServer {
+    fn run(&self) -> Result<()> {
+        start_light_server(self.port, self.client.clone())
+    }
+}
+
+impl LightServer {
+    pub fn new(port: u16, client: LightClient) -> LightServer {
+        LightServer { port, client }
+    }
+}
 
 #[actix_web::main]
-async fn start_blockchain_server(
-    port: u16,
-    blockchain: Blockchain,
-    pool: TransactionPool,
-) -> Result<()> {
+async fn start_blockchain_server(port: u16, server_data: ServerData) -> Result<()> {
     let url = format!("localhost:{}", port);
-    // These variables are really "Arc" pointers to a shared memory value
-    // So when we clone them, we are only cloning the pointers and not the actual data
-    let server_data = web::Data::new(ServerData { blockchain, pool });
+    let server_data = web::Data::new(server_data);
 
     HttpServer::new(move || {
         App::new()
@@ -54,11 +107,35 @@ async fn start_blockchain_server(
             .route("/blocks", web::get().to(get_blocks))
             .route("/blocks", web::post().to(add_block))
             .route("/blocks/get/{index}", web::get().to(get_block_by_index))
+            .route("/accounts/{id}", web::get().to(get_account))
             .route("/tx/pool", web::get().to(get_transactions))
-            .route(
-                "/tx/new/{from}/{to}/{amount}",
-                web::get().to(add_transaction),
-            )
+            .route("/tx/new", web::post().to(add_transaction))
+            .route("/tx/{hash}/proof", web::get().to(get_transaction_proof))
+            .route("/admin/tx/pool/flush", web::post().to(flush_transactions))
+            .route("/peers", web::get().to(get_peers))
+            .route("/sync/status", web::get().to(get_sync_status))
+            .route("/mining/template", web::get().to(get_block_template))
+            .route("/mining/submit", web::post().to(submit_block_template))
+            .route("/dev/mine", web::post().to(dev_mine))
+    })
+        .bind(url)
+        .unwrap()
+        .run()
+        .await?;
+
+    Ok(())
+}
+
+#[actix_web::main]
+async fn start_light_server(port: u16, client: LightClient) -> Result<()> {
+    let url = format!("localhost:{}", port);
+    let client = web::Data::new(client);
+
+    HttpServer::new(move || {
+        App::new()
+            .app_data(client.clone())
+            .route("/headers", web::get().to(get_headers))
+            .route("/tx/{hash}/proof", web::get().to(get_verified_proof))
     })
         .bind(url)
         .unwrap()
@@ -78,44 +155,201 @@ async fn get_blocks(state: web::Data<ServerData>) -> impl Responder {
 async fn get_block_by_index(state: web::Data<ServerData>, index: web::Path<u64>) -> impl Responder {
     let blockchain = &state.blockchain;
 
-    HttpResponse::Ok().json(&blockchain.get_block_by_index(index.into_inner()))
+    HttpResponse::Ok().json(blockchain.get_block_by_index(index.into_inner()))
 }
 
+// Returns the balance and the next nonce of an account at the current tip of the blockchain
+async fn get_account(state: web::Data<ServerData>, id: web::Path<String>) -> impl Responder {
+    let account = id.into_inner();
+    let (balance, nonce) = state.blockchain.with_world_state(|world_state| {
+        (world_state.get_balance(&account), world_state.get_nonce(&account))
+    });
+
+    HttpResponse::Ok().json(AccountInfo {
+        balance,
+        nonce,
+        account,
+    })
+}
+
+// Returns the pending transactions without removing them from the pool
 async fn get_transactions(state: web::Data<ServerData>) -> impl Responder {
-    let transactions = &state.pool.pop();
-    HttpResponse::Ok().json(&transactions)
+    HttpResponse::Ok().json(state.pool.snapshot())
+}
+
+// Returns the header of the main chain block including a transaction
+// and the Merkle path from the transaction to the transactions root of that header
+async fn get_transaction_proof(state: web::Data<ServerData>, hash: web::Path<String>) -> impl Responder {
+    match state.blockchain.get_transaction_proof(&hash.into_inner()) {
+        Some(proof) => HttpResponse::Ok().json(proof),
+        None => HttpResponse::NotFound().body("transaction is not in the main chain"),
+    }
+}
+
+// Returns the connected peers and their known heights
+async fn get_peers(state: web::Data<ServerData>) -> impl Responder {
+    HttpResponse::Ok().json(state.network.get_peers())
+}
+
+// Returns a block on the current tip for an external miner to find the nonce of
+async fn get_block_template(state: web::Data<ServerData>) -> impl Responder {
+    match state.templates.create_template() {
+        Ok(template) => HttpResponse::Ok().json(template),
+        Err(error) => HttpResponse::NotFound().body(error.to_string()),
+    }
+}
+
+// Adds the block of a template with the nonce found by an external miner
+// A template made before the last change of the tip is rejected as stale
+async fn submit_block_template(
+    state: web::Data<ServerData>,
+    submission_json: web::Json<Submission>,
+) -> impl Responder {
+    let templates = state.templates.clone();
+    let submission = submission_json.into_inner();
+    // adding the block can reorganize the chain, which must not hold up the server
+    let result = web::block(move || templates.submit(submission)).await;
+
+    match result {
+        Ok(Ok(block)) => HttpResponse::Ok().json(block.header),
+        Ok(Err(error @ TemplateError::UnknownTemplate(_))) => {
+            HttpResponse::NotFound().body(error.to_string())
+        }
+        Ok(Err(error @ TemplateError::StaleTemplate(_))) => {
+            HttpResponse::Conflict().body(error.to_string())
+        }
+        Ok(Err(error)) => HttpResponse::BadRequest().body(error.to_string()),
+        Err(error) => HttpResponse::InternalServerError().body(error.to_string()),
+    }
+}
+
+// Seals a block right away in dev mode, with the pending transactions if there are any
+// and the timestamp of the body if one is given
+async fn dev_mine(
+    state: web::Data<ServerData>,
+    request_json: Option<web::Json<DevMineRequest>>,
+) -> impl Responder {
+    let dev_miner = match state.dev_miner.clone() {
+        Some(dev_miner) => dev_miner,
+        None => return HttpResponse::NotFound().body("dev mode is disabled"),
+    };
+    let timestamp = request_json.and_then(|request| request.timestamp);
+    let result = web::block(move || dev_miner.seal_block(timestamp)).await;
+
+    match result {
+        Ok(Ok(block)) => HttpResponse::Ok().json(block.header),
+        Ok(Err(error @ DevMinerError::StaleTip)) => HttpResponse::Conflict().body(error.to_string()),
+        Ok(Err(error)) => HttpResponse::BadRequest().body(error.to_string()),
+        Err(error) => HttpResponse::InternalServerError().body(error.to_string()),
+    }
+}
+
+// Returns the progress of the download of blocks from a peer that is ahead
+async fn get_sync_status(state: web::Data<ServerData>) -> impl Responder {
+    HttpResponse::Ok().json(state.network.get_sync_status())
+}
+
+// Removes all pending transactions from the pool and returns them
+// Requires the admin token of the config as a bearer token, and is disabled without one
+async fn flush_transactions(state: web::Data<ServerData>, request: HttpRequest) -> impl Responder {
+    if !is_admin(&state, &request) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    HttpResponse::Ok().json(state.pool.pop())
+}
+
+fn is_admin(state: &ServerData, request: &HttpRequest) -> bool {
+    let admin_token = match &state.admin_token {
+        Some(admin_token) => admin_token,
+        None => return false,
+    };
+
+    request
+        .headers()
+        .get("Authorization")
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.strip_prefix("Bearer "))
+        .map(|token| constant_time_eq(token.as_bytes(), admin_token.as_bytes()))
+        .unwrap_or(false)
+}
+
+// Compares secrets without leaking through timing how many leading bytes match
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
 }
 
 // Adds a new block to the blockchain
 async fn add_block(state: web::Data<ServerData>, block_json: web::Json<Block>) -> impl Responder {
     let mut block = block_json.into_inner();
 
-    block.hash = block.calculate_hash();
+    block.header.hash = block.header.calculate_hash();
 
     let blockchain = &state.blockchain;
     let result = blockchain.add_block(block.clone());
 
     match result {
-        Ok(_) => {
+        Ok(status) => {
+            // the transactions of the block are no longer pending
+            blockchain.with_world_state(|world_state| {
+                state.pool.remove_executed(world_state);
+                if let BlockStatus::Reorganized(orphaned) = status {
+                    state.pool.add_orphaned(orphaned, world_state);
+                }
+            });
+            state.network.broadcast_block(&block);
             HttpResponse::Ok().finish()
         }
-        Err(error) => HttpResponse::BadRequest().body(error.to_string()),
+        Err(error) => HttpResponse::BadRequest().body(format!("rejected block: {}", error)),
     }
 }
 
+// Adds a new signed transaction to the pool
+// A rejected transaction gets the reason of the rejection in the body of the response
 async fn add_transaction(
     state: web::Data<ServerData>,
-    transaction: web::Path<(String, String, u64)>,
-) -> String {
-    let (sender, recipient, amount) = transaction.into_inner();
-
-    let transaction = Transaction {
-        sender,
-        recipient,
-        amount,
-    };
+    transaction_json: web::Json<Transaction>,
+) -> impl Responder {
+    let transaction = transaction_json.into_inner();
+
     let pool = &state.pool;
-    pool.add_transaction(transaction.clone());
+    let result = state
+        .blockchain
+        .with_world_state(|world_state| pool.add_transaction(transaction.clone(), world_state));
 
-    format!("new transaction {:?}!", transaction)
+    match result {
+        Ok(_) => {
+            state.network.broadcast_transaction(&transaction);
+            // in dev mode the transaction is in a block by the time the response is sent,
+            // sealing on the blocking thread pool so the worker keeps serving requests
+            if let Some(dev_miner) = state.dev_miner.clone() {
+                if let Ok(Err(error)) = web::block(move || dev_miner.seal_pending()).await {
+                    warn!("could not seal the new transaction: {}", error);
+                }
+            }
+            HttpResponse::Ok().body(format!("new transaction {:?}!", transaction))
+        }
+        Err(error) => HttpResponse::BadRequest().body(format!("rejected transaction: {}", error)),
+    }
+}
+
+// Returns the full nodes the light client is connected to and the tip of its headers
+async fn get_headers(client: web::Data<LightClient>) -> impl Responder {
+    HttpResponse::Ok().json(client.get_status())
+}
+
+// Fetches the proof of a transaction from the full nodes and checks it against the headers,
+// returning it with the number of confirmations of its block
+async fn get_verified_proof(client: web::Data<LightClient>, hash: web::Path<String>) -> impl Responder {
+    let hash = hash.into_inner();
+    let result = web::block(move || client.get_transaction_proof(&hash)).await;
+
+    match result {
+        Ok(Ok(verified)) => HttpResponse::Ok().json(verified),
+        Ok(Err(error @ (LightClientError::NotFound | LightClientError::NotOnMainChain(_)))) => {
+            HttpResponse::NotFound().body(error.to_string())
+        }
+        Ok(Err(error)) => HttpResponse::ServiceUnavailable().body(error.to_string()),
+        Err(error) => HttpResponse::InternalServerError().body(error.to_string()),
+    }
 }
@@ -0,0 +1,337 @@
+use crate::consensus::{ConsensusError, ConsensusKind};
+use crate::miner::create_next_block;
+use crate::p2p::network::Network;
+use crate::types::block::{Block, BlockHeader};
+use crate::types::blockchain::{BlockStatus, BlockchainError};
+use crate::types::target::CompactTarget;
+use crate::types::transaction::Address;
+use crate::{Blockchain, Context, TransactionPool};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+
+// Most templates kept at the same time, the oldest one is forgotten first
+const MAX_TEMPLATES: usize = 64;
+
+// Position of the nonce in the encoded header, after the version, the index and the timestamp
+pub const NONCE_OFFSET: usize = 4 + 8 + 8;
+
+#[derive(Error, Debug)]
+pub enum TemplateError {
+    #[error("template {0} is unknown or was replaced by a newer one")]
+    UnknownTemplate(u64),
+    #[error("template {0} does not build on the tip of the chain anymore")]
+    StaleTemplate(u64),
+    #[error("block templates need proof-of-work, this node uses {0:?}")]
+    UnsupportedConsensus(ConsensusKind),
+    #[error("invalid seal: {0}")]
+    InvalidSeal(#[from] ConsensusError),
+    #[error("block was rejected: {0}")]
+    Rejected(#[from] BlockchainError),
+}
+
+// Work handed out to an external miner: it has to find a nonce that makes the hash of the header,
+// encoded with the nonce little endian at `nonce_offset`, not exceed the target
+#[derive(Debug, Serialize)]
+pub struct BlockTemplate {
+    pub id: u64,
+    pub header: BlockHeader,
+    pub target: CompactTarget,
+    // Hex encoded header the block hash is computed from
+    pub encoded_header: String,
+    pub nonce_offset: usize,
+}
+
+// Solution of an external miner for a template
+#[derive(Debug, Deserialize)]
+pub struct Submission {
+    pub id: u64,
+    pub nonce: u64,
+}
+
+#[derive(Debug, Default)]
+struct TemplateState {
+    next_id: u64,
+    // Tip changes of the chain when the templates were made, they are all stale after another one
+    tip_changes: u64,
+    // Transactions added to the pool when the latest template was made
+    added: u64,
+    latest: Option<u64>,
+    blocks: BTreeMap<u64, Block>,
+}
+
+// Blocks built for external miners, tracked by id until they are solved or the tip changes
+// The blocks are built like the ones of the built-in miner, paying the configured miner address,
+// but their transactions stay in the pool until a solution is accepted
+#[derive(Debug, Clone)]
+pub struct BlockTemplates {
+    consensus: ConsensusKind,
+    miner_address: Address,
+    blockchain: Blockchain,
+    pool: TransactionPool,
+    network: Network,
+    state: Arc<Mutex<TemplateState>>,
+}
+
+impl BlockTemplates {
+    pub fn new(context: &Context) -> BlockTemplates {
+        BlockTemplates {
+            consensus: context.config.consensus,
+            miner_address: context.config.miner_address.clone(),
+            blockchain: context.blockchain.clone(),
+            pool: context.pool.clone(),
+            network: context.network.clone(),
+            state: Arc::default(),
+        }
+    }
+
+    // Builds a block on the current tip with the best pending transactions
+    // The latest template is handed out again until the tip changes or transactions enter the pool,
+    // so polling miners do not rebuild the same block over and over
+    // Only proof-of-work blocks can be completed with a nonce, other engines seal them on their own
+    pub fn create_template(&self) -> Result<BlockTemplate, TemplateError> {
+        if self.consensus != ConsensusKind::ProofOfWork {
+            return Err(TemplateError::UnsupportedConsensus(self.consensus));
+        }
+
+        let mut state = self.state.lock().unwrap();
+
+        // the tip is read first, so a block arriving after it makes the template stale
+        let tip_changes = self.blockchain.get_tip_changes();
+        if tip_changes != state.tip_changes {
+            state.blocks.clear();
+            state.tip_changes = tip_changes;
+        }
+
+        let added = self.pool.get_added();
+        if added == state.added {
+            let latest = state
+                .latest
+                .and_then(|id| state.blocks.get(&id).map(|block| (id, block.clone())));
+            if let Some((id, block)) = latest {
+                return Ok(to_template(id, block));
+            }
+        }
+
+        let last_block = self.blockchain.get_last_block();
+        let (max_transactions, max_size) = self
+            .blockchain
+            .get_pool_room(&self.miner_address, last_block.header.index + 1);
+        let transactions = self.blockchain.with_world_state(|world_state| {
+            self.pool.peek_best(world_state, max_transactions, max_size)
+        });
+        let target = self.blockchain.get_next_target(&last_block);
+        let block = create_next_block(
+            &self.blockchain,
+            &self.miner_address,
+            &last_block,
+            target,
+            transactions,
+            0,
+        );
+
+        let id = state.next_id;
+        state.next_id += 1;
+        if state.blocks.len() >= MAX_TEMPLATES {
+            state.blocks.pop_first();
+        }
+        state.blocks.insert(id, block.clone());
+        state.added = added;
+        state.latest = Some(id);
+
+        Ok(to_template(id, block))
+    }
+
+    // Completes a template with the nonce found by an external miner and adds the block to the chain,
+    // sending it to the peers like a block of the built-in miner
+    pub fn submit(&self, submission: Submission) -> Result<Block, TemplateError> {
+        let mut state = self.state.lock().unwrap();
+
+        if self.blockchain.get_tip_changes() != state.tip_changes {
+            state.blocks.clear();
+            return Err(TemplateError::StaleTemplate(submission.id));
+        }
+        let mut block = state
+            .blocks
+            .get(&submission.id)
+            .cloned()
+            .ok_or(TemplateError::UnknownTemplate(submission.id))?;
+
+        block.header.nonce = submission.nonce;
+        block.header.hash = block.header.calculate_hash();
+        self.blockchain.consensus.verify_seal(&block.header)?;
+
+        let status = self.blockchain.add_block(block.clone())?;
+        state.blocks.clear();
+        // the template only peeked at the pool, so the included transactions are still pending
+        self.blockchain.with_world_state(|world_state| {
+            self.pool.remove_executed(world_state);
+            if let BlockStatus::Reorganized(orphaned) = status {
+                self.pool.add_orphaned(orphaned, world_state);
+            }
+        });
+        self.network.broadcast_block(&block);
+
+        Ok(block)
+    }
+}
+
+fn to_template(id: u64, block: Block) -> BlockTemplate {
+    BlockTemplate {
+        id,
+        encoded_header: hex::encode(block.header.encode()),
+        target: block.header.target,
+        header: block.header,
+        nonce_offset: NONCE_OFFSET,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::hash::Hash256;
+    use crate::types::transaction::Transaction;
+    use crate::util::test_helpers::{create_signing_key, meets_target, TestNode, EASY_TARGET};
+
+    fn create_templates() -> BlockTemplates {
+        let node = TestNode::builder().target(EASY_TARGET).build();
+
+        BlockTemplates {
+            consensus: ConsensusKind::ProofOfWork,
+            miner_address: "miner".to_string(),
+            blockchain: node.blockchain,
+            pool: node.pool,
+            network: node.network,
+            state: Arc::default(),
+        }
+    }
+
+    // Finds a nonce the way an external miner would, from the encoded header only
+    fn solve(template: &BlockTemplate) -> u64 {
+        let mut header = hex::decode(&template.encoded_header).unwrap();
+        (0..)
+            .find(|nonce: &u64| {
+                header[template.nonce_offset..template.nonce_offset + 8]
+                    .copy_from_slice(&nonce.to_le_bytes());
+                meets_target(&Hash256::digest(&header), template.target)
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn solved_template_extends_the_chain() {
+        let templates = create_templates();
+        let transaction = Transaction::new_signed(&create_signing_key(1), "bob".to_string(), 3, 1, 0);
+        let state = templates.blockchain.get_world_state();
+        templates.pool.add_transaction(transaction.clone(), &state).unwrap();
+
+        let template = templates.create_template().unwrap();
+        assert_eq!(template.header.index, 1);
+        // the transactions stay in the pool until the template is solved
+        assert_eq!(templates.pool.snapshot().count, 1);
+
+        let nonce = solve(&template);
+        let block = templates
+            .submit(Submission {
+                id: template.id,
+                nonce,
+            })
+            .unwrap();
+        assert_eq!(block.transactions[1].hash(), transaction.hash());
+        assert_eq!(templates.blockchain.get_last_block().header.hash, block.header.hash);
+        assert_eq!(templates.pool.snapshot().count, 0);
+    }
+
+    #[test]
+    fn template_is_rebuilt_only_when_the_tip_or_the_pool_changes() {
+        let templates = create_templates();
+        let first = templates.create_template().unwrap();
+        assert_eq!(templates.create_template().unwrap().id, first.id);
+
+        let transaction = Transaction::new_signed(&create_signing_key(1), "bob".to_string(), 3, 1, 0);
+        let state = templates.blockchain.get_world_state();
+        templates.pool.add_transaction(transaction, &state).unwrap();
+        let second = templates.create_template().unwrap();
+        assert_ne!(second.id, first.id);
+        assert_ne!(second.header.transactions_root, first.header.transactions_root);
+
+        let nonce = solve(&second);
+        templates
+            .submit(Submission {
+                id: second.id,
+                nonce,
+            })
+            .unwrap();
+        assert_eq!(templates.create_template().unwrap().header.index, 2);
+    }
+
+    #[test]
+    fn templates_are_invalidated_by_a_new_tip() {
+        let templates = create_templates();
+        let first = templates.create_template().unwrap();
+        let transaction = Transaction::new_signed(&create_signing_key(1), "bob".to_string(), 3, 1, 0);
+        let state = templates.blockchain.get_world_state();
+        templates.pool.add_transaction(transaction, &state).unwrap();
+        let second = templates.create_template().unwrap();
+        let nonce = solve(&second);
+        templates
+            .submit(Submission {
+                id: second.id,
+                nonce,
+            })
+            .unwrap();
+
+        let result = templates.submit(Submission {
+            id: first.id,
+            nonce: solve(&first),
+        });
+        assert!(matches!(result, Err(TemplateError::StaleTemplate(_))));
+
+        let template = templates.create_template().unwrap();
+        assert_eq!(template.header.index, 2);
+        assert!(matches!(
+            templates.submit(Submission {
+                id: template.id + 1,
+                nonce: 0,
+            }),
+            Err(TemplateError::UnknownTemplate(_))
+        ));
+    }
+
+    #[test]
+    fn nonce_not_meeting_the_target_is_rejected() {
+        let templates = create_templates();
+        let template = templates.create_template().unwrap();
+
+        // with one hash in 16 meeting the target, one of the first nonces fails it
+        let mut header = template.header.clone();
+        let nonce = (0..)
+            .find(|nonce: &u64| {
+                header.nonce = *nonce;
+                !meets_target(&header.calculate_hash(), template.target)
+            })
+            .unwrap();
+        let result = templates.submit(Submission {
+            id: template.id,
+            nonce,
+        });
+        assert!(matches!(
+            result,
+            Err(TemplateError::InvalidSeal(ConsensusError::TargetNotMet))
+        ));
+    }
+
+    #[test]
+    fn templates_need_proof_of_work() {
+        let templates = BlockTemplates {
+            consensus: ConsensusKind::ProofOfAuthority,
+            ..create_templates()
+        };
+
+        assert!(matches!(
+            templates.create_template(),
+            Err(TemplateError::UnsupportedConsensus(ConsensusKind::ProofOfAuthority))
+        ));
+    }
+}
@@ -0,0 +1,83 @@
+pub(crate) mod proof_of_authority;
+pub(crate) mod proof_of_work;
+
+use crate::types::block::{Block, BlockHeader};
+use crate::types::target::{CompactTarget, Work};
+use crate::types::transaction::PublicKey;
+use crate::util::config::Config;
+use proof_of_authority::ProofOfAuthority;
+use proof_of_work::ProofOfWork;
+use serde::Deserialize;
+use std::fmt::Debug;
+use std::sync::Arc;
+use thiserror::Error;
+
+pub type SyncedConsensus = Arc<dyn ConsensusEngine>;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ConsensusError {
+    #[error("hash does not meet the target")]
+    TargetNotMet,
+    #[error("target `{0:#010x}` can never be met")]
+    UnreachableTarget(CompactTarget),
+    #[error("proof-of-work blocks do not carry a seal")]
+    UnexpectedSeal,
+    #[error("block {0} is not sealed by the authority in turn")]
+    BadSeal(u64),
+    #[error("this node is not the authority in turn for block {0}")]
+    NotInTurn(u64),
+    #[error("proof-of-authority needs at least one authority")]
+    NoAuthorities,
+    #[error("malformed authority public key `{0}`")]
+    MalformedAuthority(PublicKey),
+    #[error("malformed authority key")]
+    MalformedAuthorityKey,
+    #[error("the authority key is not one of the authorities")]
+    UnknownAuthorityKey,
+}
+
+// Rules that decide who may produce a block and how the header proves it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConsensusKind {
+    // Blocks are found by hashing headers until one meets the target
+    #[default]
+    ProofOfWork,
+    // Blocks are signed in turn by a fixed list of authorities
+    ProofOfAuthority,
+}
+
+// Seals blocks and checks the seal of blocks from other nodes
+// The blockchain checks everything else about a header, the engine only the proof it carries,
+// and the work it returns is what the branches of the block tree are compared with
+pub trait ConsensusEngine: Debug + Send + Sync {
+    // Checks the proof of a header whose hash already matches its content,
+    // returning the work it adds to its branch
+    fn verify_seal(&self, header: &BlockHeader) -> Result<Work, ConsensusError>;
+
+    // Whether this node may seal the block at the given index
+    fn can_seal(&self, index: u64) -> bool;
+
+    // Completes the header of the block so that it passes `verify_seal`
+    // Returns `None` when `is_stale` tells that the block is not worth sealing anymore
+    fn seal(
+        &self,
+        block: Block,
+        is_stale: &(dyn Fn() -> bool + Sync),
+    ) -> Result<Option<Block>, ConsensusError>;
+}
+
+// Builds the engine selected in the configuration
+pub fn create_engine(config: &Config) -> Result<SyncedConsensus, ConsensusError> {
+    let engine: SyncedConsensus = match config.consensus {
+        ConsensusKind::ProofOfWork => {
+            Arc::new(ProofOfWork::new(config.max_nonce, config.mining_threads))
+        }
+        ConsensusKind::ProofOfAuthority => Arc::new(ProofOfAuthority::new(
+            &config.authorities,
+            config.authority_key.as_deref(),
+        )?),
+    };
+
+    Ok(engine)
+}
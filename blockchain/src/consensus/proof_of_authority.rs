@@ -0,0 +1,189 @@
+use crate::consensus::{ConsensusEngine, ConsensusError};
+use crate::types::block::{Block, BlockHeader};
+use crate::types::target::Work;
+use crate::types::transaction::PublicKey;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+
+// Blocks are sealed by a fixed list of authorities taking turns: the block at index `i` must be
+// signed by authority `i % authorities`, which puts the Ed25519 signature of the block hash
+// in the seal of the header
+// Every block adds the same work, so the longest chain is the main chain, and an authority
+// seals its blocks as soon as it has transactions, without any hashing
+#[derive(Debug)]
+pub struct ProofOfAuthority {
+    authorities: Vec<VerifyingKey>,
+    // Key of this node and its position among the authorities, if it is one of them
+    signing_key: Option<(SigningKey, usize)>,
+}
+
+impl ProofOfAuthority {
+    // Parses the hex encoded public keys of the authorities and the optional secret key of this node,
+    // which must belong to one of them
+    pub fn new(
+        authorities: &[PublicKey],
+        authority_key: Option<&str>,
+    ) -> Result<ProofOfAuthority, ConsensusError> {
+        if authorities.is_empty() {
+            return Err(ConsensusError::NoAuthorities);
+        }
+
+        let authorities = authorities
+            .iter()
+            .map(|public_key| {
+                hex::decode(public_key)
+                    .ok()
+                    .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+                    .and_then(|bytes| VerifyingKey::from_bytes(&bytes).ok())
+                    .ok_or_else(|| ConsensusError::MalformedAuthority(public_key.clone()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let signing_key = match authority_key {
+            Some(secret_key) => {
+                let signing_key = hex::decode(secret_key)
+                    .ok()
+                    .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+                    .map(|bytes| SigningKey::from_bytes(&bytes))
+                    .ok_or(ConsensusError::MalformedAuthorityKey)?;
+                let position = authorities
+                    .iter()
+                    .position(|authority| *authority == signing_key.verifying_key())
+                    .ok_or(ConsensusError::UnknownAuthorityKey)?;
+                Some((signing_key, position))
+            }
+            None => None,
+        };
+
+        Ok(ProofOfAuthority {
+            authorities,
+            signing_key,
+        })
+    }
+
+    fn authority_for(&self, index: u64) -> &VerifyingKey {
+        &self.authorities[(index % self.authorities.len() as u64) as usize]
+    }
+}
+
+impl ConsensusEngine for ProofOfAuthority {
+    fn verify_seal(&self, header: &BlockHeader) -> Result<Work, ConsensusError> {
+        let signature = hex::decode(&header.seal)
+            .ok()
+            .and_then(|bytes| Signature::from_slice(&bytes).ok())
+            .ok_or(ConsensusError::BadSeal(header.index))?;
+
+        self.authority_for(header.index)
+            .verify(&header.hash.0, &signature)
+            .map_err(|_| ConsensusError::BadSeal(header.index))?;
+
+        Ok(Work::from(1))
+    }
+
+    fn can_seal(&self, index: u64) -> bool {
+        self.signing_key.as_ref().is_some_and(|(_, position)| {
+            (index % self.authorities.len() as u64) as usize == *position
+        })
+    }
+
+    // Signing is instant, so the block is never stale by the time it is sealed
+    fn seal(
+        &self,
+        mut block: Block,
+        _is_stale: &(dyn Fn() -> bool + Sync),
+    ) -> Result<Option<Block>, ConsensusError> {
+        let (signing_key, _) = self
+            .signing_key
+            .as_ref()
+            .filter(|_| self.can_seal(block.header.index))
+            .ok_or(ConsensusError::NotInTurn(block.header.index))?;
+
+        block.header.hash = block.header.calculate_hash();
+        block.header.seal = hex::encode(signing_key.sign(&block.header.hash.0).to_bytes());
+
+        Ok(Some(block))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::BlockHash;
+    use crate::types::transaction::Transaction;
+    use crate::util::test_helpers::create_signing_key;
+
+    fn public_key_of(seed: u8) -> PublicKey {
+        hex::encode(create_signing_key(seed).verifying_key().as_bytes())
+    }
+
+    // Authorities 1 and 2, this node holding the key of the given one
+    fn create_engine(seed: u8) -> ProofOfAuthority {
+        let secret_key = hex::encode(create_signing_key(seed).to_bytes());
+        ProofOfAuthority::new(&[public_key_of(1), public_key_of(2)], Some(&secret_key)).unwrap()
+    }
+
+    fn create_block(index: u64) -> Block {
+        let coinbase = Transaction::new_coinbase("authority".to_string(), 50, index);
+        Block::new(index, 0, 0, Some(BlockHash::default()), vec![coinbase])
+    }
+
+    #[test]
+    fn authorities_seal_in_turn() {
+        let first = create_engine(1);
+        let second = create_engine(2);
+        assert!(!first.can_seal(1) && second.can_seal(1));
+        assert!(first.can_seal(2) && !second.can_seal(2));
+
+        let block = second.seal(create_block(1), &|| false).unwrap().unwrap();
+        assert_eq!(first.verify_seal(&block.header), Ok(Work::from(1)));
+        assert_eq!(
+            first.seal(create_block(1), &|| false).map(|block| block.is_some()),
+            Err(ConsensusError::NotInTurn(1))
+        );
+
+        // signatures are deterministic, so sealing the same block again gives the same header
+        let mut unsealed = block.clone();
+        unsealed.header.seal = String::new();
+        let again = second.seal(unsealed, &|| false).unwrap().unwrap();
+        assert_eq!(again.header.seal, block.header.seal);
+    }
+
+    #[test]
+    fn block_sealed_by_another_key_is_rejected() {
+        let engine = create_engine(1);
+
+        // a valid authority signing out of turn
+        let mut block = create_block(1);
+        block.header.seal = hex::encode(create_signing_key(1).sign(&block.header.hash.0).to_bytes());
+        assert_eq!(engine.verify_seal(&block.header), Err(ConsensusError::BadSeal(1)));
+
+        // a seal that does not sign this header
+        let mut block = create_engine(2).seal(create_block(1), &|| false).unwrap().unwrap();
+        block.header.timestamp += 1;
+        block.header.hash = block.header.calculate_hash();
+        assert_eq!(engine.verify_seal(&block.header), Err(ConsensusError::BadSeal(1)));
+
+        block.header.seal = String::new();
+        assert_eq!(engine.verify_seal(&block.header), Err(ConsensusError::BadSeal(1)));
+    }
+
+    #[test]
+    fn configuration_is_checked() {
+        assert_eq!(
+            ProofOfAuthority::new(&[], None).map(|_| ()),
+            Err(ConsensusError::NoAuthorities)
+        );
+        assert_eq!(
+            ProofOfAuthority::new(&["00".to_string()], None).map(|_| ()),
+            Err(ConsensusError::MalformedAuthority("00".to_string()))
+        );
+        let outsider = hex::encode(create_signing_key(3).to_bytes());
+        assert_eq!(
+            ProofOfAuthority::new(&[public_key_of(1)], Some(&outsider)).map(|_| ()),
+            Err(ConsensusError::UnknownAuthorityKey)
+        );
+
+        // a node without a key follows the chain without sealing
+        let follower = ProofOfAuthority::new(&[public_key_of(1)], None).unwrap();
+        assert!(!follower.can_seal(0));
+    }
+}
@@ -0,0 +1,243 @@
+use crate::consensus::{ConsensusEngine, ConsensusError};
+use crate::types::block::{Block, BlockHeader};
+use crate::types::merkle::transactions_root;
+use crate::types::target::{Target, Work};
+use crossbeam::thread;
+use log::debug;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+// Blocks are sealed by finding a nonce that makes the hash of the header, read as a number,
+// not exceed the target of the header, and the work of a block is the work of its target
+// Any node can seal any block
+#[derive(Debug)]
+pub struct ProofOfWork {
+    // Nonces tried before the header is changed to get new hashes
+    max_nonce: u64,
+    // Number of threads hashing in parallel, each trying its own share of the nonces
+    mining_threads: u64,
+}
+
+impl ProofOfWork {
+    // A thread count of 0 uses one thread per available core
+    pub fn new(max_nonce: u64, mining_threads: usize) -> ProofOfWork {
+        ProofOfWork {
+            max_nonce,
+            mining_threads: threads_for(mining_threads),
+        }
+    }
+
+    // Tries the nonces below `max_nonce` on the header of the block
+    // The nonces are split between the mining threads, worker `i` trying `i`, `i + threads`, ...
+    // and all of them stop as soon as one finds a valid block or the block becomes stale
+    // Returns `None` once every nonce was tried or the block is stale
+    fn mine_round(
+        &self,
+        template: &Block,
+        target: Target,
+        is_stale: &(dyn Fn() -> bool + Sync),
+    ) -> Option<Block> {
+        let stopped = AtomicBool::new(false);
+        let mined_block = Mutex::new(None);
+        let hashes = AtomicU64::new(0);
+        let started_at = Instant::now();
+
+        thread::scope(|scope| {
+            for worker in 0..self.mining_threads {
+                let mut next_block = template.clone();
+                let (stopped, mined_block, hashes) = (&stopped, &mined_block, &hashes);
+
+                scope.spawn(move |_| {
+                    let mut nonce = worker;
+                    let mut worker_hashes = 0;
+                    while nonce < self.max_nonce && !stopped.load(Ordering::Relaxed) {
+                        if is_stale() {
+                            stopped.store(true, Ordering::Relaxed);
+                            break;
+                        }
+
+                        next_block.header.nonce = nonce;
+                        next_block.header.hash = next_block.header.calculate_hash();
+                        worker_hashes += 1;
+
+                        // A valid block must have a hash that, read as a number, is not above the target
+                        if target.is_met_by(&next_block.header.hash) {
+                            if !stopped.swap(true, Ordering::Relaxed) {
+                                *mined_block.lock().unwrap() = Some(next_block);
+                            }
+                            break;
+                        }
+                        // the round ends once the next nonce would not fit, whatever `max_nonce` is
+                        nonce = match nonce.checked_add(self.mining_threads) {
+                            Some(next_nonce) => next_nonce,
+                            None => break,
+                        };
+                    }
+                    hashes.fetch_add(worker_hashes, Ordering::Relaxed);
+                });
+            }
+        })
+        .unwrap();
+
+        let hashes = hashes.into_inner();
+        let seconds = started_at.elapsed().as_secs_f64();
+        // every round logs, so the hash rate is only shown with debug logs on
+        debug!(
+            "hashed {} headers in {:.2}s, {:.0} hashes/s on {} threads",
+            hashes,
+            seconds,
+            hashes as f64 / seconds.max(f64::EPSILON),
+            self.mining_threads
+        );
+
+        mined_block.into_inner().unwrap()
+    }
+}
+
+impl ConsensusEngine for ProofOfWork {
+    fn verify_seal(&self, header: &BlockHeader) -> Result<Work, ConsensusError> {
+        if !header.seal.is_empty() {
+            return Err(ConsensusError::UnexpectedSeal);
+        }
+
+        // a target that does not fit in 256 bits can never be met
+        let target = Target::from_compact(header.target).ok_or(ConsensusError::TargetNotMet)?;
+        if !target.is_met_by(&header.hash) {
+            return Err(ConsensusError::TargetNotMet);
+        }
+
+        Ok(target.work())
+    }
+
+    fn can_seal(&self, _index: u64) -> bool {
+        true
+    }
+
+    // Hashes the header with different nonces until the hash does not exceed the target,
+    // the transactions only count through their root, computed once per block
+    // Once `max_nonce` nonces were tried the header is rolled to a new timestamp or extranonce,
+    // so mining goes on until a block is found or it becomes stale
+    fn seal(
+        &self,
+        mut block: Block,
+        is_stale: &(dyn Fn() -> bool + Sync),
+    ) -> Result<Option<Block>, ConsensusError> {
+        let target = Target::from_compact(block.header.target)
+            .ok_or(ConsensusError::UnreachableTarget(block.header.target))?;
+
+        loop {
+            if let Some(mined_block) = self.mine_round(&block, target, is_stale) {
+                return Ok(Some(mined_block));
+            }
+            if is_stale() {
+                return Ok(None);
+            }
+            roll_block(&mut block);
+        }
+    }
+}
+
+// Gives the block a header that was not hashed yet: the current time if the clock moved on
+// since the header was last changed, otherwise the next extranonce of the coinbase,
+// which changes the transactions root
+fn roll_block(block: &mut Block) {
+    let now = chrono::Utc::now().timestamp_millis();
+    if now > block.header.timestamp {
+        block.header.timestamp = now;
+        return;
+    }
+
+    block.transactions[0].extra_nonce += 1;
+    block.header.transactions_root = transactions_root(&block.transactions);
+}
+
+// Mining threads to use for a configured count, 0 meaning one per available core
+fn threads_for(configured: usize) -> u64 {
+    let threads = match configured {
+        0 => std::thread::available_parallelism().map_or(1, |cores| cores.get()),
+        threads => threads,
+    };
+
+    threads as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::BlockHash;
+    use crate::types::target::CompactTarget;
+    use crate::types::transaction::Transaction;
+    use crate::util::test_helpers::{meets_target, EASY_TARGET, IMPOSSIBLE_TARGET};
+
+    fn create_block(target: CompactTarget) -> Block {
+        let coinbase = Transaction::new_coinbase("miner".to_string(), 50, 1);
+        Block::new(1, target, 0, Some(BlockHash::default()), vec![coinbase])
+    }
+
+    #[test]
+    fn sealed_block_meets_its_target() {
+        let engine = ProofOfWork::new(1_000, 4);
+        let block = engine.seal(create_block(EASY_TARGET), &|| false).unwrap().unwrap();
+
+        assert_eq!(block.header.hash, block.header.calculate_hash());
+        assert!(meets_target(&block.header.hash, EASY_TARGET));
+        assert_eq!(
+            engine.verify_seal(&block.header),
+            Ok(Target::from_compact(EASY_TARGET).unwrap().work())
+        );
+    }
+
+    #[test]
+    fn round_ends_once_the_nonces_are_shared_out() {
+        // the threads share the nonces of a round instead of each trying all of them
+        let engine = ProofOfWork::new(3, 4);
+        let target = Target::from_compact(IMPOSSIBLE_TARGET).unwrap();
+        assert!(engine.mine_round(&create_block(IMPOSSIBLE_TARGET), target, &|| false).is_none());
+
+        assert_eq!(threads_for(3), 3);
+        assert!(threads_for(0) >= 1);
+    }
+
+    #[test]
+    fn stale_block_is_not_sealed() {
+        let engine = ProofOfWork::new(u64::MAX, 1);
+
+        assert!(engine.seal(create_block(IMPOSSIBLE_TARGET), &|| true).unwrap().is_none());
+        assert!(matches!(
+            engine.seal(create_block(0x2100_0000), &|| false),
+            Err(ConsensusError::UnreachableTarget(0x2100_0000))
+        ));
+    }
+
+    #[test]
+    fn roll_block_changes_the_header() {
+        let mut block = create_block(EASY_TARGET);
+
+        // a block from the past gets the current time
+        block.header.timestamp = 0;
+        roll_block(&mut block);
+        assert!(block.header.timestamp > 0);
+
+        // the clock did not move past a block from the future, so the coinbase changes instead
+        block.header.timestamp = i64::MAX;
+        let root = block.header.transactions_root.clone();
+        roll_block(&mut block);
+        assert_eq!(block.header.timestamp, i64::MAX);
+        assert_eq!(block.transactions[0].extra_nonce, 1);
+        assert_ne!(block.header.transactions_root, root);
+        assert!(block.has_valid_transactions_root());
+    }
+
+    #[test]
+    fn header_without_the_work_is_rejected() {
+        let engine = ProofOfWork::new(1_000, 1);
+        let mut block = engine.seal(create_block(EASY_TARGET), &|| false).unwrap().unwrap();
+
+        block.header.seal = "00".to_string();
+        assert_eq!(engine.verify_seal(&block.header), Err(ConsensusError::UnexpectedSeal));
+
+        let block = create_block(IMPOSSIBLE_TARGET);
+        assert_eq!(engine.verify_seal(&block.header), Err(ConsensusError::TargetNotMet));
+    }
+}
@@ -0,0 +1,285 @@
+use crate::consensus::ConsensusError;
+use crate::miner::create_next_block;
+use crate::p2p::network::Network;
+use crate::types::block::Block;
+use crate::types::blockchain::{BlockStatus, BlockchainError};
+use crate::types::transaction::Address;
+use crate::types::transaction_pool::TransactionVec;
+use crate::util::execution::{Runnable, StopSignal};
+use crate::{Blockchain, Context, TransactionPool};
+use anyhow::Result;
+use log::{info, warn};
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum DevMinerError {
+    #[error("could not seal the block: {0}")]
+    Seal(#[from] ConsensusError),
+    #[error("the tip of the chain changed while sealing")]
+    StaleTip,
+    #[error("block was rejected: {0}")]
+    Rejected(#[from] BlockchainError),
+}
+
+// Miner of the dev mode, which seals a block as soon as a transaction enters the pool
+// or when asked to, instead of waiting for transactions and searching for a hash
+// The blocks go through the same checks as any other block, the dev mode only makes
+// them cheap to seal by starting the chain at the easiest target
+#[derive(Debug, Clone)]
+pub struct DevMiner {
+    tx_waiting_ms: u64,
+    miner_address: Address,
+    blockchain: Blockchain,
+    pool: TransactionPool,
+    network: Network,
+    // Held while sealing, so two blocks are never built on the same tip
+    sealing: Arc<Mutex<()>>,
+    stop_signal: StopSignal,
+}
+
+impl Runnable for DevMiner {
+    fn run(&self) -> Result<()> {
+        self.start()
+    }
+}
+
+impl DevMiner {
+    pub fn new(context: &Context) -> DevMiner {
+        DevMiner {
+            tx_waiting_ms: context.config.tx_waiting_ms,
+            miner_address: context.config.miner_address.clone(),
+            blockchain: context.blockchain.clone(),
+            pool: context.pool.clone(),
+            network: context.network.clone(),
+            sealing: Arc::default(),
+            stop_signal: context.stop_signal.clone(),
+        }
+    }
+
+    // Seals the pending transactions every time new ones are added to the pool,
+    // starting with the ones added before, in as many blocks as they need
+    pub fn start(&self) -> Result<()> {
+        info!("start sealing blocks in dev mode");
+
+        let mut seen = 0;
+        loop {
+            if self.stop_signal.is_stopped() {
+                info!("stopping dev mode sealing");
+                return Ok(());
+            }
+
+            let added = self.pool.wait_for_added(seen, self.tx_waiting_ms);
+            if added == seen {
+                continue;
+            }
+            seen = added;
+
+            while !self.stop_signal.is_stopped() {
+                match self.seal_pending() {
+                    Ok(Some(_)) => {}
+                    Ok(None) => break,
+                    Err(error) => {
+                        warn!("could not seal the pending transactions: {}", error);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    // Seals a block with the best executable transactions of the pool, if there are any
+    // and the consensus engine lets this node seal the next block
+    pub fn seal_pending(&self) -> Result<Option<Block>, DevMinerError> {
+        let _sealing = self.sealing.lock().unwrap();
+
+        let next_index = self.blockchain.get_last_block().header.index + 1;
+        if !self.blockchain.consensus.can_seal(next_index) {
+            return Ok(None);
+        }
+
+        let transactions = self.take_transactions();
+        if transactions.is_empty() {
+            return Ok(None);
+        }
+        self.seal(transactions, None).map(Some)
+    }
+
+    // Seals a block right away, empty if no transaction is pending
+    // The block gets the given timestamp, which must be valid for the next block like any other,
+    // or the current time, moved up to the timestamp of the tip if that one is ahead
+    pub fn seal_block(&self, timestamp: Option<i64>) -> Result<Block, DevMinerError> {
+        let _sealing = self.sealing.lock().unwrap();
+
+        let transactions = self.take_transactions();
+        self.seal(transactions, timestamp)
+    }
+
+    // Takes the best transactions that fit in the next block next to its coinbase
+    fn take_transactions(&self) -> TransactionVec {
+        let state = self.blockchain.get_world_state();
+        let next_index = self.blockchain.get_last_block().header.index + 1;
+        let (max_transactions, max_size) =
+            self.blockchain.get_pool_room(&self.miner_address, next_index);
+
+        self.pool.pop_best(&state, max_transactions, max_size)
+    }
+
+    // Builds the next block on the tip and adds it to the chain, sending it to the peers
+    // The transactions go back to the pool if the block does not make it to the main chain
+    fn seal(
+        &self,
+        transactions: TransactionVec,
+        timestamp: Option<i64>,
+    ) -> Result<Block, DevMinerError> {
+        let tip_changes = self.blockchain.get_tip_changes();
+        let last_block = self.blockchain.get_last_block();
+        let target = self.blockchain.get_next_target(&last_block);
+        let mut block = create_next_block(
+            &self.blockchain,
+            &self.miner_address,
+            &last_block,
+            target,
+            transactions.clone(),
+            0,
+        );
+        block.header.timestamp = timestamp.unwrap_or_else(|| {
+            block.header.timestamp.max(last_block.header.timestamp)
+        });
+        block.header.hash = block.header.calculate_hash();
+
+        let is_stale = || self.blockchain.get_tip_changes() != tip_changes;
+        let sealed = match self.blockchain.consensus.seal(block, &is_stale) {
+            Ok(Some(block)) => block,
+            Ok(None) => {
+                self.return_to_pool(transactions);
+                return Err(DevMinerError::StaleTip);
+            }
+            Err(error) => {
+                self.return_to_pool(transactions);
+                return Err(error.into());
+            }
+        };
+
+        let orphaned = match self.blockchain.add_block(sealed.clone()) {
+            Ok(BlockStatus::Extended) => TransactionVec::new(),
+            Ok(BlockStatus::SideBranch) => transactions,
+            Ok(BlockStatus::Reorganized(orphaned)) => orphaned,
+            Err(error) => {
+                self.return_to_pool(transactions);
+                return Err(error.into());
+            }
+        };
+        self.return_to_pool(orphaned);
+        self.network.broadcast_block(&sealed);
+        info!("sealed block {} in dev mode", sealed.header.index);
+
+        Ok(sealed)
+    }
+
+    fn return_to_pool(&self, transactions: TransactionVec) {
+        self.blockchain
+            .with_world_state(|state| self.pool.add_orphaned(transactions, state));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::blockchain::ChainParams;
+    use crate::types::target::MAX_TARGET;
+    use crate::types::transaction::Transaction;
+    use crate::util::test_helpers::{chain_params, create_signing_key, wait_for, TestNode, BLOCK_REWARD};
+
+    fn create_dev_miner() -> DevMiner {
+        create_dev_miner_with(chain_params(MAX_TARGET.to_compact(), BLOCK_REWARD))
+    }
+
+    fn create_dev_miner_with(params: ChainParams) -> DevMiner {
+        let node = TestNode::builder().params(params).build();
+
+        DevMiner {
+            tx_waiting_ms: 50,
+            miner_address: "miner".to_string(),
+            blockchain: node.blockchain,
+            pool: node.pool,
+            network: node.network,
+            sealing: Arc::default(),
+            stop_signal: StopSignal::default(),
+        }
+    }
+
+    fn add_transaction(dev_miner: &DevMiner, nonce: u64) -> Transaction {
+        let transaction = Transaction::new_signed(&create_signing_key(1), "bob".to_string(), 1, 1, nonce);
+        let state = dev_miner.blockchain.get_world_state();
+        dev_miner.pool.add_transaction(transaction.clone(), &state).unwrap();
+        transaction
+    }
+
+    #[test]
+    fn blocks_are_sealed_on_demand_with_the_given_timestamp() {
+        let dev_miner = create_dev_miner();
+        assert!(dev_miner.seal_pending().unwrap().is_none());
+
+        let transaction = add_transaction(&dev_miner, 0);
+        let block = dev_miner.seal_pending().unwrap().unwrap();
+        assert_eq!(block.transactions[1].hash(), transaction.hash());
+
+        let empty = dev_miner.seal_block(Some(block.header.timestamp + 60_000)).unwrap();
+        assert_eq!(empty.header.index, 2);
+        assert_eq!(empty.header.timestamp, block.header.timestamp + 60_000);
+        assert_eq!(empty.transactions.len(), 1);
+
+        // without a timestamp the block does not go back in time
+        let next = dev_miner.seal_block(None).unwrap();
+        assert_eq!(next.header.timestamp, empty.header.timestamp);
+        assert_eq!(dev_miner.blockchain.get_last_block().header.hash, next.header.hash);
+    }
+
+    #[test]
+    fn invalid_timestamp_is_rejected_and_transactions_stay_pending() {
+        let dev_miner = create_dev_miner();
+        add_transaction(&dev_miner, 0);
+
+        let result = dev_miner.seal_block(Some(-1));
+        assert!(matches!(
+            result,
+            Err(DevMinerError::Rejected(BlockchainError::WrongTimestamp))
+        ));
+        assert_eq!(dev_miner.pool.snapshot().count, 1);
+        assert_eq!(dev_miner.blockchain.get_last_block().header.index, 0);
+    }
+
+    #[test]
+    fn new_transactions_are_sealed_right_away() {
+        let dev_miner = create_dev_miner();
+        let runner = dev_miner.clone();
+        let runner = std::thread::spawn(move || runner.start());
+
+        add_transaction(&dev_miner, 0);
+        wait_for(|| dev_miner.blockchain.get_last_block().header.index == 1);
+
+        dev_miner.stop_signal.stop();
+        runner.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn pending_transactions_are_sealed_in_as_many_blocks_as_needed() {
+        // a block only has room for the coinbase and one transaction
+        let dev_miner = create_dev_miner_with(ChainParams {
+            max_block_transactions: 2,
+            ..chain_params(MAX_TARGET.to_compact(), BLOCK_REWARD)
+        });
+        for nonce in 0..3 {
+            add_transaction(&dev_miner, nonce);
+        }
+
+        let runner = dev_miner.clone();
+        let runner = std::thread::spawn(move || runner.start());
+        wait_for(|| dev_miner.blockchain.get_last_block().header.index == 3);
+        assert_eq!(dev_miner.pool.snapshot().count, 0);
+
+        dev_miner.stop_signal.stop();
+        runner.join().unwrap().unwrap();
+    }
+}
@@ -1,33 +1,193 @@
 mod actix_web;
+mod block_templates;
+mod consensus;
+mod dev_miner;
 mod miner;
+mod p2p;
+mod storage;
 mod types;
 mod util;
+mod wallet;
 
-use crate::actix_web::Server;
+use crate::actix_web::{LightServer, Server};
+use crate::consensus::create_engine;
+use crate::dev_miner::DevMiner;
 use crate::execution::set_ctrlc_handler;
 use crate::miner::Miner;
-use crate::types::blockchain::Blockchain;
+use crate::p2p::light_client::LightClient;
+use crate::p2p::network::{Network, P2pServer};
+use crate::storage::file_storage::FileStorage;
+use crate::types::blockchain::{Blockchain, ChainParams};
+use crate::types::header_chain::HeaderChain;
+use crate::types::target::{Target, MAX_TARGET};
 use crate::types::transaction_pool::TransactionPool;
 use crate::util::execution;
+use crate::util::execution::{Runnable, StopSignal};
+use crate::wallet::run_wallet_command;
+use anyhow::{anyhow, Result};
+use std::env;
 use util::config::Config;
 use util::context::Context;
 
 fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    // check the stored chain instead of running the node
+    if args.first().map(String::as_str) == Some("verify-db") {
+        let config = Config::read_config_from_file("config.json").unwrap();
+        if let Err(error) = verify_db(&config) {
+            eprintln!("{}", error);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // any other arguments select an offline wallet command instead of running the node
+    if !args.is_empty() {
+        if let Err(error) = run_wallet_command(&args) {
+            eprintln!("{}", error);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     // reading config from config.json
     let config = Config::read_config_from_file("config.json").unwrap();
 
-    let difficulty = config.difficulty;
+    if config.light_client {
+        run_light_client(&config);
+        return;
+    }
+
+    let consensus = create_engine(&config).expect("invalid consensus settings");
+
+    // reload the chain from the data directory, or keep it only in memory without one
+    let blockchain = match &config.data_dir {
+        Some(data_dir) => {
+            let storage = FileStorage::open(data_dir, config.sync_policy)
+                .expect("could not open the data directory");
+            Blockchain::open(
+                consensus,
+                chain_params(&config),
+                config.genesis_balances.clone(),
+                Box::new(storage),
+            )
+            .expect("could not load the stored blockchain")
+        }
+        None => Blockchain::new(
+            consensus,
+            chain_params(&config),
+            config.genesis_balances.clone(),
+        ),
+    };
+    let pool = TransactionPool::new(
+        config.pool_capacity,
+        config.pool_max_per_sender,
+        config.pool_ttl_ms,
+    );
+    let network = Network::new(
+        config.p2p_port,
+        config.peers.clone(),
+        blockchain.clone(),
+        pool.clone(),
+    );
     let context = Context {
         config,
-        blockchain: Blockchain::new(difficulty),
-        pool: TransactionPool::new(),
+        blockchain,
+        pool,
+        network,
+        stop_signal: StopSignal::default(),
     };
 
-    // initialize the processes
-    let miner = Miner::new(&context);
-    let actix_server = Server::new(&context);
+    // initialize the processes, the dev miner replacing the miner in dev mode
+    let dev_miner = context.config.dev_mode.then(|| DevMiner::new(&context));
+    let miner: Box<dyn Runnable> = match &dev_miner {
+        Some(dev_miner) => Box::new(dev_miner.clone()),
+        None => Box::new(Miner::new(&context)),
+    };
+    let actix_server = Server::new(&context, dev_miner);
+    let p2p_server = P2pServer::new(&context);
+
+    // the servers only end with the process, so the mining loops are stopped
+    // and the stored blocks flushed before it exits
+    let blockchain = context.blockchain.clone();
+    set_ctrlc_handler(context.stop_signal.clone(), move || Ok(blockchain.close()?));
+
+    execution::run_in_parallel(vec![miner, Box::new(actix_server), Box::new(p2p_server)]);
+}
+
+// Rules of the chain from the config
+// In dev mode the target is the easiest one and is never adjusted, so that blocks are sealed at once
+fn chain_params(config: &Config) -> ChainParams {
+    let (initial_target, retarget_interval) = if config.dev_mode {
+        (MAX_TARGET.to_compact(), 0)
+    } else {
+        (
+            Target::from_difficulty(config.difficulty).to_compact(),
+            config.retarget_interval,
+        )
+    };
+
+    ChainParams {
+        initial_target,
+        retarget_interval,
+        target_block_time_ms: config.target_block_time_ms,
+        block_reward: config.block_reward,
+        halving_interval: config.halving_interval,
+        max_block_transactions: config.max_block_transactions,
+        max_block_size: config.max_block_size,
+    }
+}
+
+// Follows the headers of the configured peers instead of running a full node
+fn run_light_client(config: &Config) {
+    let consensus = create_engine(config).expect("invalid consensus settings");
+    let headers = HeaderChain::new(consensus, &chain_params(config));
+    let client = LightClient::new(config.peers.clone(), headers);
+    let light_server = LightServer::new(config.port, client.clone());
+
+    set_ctrlc_handler(StopSignal::default(), || Ok(()));
+
+    execution::run_in_parallel(vec![Box::new(client), Box::new(light_server)]);
+}
+
+// Walks the stored chain re-running every check of `Blockchain::add_block` on each block
+fn verify_db(config: &Config) -> Result<()> {
+    let data_dir = config
+        .data_dir
+        .as_ref()
+        .ok_or_else(|| anyhow!("no data_dir is configured"))?;
+
+    // the files are left as they are, the node fixes a torn record when it starts
+    let storage = FileStorage::open_read_only(data_dir)?;
+    let damaged_record = storage.damaged_record().cloned();
+
+    let blockchain = Blockchain::open(
+        create_engine(config)?,
+        chain_params(config),
+        config.genesis_balances.clone(),
+        Box::new(storage),
+    )?;
+    let last_block = blockchain.get_last_block();
+    println!(
+        "verified the stored blocks, main chain height {}, tip {}",
+        last_block.header.index, last_block.header.hash
+    );
 
-    set_ctrlc_handler();
+    match damaged_record {
+        Some(damaged) if damaged.trailing => println!(
+            "torn record at offset {} after the last valid block ({}), it is dropped when the node starts",
+            damaged.offset, damaged.reason
+        ),
+        Some(damaged) => {
+            return Err(anyhow!(
+                "corrupt record at offset {} after the last valid block: {}",
+                damaged.offset,
+                damaged.reason
+            ))
+        }
+        None => {}
+    }
 
-    execution::run_in_parallel(vec![Box::new(miner), Box::new(actix_server)]);
+    Ok(())
 }
@@ -1,25 +1,35 @@
+use crate::consensus::ConsensusError;
+use crate::p2p::network::Network;
 use crate::types::block::Block;
+use crate::types::blockchain::BlockStatus;
+use crate::types::target::CompactTarget;
+use crate::types::transaction::{Address, Transaction};
 use crate::types::transaction_pool::TransactionVec;
 use crate::{Blockchain, Context, TransactionPool};
-use log::{error, info};
+use log::{info, warn};
 
-use crate::util::execution::{sleep_millis, Runnable};
+use crate::util::execution::{sleep_millis, Runnable, StopSignal};
 use anyhow::Result;
-use thiserror::Error;
-
-#[derive(Error, Debug)]
-pub enum MinerError {
-    #[error("No valid block was mined at index `{0}`")]
-    BlockNotMined(u64),
+use std::time::Instant;
+
+// How mining a block ended
+#[derive(Debug)]
+enum MiningOutcome {
+    Mined(Block),
+    // The tip of the chain changed, so the block would not extend it anymore
+    Stale,
 }
 
 pub struct Miner {
     max_blocks: u64,
-    max_nonce: u64,
+    // Longest time the miner waits for the pool without being woken up by a new transaction
     tx_waiting_ms: u64,
+    batch_policy: BatchPolicy,
+    miner_address: Address,
     blockchain: Blockchain,
     transaction_pool: TransactionPool,
-    target: u32,
+    network: Network,
+    stop_signal: StopSignal,
 }
 
 impl Runnable for Miner {
@@ -32,11 +42,16 @@ impl Miner {
     pub fn new(context: &Context) -> Miner {
         Miner {
             max_blocks: context.config.max_blocks,
-            max_nonce: context.config.max_nonce,
             tx_waiting_ms: context.config.tx_waiting_ms,
+            batch_policy: BatchPolicy {
+                min_transactions: context.config.min_block_transactions,
+                max_wait_ms: context.config.max_batch_wait_ms,
+            },
+            miner_address: context.config.miner_address.clone(),
             blockchain: context.blockchain.clone(),
             transaction_pool: context.pool.clone(),
-            target: context.config.difficulty,
+            network: context.network.clone(),
+            stop_signal: context.stop_signal.clone(),
         }
     }
 
@@ -44,152 +59,302 @@ impl Miner {
     // including all pending transactions in the transaction pool each time
     pub fn start(&self) -> Result<()> {
         info!(
-            "start mining with difficulty {}",
-            self.blockchain.difficulty
+            "start mining with initial target {:#010x} and {:?}",
+            self.blockchain.params.initial_target, self.blockchain.consensus
         );
 
         // In each loop it tries to find the next valid block and append it to the blockchain
+        // Between blocks it sleeps until the pool signals more transactions than the `added` seen so far,
+        // and `batch_started` is when the pending transactions started waiting for a full batch
         let mut block_counter = 0;
+        let mut added = 0;
+        let mut batch_started: Option<Instant> = None;
         loop {
             if self.must_stop_mining(block_counter) {
                 info!("block limit reached, stopping mining");
                 return Ok(());
             }
+            if self.stop_signal.is_stopped() {
+                info!("stopping mining");
+                return Ok(());
+            }
+
+            // The tip is read first, so a block arriving after it makes the work stale
+            let tip_changes = self.blockchain.get_tip_changes();
+
+            // Only try to seal the blocks the consensus engine lets this node seal,
+            // others wait for the block of the node whose turn it is
+            let next_index = self.blockchain.get_last_block().header.index + 1;
+            if !self.blockchain.consensus.can_seal(next_index) {
+                self.blockchain.wait_for_tip_change(tip_changes, self.tx_waiting_ms);
+                continue;
+            }
 
-            // Empty all transactions from the pool, they will be included in the new block
-            let transactions = self.transaction_pool.pop();
+            // Take the best paying executable transactions from the pool that fit in a block,
+            // they will be included in the new block and the rest stay in the pool
+            let state = self.blockchain.get_world_state();
+            let (max_transactions, max_size) =
+                self.blockchain.get_pool_room(&self.miner_address, next_index);
+
+            // Do not try to mine a block until the pool has enough executable transactions,
+            // or they waited long enough, and wake up as soon as new ones arrive
+            let pending = self
+                .transaction_pool
+                .peek_best(&state, max_transactions, max_size)
+                .len();
+            let waited_ms = batch_started.map(|started| started.elapsed().as_millis() as u64);
+            if let Some(wait_ms) = self.batch_policy.wait_ms(pending, waited_ms) {
+                if pending == 0 {
+                    batch_started = None;
+                } else {
+                    batch_started.get_or_insert_with(Instant::now);
+                }
+                added = self
+                    .transaction_pool
+                    .wait_for_added(added, wait_ms.min(self.tx_waiting_ms));
+                continue;
+            }
+            batch_started = None;
 
-            // Do not try to mine a block if there are no transactions in the pool
+            let transactions = self.transaction_pool.pop_best(&state, max_transactions, max_size);
             if transactions.is_empty() {
-                sleep_millis(self.tx_waiting_ms);
                 continue;
             }
 
-            // try to find a valid next block of the blockchain
+            // try to find a valid next block of the blockchain, at the target the chain demands,
+            // giving up as soon as another block replaces the tip the work started on
             let last_block = self.blockchain.get_last_block();
-            let mining_result = self.mine_block(&last_block, transactions.clone());
+            let target = self.blockchain.get_next_target(&last_block);
+            let mining_result = self.mine_block(&last_block, target, transactions.clone(), tip_changes);
             match mining_result {
-                Some(block) => {
-                    self.blockchain.add_block(block.clone()).unwrap();
+                Ok(MiningOutcome::Mined(block)) => {
+                    // a block found after another one took its place does not include the
+                    // transactions in the main chain, so they go back to the pool
+                    let orphaned = match self.blockchain.add_block(block.clone()) {
+                        Ok(BlockStatus::Extended) => TransactionVec::new(),
+                        Ok(BlockStatus::SideBranch) => transactions,
+                        Ok(BlockStatus::Reorganized(orphaned)) => orphaned,
+                        Err(error) => {
+                            warn!("mined block {} was rejected: {}", block.header.index, error);
+                            self.return_to_pool(transactions);
+                            continue;
+                        }
+                    };
+                    self.network.broadcast_block(&block);
                     block_counter += 1;
+                    self.return_to_pool(orphaned);
                 }
-                None => {
-                    let index = last_block.index + 1;
-                    return Err(MinerError::BlockNotMined(index).into());
+                Ok(MiningOutcome::Stale) => {
+                    info!(
+                        "abandoning block {}, the tip of the chain changed",
+                        last_block.header.index + 1
+                    );
+                    self.return_to_pool(transactions);
+                }
+                // a block the engine can not seal is given up and tried again after a while,
+                // in case the tip or the turn of the node changed by then
+                Err(error) => {
+                    warn!("could not seal block {}: {}", last_block.header.index + 1, error);
+                    self.return_to_pool(transactions);
+                    sleep_millis(self.tx_waiting_ms);
                 }
             }
         }
     }
 
+    // Puts back transactions that did not make it to the main chain,
+    // the ones a new tip already includes or made invalid are dropped by the pool
+    fn return_to_pool(&self, transactions: TransactionVec) {
+        self.blockchain
+            .with_world_state(|state| self.transaction_pool.add_orphaned(transactions, state));
+    }
+
     // check if we have hit the limit of mined blocks (if the limit is set)
     fn must_stop_mining(&self, block_counter: u64) -> bool {
         self.max_blocks > 0 && block_counter >= self.max_blocks
     }
 
-    // Tries to find the next valid block of the blockchain
-    // It will create blocks with different "nonce" values until one has a hash that matches the difficulty
-    // Returns either a valid block (that satisfies the difficulty) or "None" if no block was found
-    fn mine_block(&self, last_block: &Block, transactions: TransactionVec) -> Option<Block> {
-        for nonce in 0..self.max_nonce {
-            let next_block = self.create_next_block(last_block, transactions.clone(), nonce);
-
-            // A valid block must have a hash with enough starting zeroes with represents as target
-            if next_block
-                .hash
-                .starts_with(&"0".repeat(self.target as usize))
-            {
-                return Some(next_block);
-            }
-        }
+    // Tries to find the next valid block of the blockchain, sealed by the consensus engine
+    // Sealing goes on until it succeeds, the tip changes after `tip_changes` changes
+    // or the miner is stopped
+    fn mine_block(
+        &self,
+        last_block: &Block,
+        target: CompactTarget,
+        transactions: TransactionVec,
+        tip_changes: u64,
+    ) -> Result<MiningOutcome, ConsensusError> {
+        let next_block = self.create_next_block(last_block, target, transactions, 0);
+        let is_stale = || {
+            self.stop_signal.is_stopped() || self.blockchain.get_tip_changes() != tip_changes
+        };
 
-        None
+        match self.blockchain.consensus.seal(next_block, &is_stale)? {
+            Some(block) => Ok(MiningOutcome::Mined(block)),
+            None => Ok(MiningOutcome::Stale),
+        }
     }
 
-    // Creates a valid next block for a blockchain
-    // Takes into account the index and the hash of the previous block
     fn create_next_block(
         &self,
         last_block: &Block,
+        target: CompactTarget,
         transactions: TransactionVec,
         nonce: u64,
     ) -> Block {
-        let index = (last_block.index + 1) as u64;
-        let previous_hash = last_block.clone().hash;
+        create_next_block(
+            &self.blockchain,
+            &self.miner_address,
+            last_block,
+            target,
+            transactions,
+            nonce,
+        )
+    }
+}
+
+// When the miner takes the pending transactions: as soon as `min_transactions` of them are executable,
+// or once the first ones waited `max_wait_ms` for the others, 0 meaning without limit
+#[derive(Debug, Clone, Copy)]
+struct BatchPolicy {
+    min_transactions: usize,
+    max_wait_ms: u64,
+}
+
+impl BatchPolicy {
+    // Returns how long to wait for more transactions, given how many are pending and for how long
+    // they have been waiting, or `None` if a block should be mined with them right away
+    fn wait_ms(&self, pending: usize, waited_ms: Option<u64>) -> Option<u64> {
+        if pending == 0 {
+            return Some(u64::MAX);
+        }
+        if pending >= self.min_transactions {
+            return None;
+        }
+        if self.max_wait_ms == 0 {
+            return Some(u64::MAX);
+        }
 
-        // hash of the new block is automatically calculated on creation
-        Block::new(index, nonce, Some(previous_hash), transactions)
+        let remaining_ms = self.max_wait_ms.saturating_sub(waited_ms.unwrap_or(0));
+        (remaining_ms > 0).then_some(remaining_ms)
     }
 }
 
+// Creates a valid next block for a blockchain
+// Takes into account the index and the hash of the previous block,
+// and prepends the coinbase paying the block reward and all the fees to the miner
+pub fn create_next_block(
+    blockchain: &Blockchain,
+    miner_address: &Address,
+    last_block: &Block,
+    target: CompactTarget,
+    transactions: TransactionVec,
+    nonce: u64,
+) -> Block {
+    let index = last_block.header.index + 1;
+    let previous_hash = last_block.header.hash.clone();
+
+    let fees = transactions
+        .iter()
+        .fold(0u64, |fees, transaction| fees.saturating_add(transaction.fee));
+    let reward = blockchain.get_block_reward(index).saturating_add(fees);
+    let coinbase = Transaction::new_coinbase(miner_address.clone(), reward, index);
+    let mut block_transactions = vec![coinbase];
+    block_transactions.extend(transactions);
+
+    // hash of the new block is automatically calculated on creation
+    Block::new(index, target, nonce, Some(previous_hash), block_transactions)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use crate::consensus::proof_of_authority::ProofOfAuthority;
+    use crate::consensus::proof_of_work::ProofOfWork;
+    use crate::consensus::SyncedConsensus;
     use crate::types::block::BlockHash;
-    use crate::types::transaction::Transaction;
+    use crate::types::transaction::address_from_public_key;
+    use crate::util::test_helpers::{
+        create_signing_key, meets_target, mine, TestNode, BLOCK_REWARD, EASY_TARGET, FUNDED_BALANCE,
+        IMPOSSIBLE_TARGET,
+    };
+    use std::sync::Arc;
 
-    // We use SHA 256 hashes
-    const MAX_DIFFICULTY: u32 = 256;
+    const FEE: u64 = 2;
 
     #[test]
     fn test_create_next_block() {
         let miner = create_default_miner();
         let block = create_empty_block();
 
-        let next_block = miner.create_next_block(&block, Vec::new(), 0);
+        let next_block = miner.create_next_block(&block, EASY_TARGET, Vec::new(), 0);
 
         // the next block must follow the previous one
-        assert_eq!(next_block.index, block.index + 1);
-        assert_eq!(next_block.previous_hash.unwrap(), block.hash);
+        assert_eq!(next_block.header.index, block.header.index + 1);
+        assert_eq!(next_block.header.previous_hash.unwrap(), block.header.hash);
+
+        // and start with the coinbase paying the miner
+        let coinbase = &next_block.transactions[0];
+        assert!(coinbase.is_coinbase());
+        assert_eq!(coinbase.recipient, miner.miner_address);
+        assert_eq!(coinbase.amount, BLOCK_REWARD);
     }
 
     #[test]
     fn test_mine_block_found() {
-        // let's use a small difficulty target for fast testing
-        let difficulty = 1;
+        // let's use an easy target for fast testing
+        let target = EASY_TARGET;
 
-        // this should be more than enough nonce's to find a block with only 1 zero
+        // this should be more than enough nonce's to find a block 16 times harder than any hash
         let max_nonce = 1_000;
 
         // check that the block is mined
-        let miner = create_miner(difficulty, max_nonce);
+        let miner = create_miner(target, max_nonce);
         let last_block = create_empty_block();
-        let result = miner.mine_block(&last_block, Vec::new());
-        assert!(result.is_some());
+        let result = mine_on_current_tip(&miner, &last_block, target);
 
         // check that the block is valid
-        let mined_block = result.unwrap();
-        assert_mined_block_is_valid(&mined_block, &last_block, difficulty);
+        match result {
+            MiningOutcome::Mined(mined_block) => {
+                assert_mined_block_is_valid(&mined_block, &last_block, target)
+            }
+            outcome => panic!("unexpected outcome {:?}", outcome),
+        }
     }
 
     #[test]
-    fn test_mine_block_not_found() {
-        // let's use a high difficulty target to never find a block
-        let difficulty = MAX_DIFFICULTY;
-
-        // with a max_nonce so low, we will never find a block
-        // and also the test will end fast
-        let max_nonce = 10;
-
-        // check that the block is not mined
-        let miner = create_miner(difficulty, max_nonce);
-        let last_block = create_empty_block();
-        let result = miner.mine_block(&last_block, Vec::new());
-        assert!(result.is_none());
+    fn test_mine_block_abandoned_when_the_tip_changes() {
+        // the miner would grind forever on this target if the tip did not change
+        let miner = create_miner(EASY_TARGET, u64::MAX);
+        let genesis_block = miner.blockchain.get_last_block();
+        let tip_changes = miner.blockchain.get_tip_changes();
+
+        // another block extends the chain while the miner is working on the genesis block
+        let block = mine(miner.create_next_block(&genesis_block, EASY_TARGET, Vec::new(), 0));
+        let blockchain = miner.blockchain.clone();
+        let other_miner = std::thread::spawn(move || {
+            sleep_millis(50);
+            blockchain.add_block(block).unwrap();
+        });
+
+        let result = miner.mine_block(&genesis_block, IMPOSSIBLE_TARGET, Vec::new(), tip_changes);
+        assert!(matches!(result, Ok(MiningOutcome::Stale)));
+        other_miner.join().unwrap();
     }
 
     #[test]
     fn test_run_block_found() {
-        // with a max_nonce so high and difficulty so low
+        // with a max_nonce so high and target so easy
         // we will always find a valid block
-        let difficulty = 1;
+        let target = EASY_TARGET;
         let max_nonce = 1_000_000;
-        let miner = create_miner(difficulty, max_nonce);
+        let miner = create_miner(target, max_nonce);
 
         let blockchain = miner.blockchain.clone();
         let transaction_pool = miner.transaction_pool.clone();
 
-        add_mock_transaction(&transaction_pool);
+        add_mock_transaction(&transaction_pool, &blockchain);
         let result = miner.run();
 
         // mining should be successful
@@ -202,11 +367,20 @@ mod tests {
         let mined_block = &blocks[1];
 
         // the mined block must be valid
-        assert_mined_block_is_valid(mined_block, genesis_block, blockchain.difficulty);
+        assert_mined_block_is_valid(mined_block, genesis_block, blockchain.params.initial_target);
 
-        // the mined block must include the transaction added previously
+        // the mined block must include the coinbase and the transaction added previously
         let mined_transactions = &mined_block.transactions;
-        assert_eq!(mined_transactions.len(), 1);
+        assert_eq!(mined_transactions.len(), 2);
+        assert_eq!(
+            blockchain.get_world_state().get_balance("miner"),
+            BLOCK_REWARD + FEE
+        );
+
+        // the transfer must be reflected in the balances
+        let sender = address_from_public_key(&create_signing_key(1).verifying_key());
+        assert_eq!(blockchain.get_world_state().get_balance(&sender), FUNDED_BALANCE - 3 - FEE);
+        assert_eq!(blockchain.get_world_state().get_balance("2"), 3);
 
         // the transaction pool must be empty
         // because the transaction was added to the block when mining
@@ -215,65 +389,183 @@ mod tests {
     }
 
     #[test]
-    #[should_panic(expected = "No valid block was mined at index `1`")]
-    fn test_run_block_not_found() {
-        // with a max_nonce so low and difficulty so high
-        // we will never find a valid block
-        let difficulty = MAX_DIFFICULTY;
+    fn test_run_block_found_after_rolling() {
+        // a single nonce per round is not enough, so the header is rolled until a block is found
+        let target = EASY_TARGET;
         let max_nonce = 1;
-        let miner = create_miner(difficulty, max_nonce);
+        let miner = create_miner(target, max_nonce);
 
-        let transaction_pool = &miner.transaction_pool;
-        add_mock_transaction(transaction_pool);
+        add_mock_transaction(&miner.transaction_pool, &miner.blockchain);
+        miner.run().unwrap();
 
-        // mining should return a BlockNotMined error
+        let blocks = miner.blockchain.get_all_blocks();
+        assert_eq!(blocks.len(), 2);
+        assert_mined_block_is_valid(&blocks[1], &blocks[0], target);
+        assert_eq!(blocks[1].header.nonce, 0);
+    }
+
+    #[test]
+    fn test_run_unreachable_target() {
+        // a target that does not fit in 256 bits can not be mined at all
+        let target = 0x2100_0000;
+        let max_nonce = 1;
+        let mut miner = create_miner(target, max_nonce);
+        miner.tx_waiting_ms = 200;
+        let blockchain = miner.blockchain.clone();
+        let transaction_pool = miner.transaction_pool.clone();
+        add_mock_transaction(&transaction_pool, &blockchain);
+
+        // the miner keeps running, with the transaction back in the pool while it sleeps
+        let stop_signal = miner.stop_signal.clone();
+        let runner = std::thread::spawn(move || miner.run());
+        sleep_millis(100);
+        assert!(!runner.is_finished());
+        assert_eq!(transaction_pool.snapshot().count, 1);
+        assert_eq!(blockchain.get_all_blocks().len(), 1);
+
+        stop_signal.stop();
+        runner.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_run_block_sealed_by_the_authority() {
+        // the only authority seals every block, without searching for a hash below the target
+        let authority_key = create_signing_key(5);
+        let consensus = ProofOfAuthority::new(
+            &[hex::encode(authority_key.verifying_key().as_bytes())],
+            Some(&hex::encode(authority_key.to_bytes())),
+        )
+        .unwrap();
+        let miner = create_miner_with(Arc::new(consensus), IMPOSSIBLE_TARGET);
+
+        add_mock_transaction(&miner.transaction_pool, &miner.blockchain);
         miner.run().unwrap();
+
+        let blocks = miner.blockchain.get_all_blocks();
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[1].header.nonce, 0);
+        assert!(!blocks[1].header.seal.is_empty());
+        assert_eq!(blocks[1].transactions.len(), 2);
+    }
+
+    #[test]
+    fn test_batch_policy() {
+        let policy = BatchPolicy {
+            min_transactions: 3,
+            max_wait_ms: 100,
+        };
+        assert_eq!(policy.wait_ms(0, None), Some(u64::MAX));
+        assert_eq!(policy.wait_ms(1, None), Some(100));
+        assert_eq!(policy.wait_ms(2, Some(40)), Some(60));
+        assert_eq!(policy.wait_ms(2, Some(100)), None);
+        assert_eq!(policy.wait_ms(3, None), None);
+
+        // without a limit the transactions wait for the minimum
+        let unlimited = BatchPolicy {
+            min_transactions: 3,
+            max_wait_ms: 0,
+        };
+        assert_eq!(unlimited.wait_ms(2, Some(1_000_000)), Some(u64::MAX));
+
+        // without a minimum any transaction is mined right away
+        let any = BatchPolicy {
+            min_transactions: 0,
+            max_wait_ms: 0,
+        };
+        assert_eq!(any.wait_ms(1, None), None);
+    }
+
+    #[test]
+    fn test_run_woken_up_by_a_new_transaction() {
+        // the miner would sleep for a minute if nothing woke it up
+        let mut miner = create_miner(EASY_TARGET, 1_000);
+        miner.tx_waiting_ms = 60_000;
+        let blockchain = miner.blockchain.clone();
+        let transaction_pool = miner.transaction_pool.clone();
+
+        let started_at = Instant::now();
+        let runner = std::thread::spawn(move || miner.run());
+        sleep_millis(50);
+        add_mock_transaction(&transaction_pool, &blockchain);
+        runner.join().unwrap().unwrap();
+
+        assert!(started_at.elapsed().as_secs() < 30);
+        assert_eq!(blockchain.get_all_blocks().len(), 2);
+    }
+
+    #[test]
+    fn test_run_waits_for_a_batch_until_the_limit() {
+        let mut miner = create_miner(EASY_TARGET, 1_000);
+        miner.tx_waiting_ms = 60_000;
+        miner.batch_policy = BatchPolicy {
+            min_transactions: 2,
+            max_wait_ms: 200,
+        };
+
+        let started_at = Instant::now();
+        add_mock_transaction(&miner.transaction_pool, &miner.blockchain);
+        miner.run().unwrap();
+
+        // a single transaction is mined once it waited for the maximum time
+        assert!(started_at.elapsed().as_millis() >= 200);
+        let blocks = miner.blockchain.get_all_blocks();
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[1].transactions.len(), 2);
     }
 
     fn create_default_miner() -> Miner {
-        let difficulty = 1;
+        let target = EASY_TARGET;
         let max_nonce = 1;
-        create_miner(difficulty, max_nonce)
+        create_miner(target, max_nonce)
     }
 
-    fn create_miner(difficulty: u32, max_nonce: u64) -> Miner {
+    fn create_miner(target: CompactTarget, max_nonce: u64) -> Miner {
+        create_miner_with(Arc::new(ProofOfWork::new(max_nonce, 1)), target)
+    }
+
+    fn create_miner_with(consensus: SyncedConsensus, target: CompactTarget) -> Miner {
         let max_blocks = 1;
         let tx_waiting_ms = 1;
 
-        let blockchain = Blockchain::new(difficulty);
-        let transaction_pool = TransactionPool::new();
+        let node = TestNode::builder().consensus(consensus).target(target).build();
 
         Miner {
             max_blocks,
-            max_nonce,
             tx_waiting_ms,
-            blockchain,
-            transaction_pool,
-            target: difficulty,
+            batch_policy: BatchPolicy {
+                min_transactions: 0,
+                max_wait_ms: 0,
+            },
+            miner_address: "miner".to_string(),
+            blockchain: node.blockchain,
+            transaction_pool: node.pool,
+            network: node.network,
+            stop_signal: StopSignal::default(),
         }
     }
 
+    fn mine_on_current_tip(miner: &Miner, last_block: &Block, target: CompactTarget) -> MiningOutcome {
+        let tip_changes = miner.blockchain.get_tip_changes();
+        miner.mine_block(last_block, target, Vec::new(), tip_changes).unwrap()
+    }
+
     fn create_empty_block() -> Block {
-        return Block::new(0, 0, Some(BlockHash::default()), Vec::new());
+        Block::new(0, 0, 0, Some(BlockHash::default()), Vec::new())
     }
 
-    fn add_mock_transaction(pool: &TransactionPool) {
-        let transaction = Transaction {
-            sender: "1".to_string(),
-            recipient: "2".to_string(),
-            amount: 3,
-        };
-        pool.add_transaction(transaction.clone());
+    fn add_mock_transaction(pool: &TransactionPool, blockchain: &Blockchain) {
+        let transaction =
+            Transaction::new_signed(&create_signing_key(1), "2".to_string(), 3, FEE, 0);
+        pool.add_transaction(transaction, &blockchain.get_world_state())
+            .unwrap();
     }
 
-    fn assert_mined_block_is_valid(mined_block: &Block, previous_block: &Block, difficulty: u32) {
-        assert_eq!(mined_block.index, previous_block.index + 1);
+    fn assert_mined_block_is_valid(mined_block: &Block, previous_block: &Block, target: CompactTarget) {
+        assert_eq!(mined_block.header.index, previous_block.header.index + 1);
         assert_eq!(
-            mined_block.previous_hash.as_ref().unwrap(),
-            &previous_block.hash
+            mined_block.header.previous_hash.as_ref().unwrap(),
+            &previous_block.header.hash
         );
-        assert!(mined_block
-            .hash
-            .starts_with(&"0".repeat(difficulty as usize)));
+        assert!(meets_target(&mined_block.header.hash, target));
     }
 }
@@ -0,0 +1,338 @@
+use crate::p2p::message::{read_message, write_message, Handshake, Message};
+use crate::types::block::BlockHash;
+use log::{debug, warn};
+use std::collections::{HashMap, HashSet};
+use std::io::{self, BufReader};
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+// A peer that does not accept writes or does not complete the handshake in time is dropped
+pub const TIMEOUT_MS: u64 = 5_000;
+
+// Dials run on their own threads, a round starts at most this many of them
+pub const MAX_DIALS_PER_ROUND: usize = 16;
+
+// An address that can not be reached is retried after a delay doubling with every failure,
+// starting from this delay and up to the longest one
+pub const MIN_DIAL_BACKOFF_MS: u64 = 2_000;
+pub const MAX_DIAL_BACKOFF_MS: u64 = 60_000;
+
+#[derive(Error, Debug)]
+pub enum HandshakeError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("peer follows a chain with genesis `{0}`")]
+    GenesisMismatch(BlockHash),
+    #[error("peer did not start with a handshake")]
+    MissingHandshake,
+}
+
+// Write half of a connection, locked on its own so a slow peer only holds up the writes to it
+type SyncedWriter = Arc<Mutex<TcpStream>>;
+
+// An open connection, after a successful handshake
+#[derive(Debug)]
+pub struct Connection<T> {
+    // Address the other side is known by
+    pub address: String,
+    // What the owner of the connections keeps about the other side
+    pub info: T,
+    writer: SyncedWriter,
+}
+
+// Open connections by id, shared by the threads reading from them
+// A connection is written to without holding the others, and dropped when a write fails
+#[derive(Debug, Clone)]
+pub struct Connections<T> {
+    // What the other side is called in the logs
+    kind: &'static str,
+    connections: Arc<Mutex<HashMap<u64, Connection<T>>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl<T> Connections<T> {
+    pub fn new(kind: &'static str) -> Connections<T> {
+        Connections {
+            kind,
+            connections: Arc::default(),
+            next_id: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    // Keeps a connection that completed its handshake, returns its id
+    pub fn add(&self, address: String, info: T, writer: TcpStream) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.connections.lock().unwrap().insert(
+            id,
+            Connection {
+                address,
+                info,
+                writer: Arc::new(Mutex::new(writer)),
+            },
+        );
+
+        id
+    }
+
+    // Forgets a connection whose reading thread stopped
+    pub fn remove(&self, id: u64) {
+        self.connections.lock().unwrap().remove(&id);
+    }
+
+    // Calls `f` on every connection, keeping what it returns
+    pub fn filter_map<R>(&self, f: impl FnMut((&u64, &Connection<T>)) -> Option<R>) -> Vec<R> {
+        self.connections.lock().unwrap().iter().filter_map(f).collect()
+    }
+
+    // Calls `f` on a connection, `None` if it is closed
+    pub fn with<R>(&self, id: u64, f: impl FnOnce(&mut Connection<T>) -> R) -> Option<R> {
+        self.connections.lock().unwrap().get_mut(&id).map(f)
+    }
+
+    // Sends a message to a connection, returns whether it was written
+    pub fn send(&self, id: u64, message: &Message) -> bool {
+        let writer = match self.connections.lock().unwrap().get(&id) {
+            Some(connection) => connection.writer.clone(),
+            None => return false,
+        };
+        self.write_to(id, &writer, message)
+    }
+
+    // Sends a message to every connection except the given one,
+    // returns to how many connections it was written
+    // The connections are not locked while writing, which can take up to the write timeout
+    pub fn broadcast(&self, message: &Message, except: Option<u64>) -> usize {
+        let writers: Vec<(u64, SyncedWriter)> = self
+            .connections
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(id, _)| Some(**id) != except)
+            .map(|(id, connection)| (*id, connection.writer.clone()))
+            .collect();
+
+        writers
+            .iter()
+            .filter(|(id, writer)| self.write_to(*id, writer, message))
+            .count()
+    }
+
+    // Closes a connection, which also stops the thread reading from it
+    pub fn disconnect(&self, id: u64) {
+        // the connections are released before waiting on a writer that may be blocked in a write
+        let connection = self.connections.lock().unwrap().remove(&id);
+        if let Some(connection) = connection {
+            let _ = connection.writer.lock().unwrap().shutdown(Shutdown::Both);
+        }
+    }
+
+    // Writes a message without holding the connections, dropping the connection if it fails
+    fn write_to(&self, id: u64, writer: &SyncedWriter, message: &Message) -> bool {
+        let result = write_message(&mut *writer.lock().unwrap(), message);
+        match result {
+            Ok(_) => true,
+            Err(error) => {
+                if let Some(connection) = self.connections.lock().unwrap().remove(&id) {
+                    warn!("dropping {} {}: {}", self.kind, connection.address, error);
+                }
+                false
+            }
+        }
+    }
+}
+
+// Failed dials of an address since it was last reached
+#[derive(Debug)]
+struct DialFailures {
+    count: u32,
+    retry_at: Instant,
+}
+
+// Opens connections on their own threads, so an unreachable address does not hold up the others
+// An address is not dialed again while a dial to it, or the connection that dial opened,
+// is still running, and an address that could not be reached is backed off from
+#[derive(Debug, Clone, Default)]
+pub struct Dialer {
+    // Addresses with a dial in progress or a connection opened by a dial
+    dialing: Arc<Mutex<HashSet<String>>>,
+    failures: Arc<Mutex<HashMap<String, DialFailures>>>,
+}
+
+impl Dialer {
+    // Starts dials to the addresses that are not connected, not being dialed and not backing off
+    // `run` gets the address and the outcome of the dial on the thread of the dial,
+    // and the address can be dialed again once `run` returns
+    pub fn dial<F>(&self, addresses: Vec<String>, connected: &HashSet<String>, run: F)
+    where
+        F: Fn(String, io::Result<TcpStream>) + Send + Clone + 'static,
+    {
+        let now = Instant::now();
+        let due: Vec<String> = {
+            let mut dialing = self.dialing.lock().unwrap();
+            let failures = self.failures.lock().unwrap();
+            let due: Vec<String> = addresses
+                .into_iter()
+                .filter(|address| !connected.contains(address) && !dialing.contains(address))
+                .filter(|address| {
+                    failures
+                        .get(address)
+                        .is_none_or(|failures| failures.retry_at <= now)
+                })
+                .take(MAX_DIALS_PER_ROUND)
+                .collect();
+            dialing.extend(due.iter().cloned());
+            due
+        };
+
+        for address in due {
+            let dialer = self.clone();
+            let run = run.clone();
+            thread::spawn(move || {
+                let stream = connect(&address);
+                if let Err(error) = &stream {
+                    debug!("could not connect to {}: {}", address, error);
+                }
+                run(address.clone(), stream);
+                dialer.dialing.lock().unwrap().remove(&address);
+            });
+        }
+    }
+
+    // Backs off from an address that could not be reached or lost its connection,
+    // returns how many times in a row it failed
+    pub fn record_failure(&self, address: &str) -> u32 {
+        let mut failures = self.failures.lock().unwrap();
+        let failures = failures
+            .entry(address.to_string())
+            .or_insert(DialFailures {
+                count: 0,
+                retry_at: Instant::now(),
+            });
+        failures.count += 1;
+
+        let backoff_ms = MIN_DIAL_BACKOFF_MS
+            .saturating_mul(1 << failures.count.min(16))
+            .min(MAX_DIAL_BACKOFF_MS);
+        failures.retry_at = Instant::now() + Duration::from_millis(backoff_ms);
+
+        failures.count
+    }
+
+    // Forgets the failures of an address, once it was reached or when it is not dialed anymore
+    pub fn reset(&self, address: &str) {
+        self.failures.lock().unwrap().remove(address);
+    }
+}
+
+pub fn connect(address: &str) -> io::Result<TcpStream> {
+    let socket_address = address
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "address did not resolve"))?;
+
+    TcpStream::connect_timeout(&socket_address, Duration::from_millis(TIMEOUT_MS))
+}
+
+// Exchanges handshakes over a new connection and checks that both sides follow the same chain
+// Returns the handshake of the other side with the write half and the reader of the connection,
+// which no longer times out since an idle peer is not an error once the handshake is done
+pub fn handshake(
+    stream: TcpStream,
+    own_handshake: Handshake,
+) -> Result<(Handshake, TcpStream, BufReader<TcpStream>), HandshakeError> {
+    let timeout = Some(Duration::from_millis(TIMEOUT_MS));
+    stream.set_write_timeout(timeout)?;
+    stream.set_read_timeout(timeout)?;
+
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    let genesis_hash = own_handshake.genesis_hash.clone();
+    write_message(&mut writer, &Message::Handshake(own_handshake))?;
+
+    let handshake = match read_message(&mut reader)? {
+        Some(Message::Handshake(handshake)) => handshake,
+        _ => return Err(HandshakeError::MissingHandshake),
+    };
+    if handshake.genesis_hash != genesis_hash {
+        return Err(HandshakeError::GenesisMismatch(handshake.genesis_hash));
+    }
+
+    reader.get_ref().set_read_timeout(None)?;
+
+    Ok((handshake, writer, reader))
+}
+
+// Handles the messages of a connection until the other side closes it
+pub fn read_messages(
+    reader: &mut BufReader<TcpStream>,
+    mut handle_message: impl FnMut(Message),
+) -> io::Result<()> {
+    while let Some(message) = read_message(reader)? {
+        handle_message(message);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dials_do_not_hold_up_the_caller() {
+        let dialer = Dialer::default();
+        // addresses that do not answer keep a dial waiting until the connect timeout
+        let addresses = (0..100).map(|i| format!("10.255.255.{}:1", i)).collect();
+
+        let started = Instant::now();
+        dialer.dial(addresses, &HashSet::new(), |_, _| {});
+        assert!(started.elapsed() < Duration::from_millis(TIMEOUT_MS));
+        assert!(dialer.dialing.lock().unwrap().len() <= MAX_DIALS_PER_ROUND);
+    }
+
+    #[test]
+    fn address_is_not_dialed_while_its_connection_runs() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let dialer = Dialer::default();
+
+        // every connection runs until the end of the test
+        let (dialed, dials) = crossbeam::channel::unbounded();
+        let (_release, released) = crossbeam::channel::bounded::<()>(0);
+        let run = move |address: String, _| {
+            let _ = dialed.send(address);
+            let _ = released.recv();
+        };
+        dialer.dial(vec![address.clone()], &HashSet::new(), run.clone());
+        assert_eq!(dials.recv_timeout(Duration::from_millis(TIMEOUT_MS)), Ok(address.clone()));
+
+        dialer.dial(vec![address], &HashSet::new(), run);
+        assert!(dials.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+
+    #[test]
+    fn unreachable_addresses_are_backed_off_from() {
+        let dialer = Dialer::default();
+        let address = "127.0.0.1:1".to_string();
+
+        assert_eq!(dialer.record_failure(&address), 1);
+        let first_retry = dialer.failures.lock().unwrap()[&address].retry_at;
+        assert!(first_retry > Instant::now());
+        assert_eq!(dialer.record_failure(&address), 2);
+        assert!(dialer.failures.lock().unwrap()[&address].retry_at > first_retry);
+
+        // an address backing off is skipped
+        let (sender, receiver) = crossbeam::channel::unbounded();
+        dialer.dial(vec![address.clone()], &HashSet::new(), move |address, _| {
+            let _ = sender.send(address);
+        });
+        assert!(receiver.recv_timeout(Duration::from_millis(100)).is_err());
+
+        dialer.reset(&address);
+        assert_eq!(dialer.record_failure(&address), 1);
+    }
+}
@@ -0,0 +1,412 @@
+use crate::p2p::connection::{self, Connections, Dialer, HandshakeError, TIMEOUT_MS};
+use crate::p2p::message::{Handshake, Message};
+use crate::p2p::network::DIAL_INTERVAL_MS;
+use crate::p2p::sync::MAX_HEADERS;
+use crate::types::block::{BlockHash, BlockHeader};
+use crate::types::blockchain::BlockchainError;
+use crate::types::header_chain::HeaderChain;
+use crate::types::merkle::{verify_proof, InclusionProof};
+use crate::types::transaction::TransactionHash;
+use crate::util::execution::{sleep_millis, Runnable};
+use anyhow::Result;
+use crossbeam::channel::{self, Sender};
+use log::{debug, info, warn};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum LightClientError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Handshake(#[from] HandshakeError),
+    #[error("not connected to any full node")]
+    NoPeers,
+    #[error("no full node answered in time")]
+    Timeout,
+    #[error("transaction is not in the main chain of the full nodes")]
+    NotFound,
+    #[error("the proof is invalid")]
+    InvalidProof,
+    #[error("block `{0}` of the proof is not on the main chain of the headers")]
+    NotOnMainChain(BlockHash),
+}
+
+// A transaction proven against a header of the main chain
+#[derive(Debug, Serialize)]
+pub struct VerifiedProof {
+    pub proof: InclusionProof,
+    // Headers of the main chain from the block of the transaction up to the tip
+    pub confirmations: u64,
+}
+
+// Headers downloaded by the light client, as reported by the API
+#[derive(Debug, Serialize)]
+pub struct LightClientStatus {
+    pub peers: Vec<String>,
+    pub height: u64,
+    pub tip: BlockHash,
+}
+
+// Answers to a proof request, `None` when a full node does not have the transaction
+type ProofSender = Sender<Option<InclusionProof>>;
+
+// Node that keeps only the headers of the chain, downloaded from the configured full nodes
+// and validated like the headers of the blocks of a full node
+// Transactions are checked with Merkle proofs fetched from the full nodes,
+// so the full nodes are trusted to answer but not to tell the truth
+#[derive(Debug, Clone)]
+pub struct LightClient {
+    node_id: u64,
+    headers: HeaderChain,
+    full_nodes: Vec<String>,
+    connections: Connections<()>,
+    dialer: Dialer,
+    // Proof requests waiting for an answer, by transaction
+    pending_proofs: Arc<Mutex<HashMap<TransactionHash, Vec<ProofSender>>>>,
+}
+
+impl Runnable for LightClient {
+    fn run(&self) -> Result<()> {
+        self.start();
+
+        Ok(())
+    }
+}
+
+impl LightClient {
+    pub fn new(full_nodes: Vec<String>, headers: HeaderChain) -> LightClient {
+        LightClient {
+            node_id: rand::random(),
+            headers,
+            full_nodes,
+            connections: Connections::new("full node"),
+            dialer: Dialer::default(),
+            pending_proofs: Arc::default(),
+        }
+    }
+
+    // Stays connected to the full nodes and asks them for new headers
+    // New blocks are also announced by the full nodes, so this only catches up on missed ones
+    pub fn start(&self) {
+        info!("light client following {:?}", self.full_nodes);
+
+        loop {
+            self.dial_full_nodes();
+            self.broadcast(&Message::GetHeaders(self.headers.get_locator()));
+            sleep_millis(DIAL_INTERVAL_MS);
+        }
+    }
+
+    pub fn get_status(&self) -> LightClientStatus {
+        let last_header = self.headers.get_last_header();
+
+        LightClientStatus {
+            peers: self
+                .connections
+                .filter_map(|(_, connection)| Some(connection.address.clone())),
+            height: last_header.index,
+            tip: last_header.hash,
+        }
+    }
+
+    // Asks the full nodes for the proof that a transaction is included in a block,
+    // and accepts the first one that is valid and proves it against a header of the main chain
+    pub fn get_transaction_proof(
+        &self,
+        transaction_hash: &str,
+    ) -> Result<VerifiedProof, LightClientError> {
+        let (sender, receiver) = channel::unbounded();
+        self.pending_proofs
+            .lock()
+            .unwrap()
+            .entry(transaction_hash.to_string())
+            .or_default()
+            .push(sender.clone());
+
+        let asked = self.broadcast(&Message::GetProof(transaction_hash.to_string()));
+        let result = self.wait_for_proof(transaction_hash, asked, receiver);
+
+        // other requests for the same transaction keep waiting
+        let mut pending_proofs = self.pending_proofs.lock().unwrap();
+        if let Some(senders) = pending_proofs.get_mut(transaction_hash) {
+            senders.retain(|pending| !pending.same_channel(&sender));
+            if senders.is_empty() {
+                pending_proofs.remove(transaction_hash);
+            }
+        }
+
+        result
+    }
+
+    fn wait_for_proof(
+        &self,
+        transaction_hash: &str,
+        asked: usize,
+        receiver: channel::Receiver<Option<InclusionProof>>,
+    ) -> Result<VerifiedProof, LightClientError> {
+        if asked == 0 {
+            return Err(LightClientError::NoPeers);
+        }
+
+        // the last reason a proof was not accepted is reported once every full node answered
+        let deadline = Instant::now() + Duration::from_millis(TIMEOUT_MS);
+        let mut error = LightClientError::Timeout;
+        for _ in 0..asked {
+            let answer = match receiver.recv_deadline(deadline) {
+                Ok(answer) => answer,
+                Err(_) => return Err(LightClientError::Timeout),
+            };
+            match answer.map(|proof| self.check_proof(transaction_hash, proof)) {
+                Some(Ok(verified)) => return Ok(verified),
+                Some(Err(rejection)) => error = rejection,
+                None => {
+                    if matches!(error, LightClientError::Timeout) {
+                        error = LightClientError::NotFound;
+                    }
+                }
+            }
+        }
+
+        Err(error)
+    }
+
+    // A valid proof of a block the light client does not know yet can be checked again
+    // once the headers caught up with the full node
+    fn check_proof(
+        &self,
+        transaction_hash: &str,
+        proof: InclusionProof,
+    ) -> Result<VerifiedProof, LightClientError> {
+        if proof.transaction.hash() != transaction_hash || !verify_proof(&proof) {
+            return Err(LightClientError::InvalidProof);
+        }
+
+        let confirmations = self
+            .headers
+            .get_confirmations(&proof.header.hash)
+            .ok_or_else(|| LightClientError::NotOnMainChain(proof.header.hash.clone()))?;
+
+        Ok(VerifiedProof {
+            proof,
+            confirmations,
+        })
+    }
+
+    // Starts dials to the configured full nodes that have no connection yet and are not backing off,
+    // the same way the full nodes dial their peers
+    fn dial_full_nodes(&self) {
+        let connected: HashSet<String> = self
+            .connections
+            .filter_map(|(_, connection)| Some(connection.address.clone()))
+            .into_iter()
+            .collect();
+
+        let client = self.clone();
+        self.dialer
+            .dial(self.full_nodes.clone(), &connected, move |address, stream| {
+                let result = match stream {
+                    Ok(stream) => client.handle_connection(stream, address.clone()),
+                    Err(error) => Err(error.into()),
+                };
+                if let Err(error) = result {
+                    debug!("connection to {} closed: {}", address, error);
+                    client.dialer.record_failure(&address);
+                }
+            });
+    }
+
+    // Exchanges handshakes with a full node, then handles its messages until the connection closes
+    fn handle_connection(&self, stream: TcpStream, address: String) -> Result<(), LightClientError> {
+        let (handshake, writer, mut reader) = connection::handshake(stream, self.create_handshake())?;
+        info!(
+            "connected to full node {} at height {}",
+            address, handshake.height
+        );
+
+        // a full node that was reached backs off only briefly after losing its connection
+        self.dialer.reset(&address);
+        let connection_id = self.connections.add(address, (), writer);
+        self.send(connection_id, &Message::GetHeaders(self.headers.get_locator()));
+
+        let result = connection::read_messages(&mut reader, |message| {
+            self.handle_message(connection_id, message)
+        });
+        self.connections.remove(connection_id);
+
+        Ok(result?)
+    }
+
+    // Only headers and proofs matter to the light client, the rest of the gossip is dropped
+    fn handle_message(&self, connection_id: u64, message: Message) {
+        match message {
+            Message::Headers(headers) => {
+                let full_answer = headers.len() == MAX_HEADERS;
+                if self.add_headers(connection_id, headers) && full_answer {
+                    self.send(connection_id, &Message::GetHeaders(self.headers.get_locator()));
+                }
+            }
+            Message::Block(block) => {
+                self.add_headers(connection_id, vec![block.header]);
+            }
+            Message::Proof(proof) => self.answer_proof(&proof.transaction.hash(), Some(proof)),
+            Message::NoProof(transaction_hash) => self.answer_proof(&transaction_hash, None),
+            _ => {}
+        }
+    }
+
+    // Adds headers in chain order, returns whether they all were valid
+    // A header that does not follow a known one means headers are missing and asks for them,
+    // and a full node sending an invalid header is disconnected
+    fn add_headers(&self, connection_id: u64, headers: Vec<BlockHeader>) -> bool {
+        for header in headers {
+            let index = header.index;
+            match self.headers.add_header(header) {
+                Ok(_) | Err(BlockchainError::KnownBlock) => {}
+                Err(BlockchainError::UnknownParent) => {
+                    self.send(connection_id, &Message::GetHeaders(self.headers.get_locator()));
+                    return false;
+                }
+                Err(error) => {
+                    warn!("invalid header {} from a full node: {}", index, error);
+                    self.disconnect(connection_id);
+                    return false;
+                }
+            }
+        }
+
+        true
+    }
+
+    fn answer_proof(&self, transaction_hash: &str, proof: Option<InclusionProof>) {
+        if let Some(senders) = self.pending_proofs.lock().unwrap().get(transaction_hash) {
+            for sender in senders {
+                let _ = sender.send(proof.clone());
+            }
+        }
+    }
+
+    // Sends a message to every connected full node, returns to how many it was sent
+    fn broadcast(&self, message: &Message) -> usize {
+        self.connections.broadcast(message, None)
+    }
+
+    fn send(&self, connection_id: u64, message: &Message) {
+        self.connections.send(connection_id, message);
+    }
+
+    // Closes the connection to a full node, which also stops the thread reading from it
+    fn disconnect(&self, connection_id: u64) {
+        self.connections.disconnect(connection_id);
+    }
+
+    fn create_handshake(&self) -> Handshake {
+        Handshake {
+            node_id: self.node_id,
+            genesis_hash: self.headers.get_genesis_hash(),
+            height: self.headers.get_last_header().index,
+            listen_port: 0,
+            light: true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::transaction::Transaction;
+    use crate::util::test_helpers::{create_signing_key, mine_on_tip, proof_of_work, wait_for, TestNode};
+    use std::net::TcpListener;
+    use std::thread;
+
+    // Follows the headers of the chains of the test nodes
+    fn create_client(full_nodes: Vec<String>) -> LightClient {
+        let params = TestNode::builder().build_blockchain().params;
+        LightClient::new(full_nodes, HeaderChain::new(proof_of_work(), &params))
+    }
+
+    #[test]
+    fn light_client_follows_the_headers_and_proves_transactions() {
+        let TestNode {
+            blockchain,
+            network,
+            ..
+        } = TestNode::builder().build();
+        let transaction = Transaction::new_signed(&create_signing_key(1), "bob".to_string(), 3, 1, 0);
+        blockchain
+            .add_block(mine_on_tip(&blockchain, "miner", vec![transaction.clone()]))
+            .unwrap();
+        blockchain.add_block(mine_on_tip(&blockchain, "miner", vec![])).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = network.clone();
+        thread::spawn(move || server.serve(listener));
+
+        let client = create_client(vec![address]);
+        let runner = client.clone();
+        thread::spawn(move || runner.start());
+        wait_for(|| client.get_status().height == 2);
+
+        // a new block announced by the full node extends the headers
+        let block = mine_on_tip(&blockchain, "miner", vec![]);
+        blockchain.add_block(block.clone()).unwrap();
+        network.broadcast_block(&block);
+        wait_for(|| client.get_status().tip == block.header.hash);
+
+        let verified = client.get_transaction_proof(&transaction.hash()).unwrap();
+        assert_eq!(verified.proof.header.index, 1);
+        assert_eq!(verified.confirmations, 3);
+
+        let unknown_hash = hex::encode([0; 32]);
+        assert!(matches!(
+            client.get_transaction_proof(&unknown_hash),
+            Err(LightClientError::NotFound)
+        ));
+    }
+
+    #[test]
+    fn full_node_is_dialed_once_at_a_time() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let network = TestNode::builder().build().network;
+        thread::spawn(move || network.serve(listener));
+
+        // the second round finds the first dial still running
+        let client = create_client(vec![address]);
+        client.dial_full_nodes();
+        client.dial_full_nodes();
+        wait_for(|| client.get_status().peers.len() == 1);
+        client.dial_full_nodes();
+        sleep_millis(200);
+        assert_eq!(client.get_status().peers.len(), 1);
+    }
+
+    #[test]
+    fn proof_of_a_block_off_the_main_chain_is_rejected() {
+        let client = create_client(vec![]);
+        let blockchain = TestNode::builder().build_blockchain();
+        let block = mine_on_tip(&blockchain, "miner", vec![]);
+        blockchain.add_block(block.clone()).unwrap();
+        let coinbase_hash = block.transactions[0].hash();
+
+        let proof = blockchain.get_transaction_proof(&coinbase_hash).unwrap();
+        assert!(matches!(
+            client.check_proof(&coinbase_hash, proof.clone()),
+            Err(LightClientError::NotOnMainChain(_))
+        ));
+        assert!(matches!(
+            client.check_proof(&hex::encode([0; 32]), proof),
+            Err(LightClientError::InvalidProof)
+        ));
+        assert!(matches!(
+            client.get_transaction_proof(&coinbase_hash),
+            Err(LightClientError::NoPeers)
+        ));
+    }
+}
@@ -0,0 +1,106 @@
+use crate::types::block::{Block, BlockHash, BlockHeader};
+use crate::types::merkle::InclusionProof;
+use crate::types::transaction::{Transaction, TransactionHash};
+use serde::{Deserialize, Serialize};
+use std::io::{self, BufRead, Read, Write};
+
+// Longest line read from a peer, far above a batch of full blocks
+// A peer sending a longer line is dropped instead of filling the memory of the node
+pub const MAX_MESSAGE_SIZE: u64 = 16 * 1024 * 1024;
+
+// First message sent by both sides of a connection
+// Nodes only talk to peers that share the same genesis block
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Handshake {
+    pub node_id: u64,
+    pub genesis_hash: BlockHash,
+    pub height: u64,
+    pub listen_port: u16,
+    // Light clients only keep headers, they do not serve blocks or accept connections
+    #[serde(default)]
+    pub light: bool,
+}
+
+// Messages exchanged between nodes, sent as one JSON document per line
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum Message {
+    Handshake(Handshake),
+    Block(Block),
+    Transaction(Transaction),
+    GetPeers,
+    Peers(Vec<String>),
+    // Asks for the headers of the main chain after the first hash of the locator the peer knows
+    GetHeaders(Vec<BlockHash>),
+    Headers(Vec<BlockHeader>),
+    // Asks for blocks by hash, blocks the peer does not know are left out of the answer
+    GetBlocks(Vec<BlockHash>),
+    Blocks(Vec<Block>),
+    // Asks for the proof that a transaction is included in the main chain of the peer
+    GetProof(TransactionHash),
+    Proof(InclusionProof),
+    // Answer to `GetProof` for a transaction that is not in the main chain of the peer
+    NoProof(TransactionHash),
+}
+
+pub fn write_message<W: Write>(writer: &mut W, message: &Message) -> io::Result<()> {
+    let mut line = serde_json::to_vec(message)?;
+    line.push(b'\n');
+    writer.write_all(&line)?;
+    writer.flush()
+}
+
+// Returns `None` once the other side closed the connection
+// and fails on a line longer than `MAX_MESSAGE_SIZE`
+pub fn read_message<R: BufRead>(reader: &mut R) -> io::Result<Option<Message>> {
+    let mut line = Vec::new();
+    let read = reader
+        .by_ref()
+        .take(MAX_MESSAGE_SIZE)
+        .read_until(b'\n', &mut line)?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if read as u64 == MAX_MESSAGE_SIZE && !line.ends_with(b"\n") {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "message exceeds the maximum size",
+        ));
+    }
+
+    let message = serde_json::from_slice(&line)?;
+    Ok(Some(message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufReader;
+
+    #[test]
+    fn messages_survive_a_round_trip() {
+        let mut buffer = Vec::new();
+        write_message(&mut buffer, &Message::GetPeers).unwrap();
+        write_message(&mut buffer, &Message::Peers(vec!["127.0.0.1:9000".to_string()])).unwrap();
+
+        let mut reader = BufReader::new(buffer.as_slice());
+        assert!(matches!(
+            read_message(&mut reader).unwrap(),
+            Some(Message::GetPeers)
+        ));
+        match read_message(&mut reader).unwrap() {
+            Some(Message::Peers(peers)) => assert_eq!(peers, vec!["127.0.0.1:9000"]),
+            message => panic!("unexpected message {:?}", message),
+        }
+        assert!(read_message(&mut reader).unwrap().is_none());
+    }
+
+    #[test]
+    fn oversized_message_is_rejected() {
+        let line = vec![b' '; MAX_MESSAGE_SIZE as usize + 1];
+
+        let mut reader = BufReader::new(line.as_slice());
+        let error = read_message(&mut reader).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
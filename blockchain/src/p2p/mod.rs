@@ -0,0 +1,5 @@
+pub(crate) mod connection;
+pub(crate) mod light_client;
+pub(crate) mod message;
+pub(crate) mod network;
+pub(crate) mod sync;
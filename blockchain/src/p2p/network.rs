@@ -0,0 +1,635 @@
+use crate::p2p::connection::{self, Connections, Dialer, HandshakeError};
+use crate::p2p::message::{Handshake, Message};
+use crate::p2p::sync::{
+    Requests, SyncError, SyncPeer, SyncStatus, Synchronizer, BATCH_SIZE, MAX_HEADERS,
+};
+use crate::types::block::Block;
+use crate::types::blockchain::{BlockStatus, BlockchainError};
+use crate::types::transaction::Transaction;
+use crate::util::execution::{sleep_millis, Runnable};
+use crate::{Blockchain, Context, TransactionPool};
+use anyhow::Result;
+use log::{debug, info, warn};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use thiserror::Error;
+
+// How often the node tries to connect to the known peers it is not connected to
+pub const DIAL_INTERVAL_MS: u64 = 2_000;
+
+// How often stalled block requests are retried and peers ahead of the node are synced from
+pub const SYNC_INTERVAL_MS: u64 = 2_000;
+
+// An address learned from a peer is forgotten after this many failed dials in a row
+pub const MAX_DIAL_FAILURES: u32 = 3;
+
+// Addresses learned from peers are bounded, so a peer can not make the node dial without end
+pub const MAX_KNOWN_ADDRESSES: usize = 1_000;
+pub const MAX_ADDRESSES_PER_MESSAGE: usize = 100;
+
+#[derive(Error, Debug)]
+pub enum NetworkError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Handshake(#[from] HandshakeError),
+    #[error("connected to itself")]
+    SelfConnection,
+}
+
+// What the node keeps about a connected peer, whose address is the one it accepts connections on
+#[derive(Debug, Clone)]
+struct Peer {
+    height: u64,
+    // Light clients get the new blocks but are never asked for blocks
+    light: bool,
+}
+
+// Connected peer as reported by the API
+#[derive(Debug, Serialize)]
+pub struct PeerInfo {
+    pub address: String,
+    pub height: u64,
+}
+
+type SyncedAddresses = Arc<Mutex<HashSet<String>>>;
+
+// Handle to the peer-to-peer network of the node
+// Every connection has its own thread reading messages from the peer,
+// and blocks and transactions accepted by the node are sent to every connected peer
+// Peers learn the addresses of each other's peers, so a node only needs a few bootstrap peers
+#[derive(Debug, Clone)]
+pub struct Network {
+    // Random identifier used to detect connections of the node to itself
+    node_id: u64,
+    listen_port: u16,
+    blockchain: Blockchain,
+    pool: TransactionPool,
+    peers: Connections<Peer>,
+    // Addresses the node tries to stay connected to
+    known_addresses: SyncedAddresses,
+    // Bootstrap peers are retried forever, unlike the addresses learned from peers
+    bootstrap_addresses: Arc<HashSet<String>>,
+    dialer: Dialer,
+    // Addresses of the node itself or of nodes following another chain
+    ignored_addresses: SyncedAddresses,
+    sync: Synchronizer,
+}
+
+impl Network {
+    pub fn new(
+        listen_port: u16,
+        bootstrap_peers: Vec<String>,
+        blockchain: Blockchain,
+        pool: TransactionPool,
+    ) -> Network {
+        let bootstrap_addresses: HashSet<String> = bootstrap_peers.into_iter().collect();
+        Network {
+            node_id: rand::random(),
+            listen_port,
+            peers: Connections::new("peer"),
+            known_addresses: Arc::new(Mutex::new(bootstrap_addresses.clone())),
+            bootstrap_addresses: Arc::new(bootstrap_addresses),
+            dialer: Dialer::default(),
+            ignored_addresses: SyncedAddresses::default(),
+            sync: Synchronizer::new(blockchain.clone(), pool.clone()),
+            blockchain,
+            pool,
+        }
+    }
+
+    // Returns the connected peers with the highest block they are known to have
+    pub fn get_peers(&self) -> Vec<PeerInfo> {
+        self.peers.filter_map(|(_, peer)| {
+            Some(PeerInfo {
+                address: peer.address.clone(),
+                height: peer.info.height,
+            })
+        })
+    }
+
+    // Returns the progress of the block download
+    pub fn get_sync_status(&self) -> SyncStatus {
+        self.sync.status()
+    }
+
+    // Sends a block accepted by the node to all the peers
+    pub fn broadcast_block(&self, block: &Block) {
+        self.broadcast(&Message::Block(block.clone()), None);
+    }
+
+    // Sends a transaction accepted into the pool to all the peers
+    pub fn broadcast_transaction(&self, transaction: &Transaction) {
+        self.broadcast(&Message::Transaction(transaction.clone()), None);
+    }
+
+    // Sends a message to every peer except the one it came from,
+    // dropping the peers the message can not be written to
+    fn broadcast(&self, message: &Message, except: Option<u64>) {
+        self.peers.broadcast(message, except);
+    }
+
+    // Accepts connections from other nodes while connecting to the known ones in the background
+    // The block download is maintained on its own thread, so it never waits on the dials
+    pub fn serve(&self, listener: TcpListener) -> Result<()> {
+        info!("p2p listening on {}", listener.local_addr()?);
+
+        let network = self.clone();
+        thread::spawn(move || loop {
+            network.dial_known_addresses();
+            network.broadcast(&Message::GetPeers, None);
+            sleep_millis(DIAL_INTERVAL_MS);
+        });
+
+        let network = self.clone();
+        thread::spawn(move || loop {
+            network.maintain_sync();
+            sleep_millis(SYNC_INTERVAL_MS);
+        });
+
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(error) => {
+                    warn!("could not accept a peer: {}", error);
+                    continue;
+                }
+            };
+
+            let network = self.clone();
+            thread::spawn(move || network.run_connection(stream, None));
+        }
+
+        Ok(())
+    }
+
+    // Starts dials to the known addresses that have no connection yet and are not backing off,
+    // each on its own thread so an unreachable address does not hold up the others
+    fn dial_known_addresses(&self) {
+        let connected: HashSet<String> = self
+            .peers
+            .filter_map(|(_, peer)| Some(peer.address.clone()))
+            .into_iter()
+            .collect();
+        let addresses = self.known_addresses.lock().unwrap().iter().cloned().collect();
+
+        let network = self.clone();
+        self.dialer
+            .dial(addresses, &connected, move |address, stream| match stream {
+                Ok(stream) => network.run_connection(stream, Some(address)),
+                Err(_) => network.record_dial_failure(&address),
+            });
+    }
+
+    fn run_connection(&self, stream: TcpStream, dialed_address: Option<String>) {
+        let result = self.handle_connection(stream, dialed_address.clone());
+        match (result, dialed_address) {
+            (Err(NetworkError::SelfConnection), Some(address))
+            | (Err(NetworkError::Handshake(HandshakeError::GenesisMismatch(_))), Some(address)) => {
+                info!("ignoring peer {}", address);
+                self.known_addresses.lock().unwrap().remove(&address);
+                self.ignored_addresses.lock().unwrap().insert(address);
+            }
+            // the failures are reset once the handshake is done,
+            // so a peer that was reached backs off only briefly after losing its connection
+            (Err(error), Some(address)) => {
+                debug!("connection to {} closed: {}", address, error);
+                self.record_dial_failure(&address);
+            }
+            (Err(error), None) => debug!("peer connection closed: {}", error),
+            (Ok(_), _) => {}
+        }
+    }
+
+    // Backs off from an address that could not be reached,
+    // and forgets it after too many failures unless it is a bootstrap peer
+    fn record_dial_failure(&self, address: &str) {
+        let failures = self.dialer.record_failure(address);
+        if failures >= MAX_DIAL_FAILURES && !self.bootstrap_addresses.contains(address) {
+            debug!("forgetting unreachable peer {}", address);
+            self.dialer.reset(address);
+            self.known_addresses.lock().unwrap().remove(address);
+        }
+    }
+
+    // Exchanges handshakes with a peer, then handles its messages until the connection closes
+    fn handle_connection(
+        &self,
+        stream: TcpStream,
+        dialed_address: Option<String>,
+    ) -> Result<(), NetworkError> {
+        let (handshake, writer, mut reader) = connection::handshake(stream, self.create_handshake())?;
+        if handshake.node_id == self.node_id {
+            return Err(NetworkError::SelfConnection);
+        }
+
+        // peers that connected to us are reachable on their own listening port,
+        // except light clients which do not listen
+        let address = match dialed_address {
+            Some(address) => address,
+            None if handshake.light => writer.peer_addr()?.to_string(),
+            None => SocketAddr::new(writer.peer_addr()?.ip(), handshake.listen_port).to_string(),
+        };
+        if handshake.light {
+            info!("connected to light client {}", address);
+        } else {
+            info!(
+                "connected to peer {} at height {}",
+                address, handshake.height
+            );
+        }
+
+        if !handshake.light {
+            self.known_addresses.lock().unwrap().insert(address.clone());
+            self.dialer.reset(&address);
+        }
+        let peer = Peer {
+            height: handshake.height,
+            light: handshake.light,
+        };
+        let connection_id = self.peers.add(address.clone(), peer, writer);
+
+        // catch up with a peer that is ahead
+        if !handshake.light {
+            if let Some(request) = self.sync.start(connection_id, &address, handshake.height) {
+                self.send(connection_id, &request);
+            }
+        }
+
+        let result = connection::read_messages(&mut reader, |message| {
+            self.handle_message(connection_id, message)
+        });
+        self.peers.remove(connection_id);
+
+        Ok(result?)
+    }
+
+    // Blocks and transactions are relayed to the other peers only when the node accepts them,
+    // so a message seen before is dropped instead of going around the network forever
+    fn handle_message(&self, connection_id: u64, message: Message) {
+        match message {
+            Message::Block(block) => match self.blockchain.add_block(block.clone()) {
+                Ok(status) => {
+                    // the transactions of the block are no longer pending
+                    self.blockchain.with_world_state(|state| {
+                        self.pool.remove_executed(state);
+                        if let BlockStatus::Reorganized(orphaned) = status {
+                            self.pool.add_orphaned(orphaned, state);
+                        }
+                    });
+                    self.update_height(connection_id, block.header.index);
+                    self.broadcast(&Message::Block(block), Some(connection_id));
+                }
+                // a block far ahead of the chain means the peer has blocks the node is missing
+                Err(BlockchainError::UnknownParent) => {
+                    self.update_height(connection_id, block.header.index);
+                    self.start_sync(connection_id, block.header.index);
+                }
+                Err(error) => debug!("ignoring block {}: {}", block.header.index, error),
+            },
+            Message::Transaction(transaction) => {
+                let result = self.blockchain.with_world_state(|state| {
+                    self.pool.add_transaction(transaction.clone(), state)
+                });
+                match result {
+                    Ok(_) => {
+                        self.broadcast(&Message::Transaction(transaction), Some(connection_id))
+                    }
+                    Err(error) => debug!("ignoring transaction: {}", error),
+                }
+            }
+            // only the addresses of connected peers are shared,
+            // so addresses nobody could reach are not passed around the network
+            Message::GetPeers => {
+                let mut addresses = self.peers.filter_map(|(id, peer)| {
+                    (*id != connection_id && !peer.info.light).then(|| peer.address.clone())
+                });
+                addresses.truncate(MAX_ADDRESSES_PER_MESSAGE);
+                self.send(connection_id, &Message::Peers(addresses));
+            }
+            Message::Peers(addresses) => {
+                let ignored_addresses = self.ignored_addresses.lock().unwrap();
+                let mut known_addresses = self.known_addresses.lock().unwrap();
+                for address in addresses.into_iter().take(MAX_ADDRESSES_PER_MESSAGE) {
+                    if known_addresses.len() >= MAX_KNOWN_ADDRESSES {
+                        break;
+                    }
+                    if !ignored_addresses.contains(&address) {
+                        known_addresses.insert(address);
+                    }
+                }
+            }
+            Message::GetHeaders(locator) => {
+                let headers = self.blockchain.get_headers_after(&locator, MAX_HEADERS);
+                self.send(connection_id, &Message::Headers(headers));
+            }
+            Message::Headers(headers) => {
+                if let Some(last_header) = headers.last() {
+                    self.update_height(connection_id, last_header.index);
+                }
+                let result = self
+                    .sync
+                    .on_headers(connection_id, headers, &self.sync_peers());
+                self.handle_sync_result(connection_id, result);
+            }
+            Message::GetBlocks(hashes) => {
+                let hashes = &hashes[..hashes.len().min(BATCH_SIZE)];
+                let blocks = self.blockchain.get_blocks_by_hash(hashes);
+                self.send(connection_id, &Message::Blocks(blocks));
+            }
+            Message::Blocks(blocks) => {
+                let result = self.sync.on_blocks(blocks, &self.sync_peers());
+                self.handle_sync_result(connection_id, result);
+            }
+            Message::GetProof(transaction_hash) => {
+                let answer = match self.blockchain.get_transaction_proof(&transaction_hash) {
+                    Some(proof) => Message::Proof(proof),
+                    None => Message::NoProof(transaction_hash),
+                };
+                self.send(connection_id, &answer);
+            }
+            Message::Proof(_) | Message::NoProof(_) => debug!("ignoring unrequested proof"),
+            Message::Handshake(_) => debug!("ignoring repeated handshake"),
+        }
+    }
+
+    // Sends the requests of the block download, or disconnects the peer that made it fail
+    fn handle_sync_result(&self, connection_id: u64, result: Result<Requests, SyncError>) {
+        match result {
+            Ok(requests) => self.send_requests(requests),
+            Err(error) => {
+                warn!("block download failed: {}", error);
+                self.disconnect(connection_id);
+            }
+        }
+    }
+
+    // Retries the stalled requests of the block download,
+    // or starts a new download if a peer is ahead of the node
+    fn maintain_sync(&self) {
+        let requests = self.sync.tick(&self.sync_peers());
+        self.send_requests(requests);
+
+        let highest_peer = self
+            .sync_peers()
+            .into_iter()
+            .max_by_key(|peer| peer.height);
+        if let Some(peer) = highest_peer {
+            self.start_sync(peer.connection_id, peer.height);
+        }
+    }
+
+    fn start_sync(&self, connection_id: u64, height: u64) {
+        let address = match self.peers.with(connection_id, |peer| peer.address.clone()) {
+            Some(address) => address,
+            None => return,
+        };
+        if let Some(request) = self.sync.start(connection_id, &address, height) {
+            self.send(connection_id, &request);
+        }
+    }
+
+    fn sync_peers(&self) -> Vec<SyncPeer> {
+        self.peers.filter_map(|(connection_id, peer)| {
+            (!peer.info.light).then_some(SyncPeer {
+                connection_id: *connection_id,
+                height: peer.info.height,
+            })
+        })
+    }
+
+    fn send_requests(&self, requests: Requests) {
+        for (connection_id, request) in requests {
+            self.send(connection_id, &request);
+        }
+    }
+
+    // Closes the connection to a peer, which also stops the thread reading from it
+    fn disconnect(&self, connection_id: u64) {
+        self.peers.disconnect(connection_id);
+    }
+
+    fn send(&self, connection_id: u64, message: &Message) {
+        self.peers.send(connection_id, message);
+    }
+
+    fn update_height(&self, connection_id: u64, height: u64) {
+        self.peers.with(connection_id, |peer| {
+            peer.info.height = peer.info.height.max(height)
+        });
+    }
+
+    fn create_handshake(&self) -> Handshake {
+        Handshake {
+            node_id: self.node_id,
+            genesis_hash: self.blockchain.get_genesis_hash(),
+            height: self.blockchain.get_last_block().header.index,
+            listen_port: self.listen_port,
+            light: false,
+        }
+    }
+}
+
+pub struct P2pServer {
+    port: u16,
+    network: Network,
+}
+
+impl Runnable for P2pServer {
+    fn run(&self) -> Result<()> {
+        let listener = TcpListener::bind(("0.0.0.0", self.port))?;
+
+        self.network.serve(listener)
+    }
+}
+
+impl P2pServer {
+    pub fn new(context: &Context) -> P2pServer {
+        P2pServer {
+            port: context.config.p2p_port,
+            network: context.network.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::p2p::message::{read_message, write_message};
+    use crate::types::hash::Hash256;
+    use crate::util::test_helpers::{create_signing_key, mine_on_tip, wait_for, TestNode};
+    use std::io::BufReader;
+
+    fn create_blockchain() -> Blockchain {
+        TestNode::builder().build_blockchain()
+    }
+
+    // Starts a node on a free local port, connected to the given bootstrap peers
+    fn start_node(blockchain: Blockchain, bootstrap_peers: Vec<String>) -> (Network, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let network = TestNode::builder()
+            .listen_port(listener.local_addr().unwrap().port())
+            .peers(bootstrap_peers)
+            .build_with(blockchain)
+            .network;
+
+        let server = network.clone();
+        thread::spawn(move || server.serve(listener));
+
+        (network, address)
+    }
+
+    fn peer_count(network: &Network) -> usize {
+        network.get_peers().len()
+    }
+
+    #[test]
+    fn blocks_and_transactions_reach_every_node() {
+        // nodes 2 and 3 only know about node 1, and learn about each other through it
+        let (node1, address1) = start_node(create_blockchain(), vec![]);
+        let (node2, _) = start_node(create_blockchain(), vec![address1.clone()]);
+        let (node3, _) = start_node(create_blockchain(), vec![address1]);
+        wait_for(|| peer_count(&node2) >= 2 && peer_count(&node3) >= 2);
+
+        let block = mine_on_tip(&node2.blockchain, "miner", vec![]);
+        node2.blockchain.add_block(block.clone()).unwrap();
+        node2.broadcast_block(&block);
+        for node in [&node1, &node3] {
+            wait_for(|| node.blockchain.get_last_block().header.hash == block.header.hash);
+        }
+
+        let transaction = Transaction::new_signed(&create_signing_key(1), "bob".to_string(), 3, 1, 0);
+        let state = node3.blockchain.get_world_state();
+        node3.pool.add_transaction(transaction.clone(), &state).unwrap();
+        node3.broadcast_transaction(&transaction);
+        for node in [&node1, &node2] {
+            wait_for(|| node.pool.snapshot().count == 1);
+        }
+
+        // once mined, the transaction leaves the pool of every node
+        let block = mine_on_tip(&node1.blockchain, "miner", vec![transaction]);
+        node1.blockchain.add_block(block.clone()).unwrap();
+        node1.pool.remove_executed(&node1.blockchain.get_world_state());
+        node1.broadcast_block(&block);
+        for node in [&node2, &node3] {
+            wait_for(|| node.pool.snapshot().count == 0);
+        }
+    }
+
+    #[test]
+    fn new_node_downloads_the_chain_of_its_peer() {
+        let blockchain = create_blockchain();
+        for _ in 0..40 {
+            blockchain.add_block(mine_on_tip(&blockchain, "miner", vec![])).unwrap();
+        }
+        let (_node1, address1) = start_node(blockchain.clone(), vec![]);
+        let (node2, _) = start_node(create_blockchain(), vec![address1]);
+
+        wait_for(|| node2.blockchain.get_last_block().header.hash == blockchain.get_last_block().header.hash);
+        wait_for(|| !node2.get_sync_status().syncing);
+        let status = node2.get_sync_status();
+        assert_eq!(status.target_height, 40);
+        assert_eq!(status.downloaded_blocks, 40);
+    }
+
+    #[test]
+    fn peers_with_another_genesis_are_disconnected() {
+        let (node, address) = start_node(create_blockchain(), vec![]);
+
+        let stream = TcpStream::connect(address).unwrap();
+        let mut writer = stream.try_clone().unwrap();
+        let mut reader = BufReader::new(stream);
+        let handshake = Handshake {
+            node_id: 0,
+            genesis_hash: Hash256([7; 32]),
+            height: 0,
+            listen_port: 0,
+            light: false,
+        };
+        write_message(&mut writer, &Message::Handshake(handshake)).unwrap();
+
+        // the node sends its own handshake, then closes the connection
+        assert!(matches!(
+            read_message(&mut reader).unwrap(),
+            Some(Message::Handshake(_))
+        ));
+        assert!(read_message(&mut reader).unwrap().is_none());
+        assert_eq!(peer_count(&node), 0);
+    }
+
+    #[test]
+    fn addresses_learned_from_a_peer_are_bounded() {
+        let (node, address) = start_node(create_blockchain(), vec![]);
+
+        let stream = TcpStream::connect(address).unwrap();
+        let mut writer = stream.try_clone().unwrap();
+        let mut reader = BufReader::new(stream);
+        let handshake = Handshake {
+            node_id: 0,
+            genesis_hash: node.blockchain.get_genesis_hash(),
+            height: 0,
+            listen_port: 0,
+            light: false,
+        };
+        write_message(&mut writer, &Message::Handshake(handshake)).unwrap();
+
+        // the node answers the requests of a peer in order, so the answer follows the addresses sent before
+        let flood_count = |reader: &mut BufReader<TcpStream>, writer: &mut TcpStream| {
+            write_message(writer, &Message::GetPeers).unwrap();
+            loop {
+                if let Some(Message::Peers(addresses)) = read_message(reader).unwrap() {
+                    assert!(addresses.len() <= MAX_ADDRESSES_PER_MESSAGE);
+                    break;
+                }
+            }
+            let known_addresses = node.known_addresses.lock().unwrap();
+            known_addresses.iter().filter(|address| address.starts_with("127.1.")).count()
+        };
+        let flood = |first: usize, count: usize| -> Vec<String> {
+            (first..first + count)
+                .map(|i| format!("127.1.{}.{}:1", i / 256, i % 256))
+                .collect()
+        };
+
+        write_message(&mut writer, &Message::Peers(flood(0, 2 * MAX_ADDRESSES_PER_MESSAGE))).unwrap();
+        assert_eq!(flood_count(&mut reader, &mut writer), MAX_ADDRESSES_PER_MESSAGE);
+
+        for i in 1..MAX_KNOWN_ADDRESSES / MAX_ADDRESSES_PER_MESSAGE + 2 {
+            let first = i * MAX_ADDRESSES_PER_MESSAGE;
+            write_message(&mut writer, &Message::Peers(flood(first, MAX_ADDRESSES_PER_MESSAGE))).unwrap();
+        }
+        flood_count(&mut reader, &mut writer);
+        assert_eq!(node.known_addresses.lock().unwrap().len(), MAX_KNOWN_ADDRESSES);
+    }
+
+    #[test]
+    fn unreachable_learned_addresses_are_forgotten() {
+        let bootstrap_address = "127.0.0.1:1".to_string();
+        let learned_address = "127.0.0.1:2".to_string();
+        let network = TestNode::builder()
+            .peers(vec![bootstrap_address.clone()])
+            .build()
+            .network;
+        network.known_addresses.lock().unwrap().insert(learned_address.clone());
+
+        for _ in 1..MAX_DIAL_FAILURES {
+            network.record_dial_failure(&learned_address);
+            network.record_dial_failure(&bootstrap_address);
+        }
+        assert!(network.known_addresses.lock().unwrap().contains(&learned_address));
+        network.record_dial_failure(&learned_address);
+        network.record_dial_failure(&bootstrap_address);
+
+        // the bootstrap peer keeps being retried, after a longer delay
+        let known_addresses = network.known_addresses.lock().unwrap();
+        assert!(!known_addresses.contains(&learned_address));
+        assert!(known_addresses.contains(&bootstrap_address));
+        assert_eq!(network.dialer.record_failure(&learned_address), 1);
+        assert_eq!(network.dialer.record_failure(&bootstrap_address), MAX_DIAL_FAILURES + 1);
+    }
+}
@@ -0,0 +1,756 @@
+use crate::consensus::ConsensusError;
+use crate::p2p::message::Message;
+use crate::types::block::{Block, BlockHash, BlockHeader};
+use crate::types::blockchain::{check_header, next_target, BlockStatus, BlockVec, BlockchainError};
+use crate::types::target::Work;
+use crate::{Blockchain, TransactionPool};
+use log::info;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+// Most headers sent in a single message
+pub const MAX_HEADERS: usize = 500;
+
+// Most validated headers waiting for their blocks, more headers are only asked for
+// once the blocks of the first ones are applied
+const MAX_PENDING_HEADERS: usize = 4 * MAX_HEADERS;
+
+// Most blocks requested from a peer in a single message
+pub const BATCH_SIZE: usize = 16;
+
+// Most batches of blocks waiting for an answer at the same time
+const MAX_BATCHES_IN_FLIGHT: usize = 8;
+
+// A request not answered in time is sent again, to another peer if possible
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Error, Debug)]
+pub enum SyncError {
+    #[error("header {0} does not follow the previous one")]
+    DisconnectedHeader(u64),
+    #[error("header {0} does not match its hash")]
+    HashMismatch(u64),
+    #[error("header {index} has an invalid seal: {source}")]
+    InvalidSeal {
+        index: u64,
+        source: ConsensusError,
+    },
+    #[error("header {index} is invalid: {source}")]
+    InvalidHeader {
+        index: u64,
+        source: BlockchainError,
+    },
+    #[error("headers do not have more work than the main chain")]
+    NotEnoughWork,
+    #[error("too many headers without more work than the main chain")]
+    TooManyHeaders,
+    #[error("downloaded block {index} is invalid: {source}")]
+    InvalidBlock {
+        index: u64,
+        source: BlockchainError,
+    },
+}
+
+// A connected peer that blocks can be requested from
+#[derive(Debug, Clone)]
+pub struct SyncPeer {
+    pub connection_id: u64,
+    pub height: u64,
+}
+
+// Progress of the block download, as reported by the API
+#[derive(Debug, Serialize)]
+pub struct SyncStatus {
+    pub syncing: bool,
+    // Address of the peer the headers are downloaded from
+    pub peer: Option<String>,
+    pub height: u64,
+    // Index of the last header received and validated
+    pub target_height: u64,
+    // Validated headers whose blocks are not applied yet
+    pub pending_blocks: usize,
+    // Blocks applied by the current or the last download
+    pub downloaded_blocks: u64,
+}
+
+// Outgoing requests, with the connection each one is sent to
+pub type Requests = Vec<(u64, Message)>;
+
+#[derive(Debug)]
+struct Request {
+    connection_id: u64,
+    sent_at: Instant,
+}
+
+#[derive(Debug, Default)]
+struct SyncState {
+    // Connection and address of the peer the headers are downloaded from
+    peer: Option<(u64, String)>,
+    // Set while the next headers are requested from the peer
+    headers_request: Option<Instant>,
+    // Set when the peer may have more headers, until they are requested
+    more_headers: bool,
+    last_header: Option<BlockHeader>,
+    // Cumulative work of the chain ending with the last header
+    last_work: Work,
+    // Set once the headers have more work than the main chain, no block is requested before
+    enough_work: bool,
+    // Validated headers whose blocks are not applied yet, in chain order
+    headers: VecDeque<BlockHeader>,
+    // Blocks requested and not received yet
+    in_flight: HashMap<BlockHash, Request>,
+    // Blocks received before the blocks they build on
+    downloaded: HashMap<BlockHash, Block>,
+    target_height: u64,
+    downloaded_blocks: u64,
+}
+
+impl SyncState {
+    // Forgets the download but keeps its progress for the status
+    fn stop(&mut self) {
+        *self = SyncState {
+            target_height: self.target_height,
+            downloaded_blocks: self.downloaded_blocks,
+            ..SyncState::default()
+        };
+    }
+}
+
+// Downloads the chain of a peer that is ahead of the node
+// The headers come first from a single peer: they are checked to form a chain
+// of valid headers with more work than the main chain, then the blocks are requested in
+// batches spread over all the peers that have them, and applied in chain order through `add_block`
+// It only decides which requests to send, the network sends them and passes the answers back
+#[derive(Debug, Clone)]
+pub struct Synchronizer {
+    blockchain: Blockchain,
+    pool: TransactionPool,
+    state: Arc<Mutex<SyncState>>,
+}
+
+impl Synchronizer {
+    pub fn new(blockchain: Blockchain, pool: TransactionPool) -> Synchronizer {
+        Synchronizer {
+            blockchain,
+            pool,
+            state: Arc::new(Mutex::new(SyncState::default())),
+        }
+    }
+
+    pub fn status(&self) -> SyncStatus {
+        let state = self.state.lock().unwrap();
+
+        SyncStatus {
+            syncing: state.peer.is_some(),
+            peer: state.peer.as_ref().map(|(_, address)| address.clone()),
+            height: self.blockchain.get_last_block().header.index,
+            target_height: state.target_height,
+            pending_blocks: state.headers.len(),
+            downloaded_blocks: state.downloaded_blocks,
+        }
+    }
+
+    // Starts downloading from a peer with a higher chain, unless a download is already running
+    // Returns the request for the first headers
+    pub fn start(&self, connection_id: u64, address: &str, height: u64) -> Option<Message> {
+        let mut state = self.state.lock().unwrap();
+        if state.peer.is_some() || height <= self.blockchain.get_last_block().header.index {
+            return None;
+        }
+
+        info!("downloading blocks from {} at height {}", address, height);
+        *state = SyncState {
+            peer: Some((connection_id, address.to_string())),
+            headers_request: Some(Instant::now()),
+            ..SyncState::default()
+        };
+
+        Some(Message::GetHeaders(self.blockchain.get_locator()))
+    }
+
+    // Validates the headers sent by the sync peer and requests the blocks they announce
+    // once they have more work than the main chain
+    // Headers that do not form a chain on top of a known block stop the download,
+    // and so do the last headers of the peer if they do not have more work
+    pub fn on_headers(
+        &self,
+        connection_id: u64,
+        headers: Vec<BlockHeader>,
+        peers: &[SyncPeer],
+    ) -> Result<Requests, SyncError> {
+        let mut state = self.state.lock().unwrap();
+        if state.peer.as_ref().map(|(id, _)| *id) != Some(connection_id) {
+            return Ok(Requests::new());
+        }
+
+        let work = match self.validate_headers(&state, &headers) {
+            Ok(work) => work,
+            Err(error) => {
+                state.stop();
+                return Err(error);
+            }
+        };
+
+        state.headers_request = None;
+        // a full answer means the peer may have more headers after these
+        state.more_headers = headers.len() == MAX_HEADERS;
+        if let Some(last_header) = headers.last() {
+            state.target_height = state.target_height.max(last_header.index);
+            state.last_header = Some(last_header.clone());
+            state.last_work = work;
+            state.enough_work |= work > self.blockchain.get_chain_work();
+
+            if !state.more_headers && !state.enough_work {
+                state.stop();
+                // the node may have received the blocks of the peer in the meantime
+                if self.blockchain.contains_block(&last_header.hash) {
+                    return Ok(Requests::new());
+                }
+                return Err(SyncError::NotEnoughWork);
+            }
+        }
+        for header in headers {
+            if !self.blockchain.contains_block(&header.hash) {
+                state.headers.push_back(header);
+            }
+        }
+
+        // no block is downloaded before the headers have more work,
+        // so the pending headers would never leave room for more
+        if state.more_headers && !state.enough_work && !has_room_for_headers(&state) {
+            state.stop();
+            return Err(SyncError::TooManyHeaders);
+        }
+
+        let mut requests: Requests = self.request_headers(&mut state).into_iter().collect();
+        requests.extend(self.request_blocks(&mut state, peers));
+        self.finish_if_done(&mut state);
+
+        Ok(requests)
+    }
+
+    // Applies the downloaded blocks that are next in chain order and requests more
+    // Blocks that were not requested are ignored
+    pub fn on_blocks(&self, blocks: BlockVec, peers: &[SyncPeer]) -> Result<Requests, SyncError> {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+
+        for block in blocks {
+            if state.in_flight.remove(&block.header.hash).is_some() {
+                state.downloaded.insert(block.header.hash.clone(), block);
+            }
+        }
+
+        while let Some(block) = state
+            .headers
+            .front()
+            .and_then(|header| state.downloaded.remove(&header.hash))
+        {
+            state.headers.pop_front();
+            match self.blockchain.add_block(block.clone()) {
+                Ok(status) => {
+                    self.blockchain.with_world_state(|world_state| {
+                        self.pool.remove_executed(world_state);
+                        if let BlockStatus::Reorganized(orphaned) = status {
+                            self.pool.add_orphaned(orphaned, world_state);
+                        }
+                    });
+                }
+                Err(BlockchainError::KnownBlock) => {}
+                Err(error) => {
+                    state.stop();
+                    return Err(SyncError::InvalidBlock {
+                        index: block.header.index,
+                        source: error,
+                    });
+                }
+            }
+            state.downloaded_blocks += 1;
+        }
+
+        let mut requests: Requests = self.request_headers(state).into_iter().collect();
+        requests.extend(self.request_blocks(state, peers));
+        self.finish_if_done(state);
+
+        Ok(requests)
+    }
+
+    // Sends again the requests that were not answered in time
+    // The download stops if the sync peer left or does not send headers anymore
+    pub fn tick(&self, peers: &[SyncPeer]) -> Requests {
+        let mut state = self.state.lock().unwrap();
+        let sync_peer = match &state.peer {
+            Some((connection_id, _)) => *connection_id,
+            None => return Requests::new(),
+        };
+
+        let now = Instant::now();
+        let is_connected =
+            |connection_id: u64| peers.iter().any(|peer| peer.connection_id == connection_id);
+        let headers_timed_out = state
+            .headers_request
+            .map(|sent_at| now.duration_since(sent_at) >= REQUEST_TIMEOUT)
+            .unwrap_or(false);
+        if !is_connected(sync_peer) || headers_timed_out {
+            info!("block download stopped, the peer is gone or not answering");
+            state.stop();
+            return Requests::new();
+        }
+
+        state.in_flight.retain(|_, request| {
+            now.duration_since(request.sent_at) < REQUEST_TIMEOUT
+                && is_connected(request.connection_id)
+        });
+        self.request_blocks(&mut state, peers)
+    }
+
+    // Checks that the headers link to each other, starting on top of a known block or of the
+    // last header received, and that each one is valid on top of its parent the same way
+    // `HeaderChain::add_header` checks it, target of the retargeting rule and seal included
+    // The transactions are checked against the root of their header once the blocks arrive
+    // Returns the cumulative work of the chain ending with the last header
+    fn validate_headers(&self, state: &SyncState, headers: &[BlockHeader]) -> Result<Work, SyncError> {
+        let retarget_interval = self.blockchain.params.retarget_interval;
+
+        // the targets are computed from the headers waiting for their blocks
+        // and from the blocks of the branch they start from
+        let mut known: HashMap<BlockHash, BlockHeader> = state
+            .headers
+            .iter()
+            .chain(&state.last_header)
+            .map(|header| (header.hash.clone(), header.clone()))
+            .collect();
+        let branch_hash = match state.headers.front() {
+            Some(header) => header.previous_hash.clone(),
+            None => state.last_header.as_ref().map(|header| header.hash.clone()),
+        };
+        if let Some((ancestors, _)) = branch_hash
+            .and_then(|hash| self.blockchain.get_branch_headers(&hash, retarget_interval))
+        {
+            known.extend(ancestors.into_iter().map(|header| (header.hash.clone(), header)));
+        }
+
+        let mut previous_hash = state.last_header.as_ref().map(|header| header.hash.clone());
+        let mut work = state.last_work;
+        for header in headers {
+            let parent_hash = header
+                .previous_hash
+                .as_ref()
+                .ok_or(SyncError::DisconnectedHeader(header.index))?;
+            if previous_hash.as_ref() != Some(parent_hash) {
+                let (ancestors, parent_work) = self
+                    .blockchain
+                    .get_branch_headers(parent_hash, retarget_interval)
+                    .ok_or(SyncError::DisconnectedHeader(header.index))?;
+                known.extend(ancestors.into_iter().map(|header| (header.hash.clone(), header)));
+                work = parent_work;
+            }
+
+            let parent = &known[parent_hash];
+            let expected_target = next_target(
+                retarget_interval,
+                self.blockchain.params.target_block_time_ms,
+                parent,
+                |hash| known.get(hash),
+            );
+            let header_work = check_header(
+                header,
+                parent,
+                expected_target,
+                self.blockchain.consensus.as_ref(),
+            )
+            .map_err(|source| match source {
+                BlockchainError::WrongIndex => SyncError::DisconnectedHeader(header.index),
+                BlockchainError::HashMismatch => SyncError::HashMismatch(header.index),
+                BlockchainError::InvalidSeal(source) => SyncError::InvalidSeal {
+                    index: header.index,
+                    source,
+                },
+                source => SyncError::InvalidHeader {
+                    index: header.index,
+                    source,
+                },
+            })?;
+
+            work = work.saturating_add(header_work);
+            known.insert(header.hash.clone(), header.clone());
+            previous_hash = Some(header.hash.clone());
+        }
+
+        Ok(work)
+    }
+
+    // Asks the sync peer for the headers following the last one,
+    // once the pending headers leave room for a full answer
+    fn request_headers(&self, state: &mut SyncState) -> Option<(u64, Message)> {
+        let connection_id = state.peer.as_ref()?.0;
+        if !state.more_headers || state.headers_request.is_some() || !has_room_for_headers(state) {
+            return None;
+        }
+        let last_hash = state.last_header.as_ref()?.hash.clone();
+
+        state.more_headers = false;
+        state.headers_request = Some(Instant::now());
+        Some((connection_id, Message::GetHeaders(vec![last_hash])))
+    }
+
+    // Requests the next blocks that are neither requested nor downloaded, in batches spread
+    // over the peers high enough to have them, falling back to the sync peer
+    fn request_blocks(&self, state: &mut SyncState, peers: &[SyncPeer]) -> Requests {
+        let sync_peer = match &state.peer {
+            Some((connection_id, _)) if state.enough_work => *connection_id,
+            _ => return Requests::new(),
+        };
+
+        let missing: Vec<(BlockHash, u64)> = state
+            .headers
+            .iter()
+            .filter(|header| {
+                !state.in_flight.contains_key(&header.hash)
+                    && !state.downloaded.contains_key(&header.hash)
+            })
+            .map(|header| (header.hash.clone(), header.index))
+            .collect();
+
+        let now = Instant::now();
+        let mut requests = Requests::new();
+        for batch in missing.chunks(BATCH_SIZE) {
+            if state.in_flight.len() >= MAX_BATCHES_IN_FLIGHT * BATCH_SIZE {
+                break;
+            }
+
+            let last_index = batch[batch.len() - 1].1;
+            let candidates: Vec<u64> = peers
+                .iter()
+                .filter(|peer| peer.height >= last_index)
+                .map(|peer| peer.connection_id)
+                .collect();
+            let connection_id = if candidates.is_empty() {
+                sync_peer
+            } else {
+                candidates[requests.len() % candidates.len()]
+            };
+
+            let hashes: Vec<BlockHash> = batch.iter().map(|(hash, _)| hash.clone()).collect();
+            for hash in &hashes {
+                state.in_flight.insert(
+                    hash.clone(),
+                    Request {
+                        connection_id,
+                        sent_at: now,
+                    },
+                );
+            }
+            requests.push((connection_id, Message::GetBlocks(hashes)));
+        }
+
+        requests
+    }
+
+    fn finish_if_done(&self, state: &mut SyncState) {
+        if state.peer.is_some() && state.headers_request.is_none() && state.headers.is_empty() {
+            info!(
+                "block download finished at height {}",
+                self.blockchain.get_last_block().header.index
+            );
+            state.stop();
+        }
+    }
+}
+
+fn has_room_for_headers(state: &SyncState) -> bool {
+    state.headers.len() + MAX_HEADERS <= MAX_PENDING_HEADERS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::blockchain::ChainParams;
+    use crate::types::target::Target;
+    use crate::types::transaction::Transaction;
+    use crate::util::test_helpers::{
+        chain_params, create_pool, mine, mine_on_tip, TestNode, BLOCK_REWARD,
+    };
+
+    const SYNC_PEER: u64 = 1;
+    const OTHER_PEER: u64 = 2;
+
+    fn create_blockchain() -> Blockchain {
+        TestNode::builder().build_blockchain()
+    }
+
+    fn create_synchronizer(blockchain: &Blockchain) -> Synchronizer {
+        Synchronizer::new(blockchain.clone(), create_pool())
+    }
+
+    // Extends the chain with empty blocks paying the given miner
+    fn add_blocks(blockchain: &Blockchain, count: u64, miner: &str) {
+        for _ in 0..count {
+            blockchain.add_block(mine_on_tip(blockchain, miner, vec![])).unwrap();
+        }
+    }
+
+    // Answers requests the way a node with the given chain does
+    fn answer(source: &Blockchain, request: &Message) -> Message {
+        match request {
+            Message::GetHeaders(locator) => {
+                Message::Headers(source.get_headers_after(locator, MAX_HEADERS))
+            }
+            Message::GetBlocks(hashes) => Message::Blocks(source.get_blocks_by_hash(hashes)),
+            request => panic!("unexpected request {:?}", request),
+        }
+    }
+
+    // Runs a download until no request is left, returning the peers the blocks were requested from
+    fn run_download(synchronizer: &Synchronizer, source: &Blockchain, peers: &[SyncPeer]) -> Vec<u64> {
+        let height = source.get_last_block().header.index;
+        let request = synchronizer.start(SYNC_PEER, "peer", height).unwrap();
+        answer_requests(synchronizer, source, peers, vec![(SYNC_PEER, request)])
+    }
+
+    fn answer_requests(
+        synchronizer: &Synchronizer,
+        source: &Blockchain,
+        peers: &[SyncPeer],
+        mut requests: Requests,
+    ) -> Vec<u64> {
+        let mut block_peers = Vec::new();
+
+        while let Some((connection_id, request)) = requests.pop() {
+            let next_requests = match answer(source, &request) {
+                Message::Headers(headers) => synchronizer
+                    .on_headers(connection_id, headers, peers)
+                    .unwrap(),
+                Message::Blocks(blocks) => {
+                    block_peers.push(connection_id);
+                    synchronizer.on_blocks(blocks, peers).unwrap()
+                }
+                _ => unreachable!(),
+            };
+            // answering the latest requests first delivers blocks out of order
+            requests.extend(next_requests);
+        }
+
+        block_peers
+    }
+
+    #[test]
+    fn download_catches_up_with_a_longer_chain() {
+        let source = create_blockchain();
+        add_blocks(&source, MAX_HEADERS as u64 + 20, "miner");
+        let blockchain = create_blockchain();
+        add_blocks(&blockchain, 3, "miner");
+        let synchronizer = create_synchronizer(&blockchain);
+
+        let peers = [
+            SyncPeer {
+                connection_id: SYNC_PEER,
+                height: source.get_last_block().header.index,
+            },
+            SyncPeer {
+                connection_id: OTHER_PEER,
+                height: source.get_last_block().header.index,
+            },
+        ];
+        let block_peers = run_download(&synchronizer, &source, &peers);
+
+        assert_eq!(blockchain.get_last_block().header.hash, source.get_last_block().header.hash);
+        // the batches are spread over both peers
+        assert!(block_peers.contains(&SYNC_PEER));
+        assert!(block_peers.contains(&OTHER_PEER));
+
+        let status = synchronizer.status();
+        assert!(!status.syncing);
+        assert_eq!(status.height, MAX_HEADERS as u64 + 20);
+        assert_eq!(status.target_height, MAX_HEADERS as u64 + 20);
+        assert_eq!(status.pending_blocks, 0);
+    }
+
+    #[test]
+    fn headers_are_asked_for_as_the_blocks_are_applied() {
+        // more headers than can wait for their blocks at once
+        let source = create_blockchain();
+        add_blocks(&source, (MAX_PENDING_HEADERS + MAX_HEADERS) as u64 + 20, "miner");
+        let blockchain = create_blockchain();
+        let synchronizer = create_synchronizer(&blockchain);
+        let peers = [SyncPeer {
+            connection_id: SYNC_PEER,
+            height: source.get_last_block().header.index,
+        }];
+
+        // only the headers are answered at first
+        let mut request = synchronizer
+            .start(SYNC_PEER, "peer", source.get_last_block().header.index)
+            .unwrap();
+        let mut block_requests = Requests::new();
+        loop {
+            let requests = match answer(&source, &request) {
+                Message::Headers(headers) => synchronizer.on_headers(SYNC_PEER, headers, &peers).unwrap(),
+                _ => unreachable!(),
+            };
+            let (mut header_requests, requests): (Requests, Requests) = requests
+                .into_iter()
+                .partition(|(_, request)| matches!(request, Message::GetHeaders(_)));
+            block_requests.extend(requests);
+            match header_requests.pop() {
+                Some((_, next_request)) => request = next_request,
+                None => break,
+            }
+        }
+        assert_eq!(synchronizer.status().pending_blocks, MAX_PENDING_HEADERS);
+
+        // the rest of the headers are asked for once blocks are applied
+        answer_requests(&synchronizer, &source, &peers, block_requests);
+        assert_eq!(blockchain.get_last_block().header.hash, source.get_last_block().header.hash);
+        assert!(!synchronizer.status().syncing);
+    }
+
+    #[test]
+    fn download_follows_the_retargeting_across_header_batches() {
+        // one hash in 256 meets the initial target, which doubles and halves every other
+        // interval, so the target of every retarget depends on the timestamps of the previous
+        // interval, some of them in the block tree or in an earlier batch of headers
+        let params = ChainParams {
+            retarget_interval: 10,
+            target_block_time_ms: 1_000,
+            ..chain_params(0x2000_ffff, BLOCK_REWARD)
+        };
+        let create_retargeting_blockchain = || TestNode::builder().params(params.clone()).build_blockchain();
+        let source = create_retargeting_blockchain();
+        for _ in 0..MAX_HEADERS + 20 {
+            let last_block = source.get_last_block();
+            let index = last_block.header.index + 1;
+            let target = source.get_next_target(&last_block);
+            let coinbase = Transaction::new_coinbase("miner".to_string(), BLOCK_REWARD, index);
+            let mut block = Block::new(index, target, 0, Some(last_block.header.hash.clone()), vec![coinbase]);
+            let block_time = if (index / 10) % 2 == 0 { 2_000 } else { 500 };
+            block.header.timestamp = last_block.header.timestamp + block_time;
+            source.add_block(mine(block)).unwrap();
+        }
+        // the node already has the first blocks of the interval the download starts in
+        let blockchain = create_retargeting_blockchain();
+        for block in source.get_all_blocks().into_iter().skip(1).take(5) {
+            blockchain.add_block(block).unwrap();
+        }
+        let synchronizer = create_synchronizer(&blockchain);
+
+        let peers = [SyncPeer {
+            connection_id: SYNC_PEER,
+            height: source.get_last_block().header.index,
+        }];
+        run_download(&synchronizer, &source, &peers);
+
+        assert_eq!(blockchain.get_last_block().header.hash, source.get_last_block().header.hash);
+    }
+
+    #[test]
+    fn headers_without_enough_work_stop_the_download() {
+        let source = create_blockchain();
+        add_blocks(&source, MAX_HEADERS as u64 + 20, "miner");
+        let blockchain = create_blockchain();
+        add_blocks(&blockchain, MAX_HEADERS as u64 + 30, "other miner");
+        let synchronizer = create_synchronizer(&blockchain);
+
+        // the peer claims a higher chain than it has
+        let request = synchronizer.start(SYNC_PEER, "peer", MAX_HEADERS as u64 + 40).unwrap();
+        let requests = match answer(&source, &request) {
+            Message::Headers(headers) => synchronizer.on_headers(SYNC_PEER, headers, &[]).unwrap(),
+            _ => unreachable!(),
+        };
+        // no block is requested before the headers have more work than the main chain
+        assert!(matches!(requests.as_slice(), [(SYNC_PEER, Message::GetHeaders(_))]));
+
+        let result = match answer(&source, &requests[0].1) {
+            Message::Headers(headers) => synchronizer.on_headers(SYNC_PEER, headers, &[]),
+            _ => unreachable!(),
+        };
+        assert!(matches!(result, Err(SyncError::NotEnoughWork)));
+        assert!(!synchronizer.status().syncing);
+        assert_eq!(blockchain.get_last_block().header.index, MAX_HEADERS as u64 + 30);
+    }
+
+    #[test]
+    fn headers_must_have_the_expected_target() {
+        let source = create_blockchain();
+        add_blocks(&source, 5, "miner");
+        let blockchain = create_blockchain();
+        let synchronizer = create_synchronizer(&blockchain);
+
+        // the headers claim more work than the retargeting rule gives them
+        let mut headers = source.get_headers_after(&blockchain.get_locator(), MAX_HEADERS);
+        for header in &mut headers {
+            header.target = Target::from_difficulty(u64::MAX).to_compact();
+            header.hash = header.calculate_hash();
+        }
+        synchronizer.start(SYNC_PEER, "peer", 5).unwrap();
+        let result = synchronizer.on_headers(SYNC_PEER, headers, &[]);
+
+        assert!(matches!(
+            result,
+            Err(SyncError::InvalidHeader {
+                index: 1,
+                source: BlockchainError::WrongTarget { .. },
+            })
+        ));
+        assert!(!synchronizer.status().syncing);
+        assert_eq!(blockchain.get_last_block().header.index, 0);
+    }
+
+    #[test]
+    fn headers_must_carry_a_valid_seal() {
+        let source = create_blockchain();
+        add_blocks(&source, 5, "miner");
+        let blockchain = create_blockchain();
+        let synchronizer = create_synchronizer(&blockchain);
+
+        // proof of work blocks have no seal, and the seal is not part of the hash
+        let mut headers = source.get_headers_after(&blockchain.get_locator(), MAX_HEADERS);
+        headers[2].seal = "signature".to_string();
+        synchronizer.start(SYNC_PEER, "peer", 5).unwrap();
+        let result = synchronizer.on_headers(SYNC_PEER, headers, &[]);
+
+        assert!(matches!(
+            result,
+            Err(SyncError::InvalidSeal {
+                index: 3,
+                source: ConsensusError::UnexpectedSeal,
+            })
+        ));
+        assert!(!synchronizer.status().syncing);
+    }
+
+    #[test]
+    fn headers_must_match_their_hash() {
+        let source = create_blockchain();
+        add_blocks(&source, 5, "miner");
+        let blockchain = create_blockchain();
+        let synchronizer = create_synchronizer(&blockchain);
+
+        let mut headers = source.get_headers_after(&blockchain.get_locator(), MAX_HEADERS);
+        headers[2].timestamp += 1;
+        synchronizer.start(SYNC_PEER, "peer", 5).unwrap();
+        let result = synchronizer.on_headers(SYNC_PEER, headers, &[]);
+
+        assert!(matches!(result, Err(SyncError::HashMismatch(3))));
+        assert!(!synchronizer.status().syncing);
+    }
+
+    #[test]
+    fn headers_must_form_a_chain() {
+        let source = create_blockchain();
+        add_blocks(&source, 5, "miner");
+        let blockchain = create_blockchain();
+        let synchronizer = create_synchronizer(&blockchain);
+
+        let mut headers = source.get_headers_after(&blockchain.get_locator(), MAX_HEADERS);
+        headers.remove(2);
+        synchronizer.start(SYNC_PEER, "peer", 5).unwrap();
+        let result = synchronizer.on_headers(SYNC_PEER, headers, &[]);
+
+        assert!(matches!(result, Err(SyncError::DisconnectedHeader(4))));
+        assert!(!synchronizer.status().syncing);
+    }
+}
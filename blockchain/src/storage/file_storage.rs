@@ -0,0 +1,501 @@
+use crate::storage::{BlockStorage, StorageError};
+use crate::types::block::Block;
+use crate::types::blockchain::BlockVec;
+use log::warn;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+const DATA_FILE: &str = "blocks.dat";
+const INDEX_FILE: &str = "blocks.idx";
+
+// Every record starts with the length of the payload and the checksum of the payload
+const LENGTH_SIZE: usize = 4;
+const CHECKSUM_SIZE: usize = 4;
+const HEADER_SIZE: usize = LENGTH_SIZE + CHECKSUM_SIZE;
+
+// Index entries are the offset of each record in the data file
+const INDEX_ENTRY_SIZE: usize = 8;
+
+// When the files are flushed to disk after appending a block
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncPolicy {
+    // fsync the data and the index files after every block
+    #[default]
+    Always,
+    // fsync only the data file, the index is rebuilt from it if needed
+    DataOnly,
+    // leave flushing to the operating system
+    Never,
+}
+
+// Append-only block storage in a data directory
+// `blocks.dat` holds one length-prefixed, checksummed JSON record per block
+// and `blocks.idx` holds the offset of every record in `blocks.dat`
+// A block is written (and synced, depending on the policy) before the chain applies it,
+// so a crash can at worst leave a torn record at the end, which is dropped on open
+// The index tells such a record apart from a damaged one in the middle, which is never dropped
+#[derive(Debug)]
+pub struct FileStorage {
+    data_path: PathBuf,
+    index_path: PathBuf,
+    data_file: File,
+    index_file: File,
+    data_len: u64,
+    sync_policy: SyncPolicy,
+    damaged_record: Option<DamagedRecord>,
+}
+
+// A record that could not be read
+// It is trailing when no indexed record follows it, as a crash while appending can leave it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DamagedRecord {
+    pub offset: u64,
+    pub reason: String,
+    pub trailing: bool,
+}
+
+// Records that can be read from the start of the data file, up to the first damaged one
+struct RecordScan {
+    offsets: Vec<u64>,
+    valid_len: u64,
+    damaged: Option<DamagedRecord>,
+}
+
+impl FileStorage {
+    // Opens the storage in a directory, creating the directory and the files if needed
+    // A torn or corrupt record after the last indexed one is truncated,
+    // while any other corruption is reported as an error
+    pub fn open<P: AsRef<Path>>(
+        data_dir: P,
+        sync_policy: SyncPolicy,
+    ) -> Result<FileStorage, StorageError> {
+        let data_dir = data_dir.as_ref();
+        fs::create_dir_all(data_dir)?;
+
+        let data_path = data_dir.join(DATA_FILE);
+        let index_path = data_dir.join(INDEX_FILE);
+        let data_file = open_for_append(&data_path)?;
+        let index_file = open_for_append(&index_path)?;
+
+        let mut storage = FileStorage {
+            data_path,
+            index_path,
+            data_file,
+            index_file,
+            data_len: 0,
+            sync_policy,
+            damaged_record: None,
+        };
+        storage.recover()?;
+
+        Ok(storage)
+    }
+
+    // Opens an existing storage without changing the files, to check it
+    // Blocks are loaded up to the first damaged record, which is reported instead of being
+    // truncated, and appending blocks fails
+    pub fn open_read_only<P: AsRef<Path>>(data_dir: P) -> Result<FileStorage, StorageError> {
+        let data_dir = data_dir.as_ref();
+        let data_path = data_dir.join(DATA_FILE);
+        let index_path = data_dir.join(INDEX_FILE);
+        let data_file = File::open(&data_path)?;
+        let index_file = File::open(&index_path)?;
+
+        let scan = scan_records(&fs::read(&data_path)?, &fs::read(&index_path)?);
+
+        Ok(FileStorage {
+            data_path,
+            index_path,
+            data_file,
+            index_file,
+            data_len: scan.valid_len,
+            sync_policy: SyncPolicy::Never,
+            damaged_record: scan.damaged,
+        })
+    }
+
+    // First record that could not be read, only set when opened read-only
+    pub fn damaged_record(&self) -> Option<&DamagedRecord> {
+        self.damaged_record.as_ref()
+    }
+
+    // Finds the last complete record, truncates a torn record after it and makes the index match
+    fn recover(&mut self) -> Result<(), StorageError> {
+        let data = fs::read(&self.data_path)?;
+        let index = fs::read(&self.index_path)?;
+        let scan = scan_records(&data, &index);
+
+        if let Some(damaged) = scan.damaged {
+            if !damaged.trailing {
+                return Err(StorageError::Corrupt {
+                    offset: damaged.offset,
+                    reason: damaged.reason,
+                });
+            }
+            warn!(
+                "dropping a torn record of {} bytes at the end of the storage: corrupt record at offset {}: {}",
+                data.len() as u64 - scan.valid_len,
+                damaged.offset,
+                damaged.reason
+            );
+            self.data_file.set_len(scan.valid_len)?;
+            self.data_file.sync_all()?;
+        }
+        self.data_len = scan.valid_len;
+
+        if index.len() % INDEX_ENTRY_SIZE != 0 || read_index(&index) != scan.offsets {
+            self.rewrite_index(&scan.offsets)?;
+        }
+
+        Ok(())
+    }
+
+    // Replaces the index with the given offsets
+    fn rewrite_index(&mut self, offsets: &[u64]) -> Result<(), StorageError> {
+        let entries: Vec<u8> = offsets
+            .iter()
+            .flat_map(|offset| offset.to_le_bytes())
+            .collect();
+
+        self.index_file.set_len(0)?;
+        self.index_file.write_all(&entries)?;
+        self.index_file.sync_all()?;
+
+        Ok(())
+    }
+
+    fn write_record(&mut self, record: &[u8]) -> Result<(), StorageError> {
+        self.data_file.write_all(record)?;
+        if self.sync_policy != SyncPolicy::Never {
+            self.data_file.sync_data()?;
+        }
+
+        self.index_file.write_all(&self.data_len.to_le_bytes())?;
+        if self.sync_policy == SyncPolicy::Always {
+            self.index_file.sync_data()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl BlockStorage for FileStorage {
+    fn append_block(&mut self, block: &Block) -> Result<(), StorageError> {
+        let record = encode_record(&serde_json::to_vec(block)?);
+
+        // a failed write must not leave a partial record in front of the next one
+        if let Err(error) = self.write_record(&record) {
+            self.data_file.set_len(self.data_len)?;
+            return Err(error);
+        }
+        self.data_len += record.len() as u64;
+
+        Ok(())
+    }
+
+    fn load_blocks(&mut self) -> Result<BlockVec, StorageError> {
+        let mut data = fs::read(&self.data_path)?;
+        data.truncate(self.data_len as usize);
+
+        let mut blocks = BlockVec::new();
+        let mut offset = 0;
+        while offset < data.len() {
+            let (payload, record_len) = decode_record(&data[offset..], offset as u64)?;
+            let block = serde_json::from_slice(payload).map_err(|error| StorageError::Corrupt {
+                offset: offset as u64,
+                reason: error.to_string(),
+            })?;
+
+            blocks.push(block);
+            offset += record_len;
+        }
+
+        Ok(blocks)
+    }
+
+    // Unless flushing is left to the operating system, both files are synced on a clean stop,
+    // so the index does not have to be rebuilt on the next open even if only the data was synced
+    fn flush(&mut self) -> Result<(), StorageError> {
+        if self.sync_policy != SyncPolicy::Never {
+            self.data_file.sync_all()?;
+            self.index_file.sync_all()?;
+        }
+
+        Ok(())
+    }
+}
+
+fn open_for_append(path: &Path) -> Result<File, StorageError> {
+    let file = OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(path)?;
+
+    Ok(file)
+}
+
+fn checksum(payload: &[u8]) -> [u8; CHECKSUM_SIZE] {
+    let hash = Sha256::digest(payload);
+    let mut checksum = [0; CHECKSUM_SIZE];
+    checksum.copy_from_slice(&hash[..CHECKSUM_SIZE]);
+    checksum
+}
+
+fn encode_record(payload: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(HEADER_SIZE + payload.len());
+    record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    record.extend_from_slice(&checksum(payload));
+    record.extend_from_slice(payload);
+    record
+}
+
+// Total length of the record at the start of the data, if its header is complete
+fn record_len(data: &[u8]) -> Option<usize> {
+    let length = data.get(..LENGTH_SIZE)?;
+    if data.len() < HEADER_SIZE {
+        return None;
+    }
+
+    let mut bytes = [0; LENGTH_SIZE];
+    bytes.copy_from_slice(length);
+    Some(HEADER_SIZE + u32::from_le_bytes(bytes) as usize)
+}
+
+// A record is the last one if its header is incomplete or it reaches the end of the data
+fn is_trailing_record(data: &[u8]) -> bool {
+    match record_len(data) {
+        Some(record_len) => record_len >= data.len(),
+        None => true,
+    }
+}
+
+// Offsets stored in the index, a partial last entry being padded with zeros
+fn read_index(index: &[u8]) -> Vec<u64> {
+    index
+        .chunks(INDEX_ENTRY_SIZE)
+        .map(|entry| {
+            let mut bytes = [0; INDEX_ENTRY_SIZE];
+            bytes[..entry.len()].copy_from_slice(entry);
+            u64::from_le_bytes(bytes)
+        })
+        .collect()
+}
+
+// Reads the records of the data file in order until the end or the first damaged record
+// A damaged record only counts as trailing if it looks cut at the end of the data and the index
+// has no record after it, since a corrupt length can also make a record in the middle reach the end
+fn scan_records(data: &[u8], index: &[u8]) -> RecordScan {
+    let indexed_offsets = read_index(index);
+
+    let mut offsets = Vec::new();
+    let mut offset = 0;
+    while offset < data.len() {
+        match decode_record(&data[offset..], offset as u64) {
+            Ok((_, record_len)) => {
+                offsets.push(offset as u64);
+                offset += record_len;
+            }
+            Err(error) => {
+                let indexed_after = indexed_offsets
+                    .iter()
+                    .any(|&indexed| indexed > offset as u64 && indexed < data.len() as u64);
+                let reason = match error {
+                    StorageError::Corrupt { reason, .. } => reason,
+                    error => error.to_string(),
+                };
+
+                return RecordScan {
+                    offsets,
+                    valid_len: offset as u64,
+                    damaged: Some(DamagedRecord {
+                        offset: offset as u64,
+                        reason,
+                        trailing: is_trailing_record(&data[offset..]) && !indexed_after,
+                    }),
+                };
+            }
+        }
+    }
+
+    RecordScan {
+        offsets,
+        valid_len: offset as u64,
+        damaged: None,
+    }
+}
+
+// Returns the payload of the record at the start of the data and the total length of the record
+fn decode_record(data: &[u8], offset: u64) -> Result<(&[u8], usize), StorageError> {
+    let corrupt = |reason: &str| StorageError::Corrupt {
+        offset,
+        reason: reason.to_string(),
+    };
+
+    let record_len = record_len(data).ok_or_else(|| corrupt("truncated header"))?;
+    if data.len() < record_len {
+        return Err(corrupt("truncated payload"));
+    }
+
+    let payload = &data[HEADER_SIZE..record_len];
+    if data[LENGTH_SIZE..HEADER_SIZE] != checksum(payload) {
+        return Err(corrupt("checksum mismatch"));
+    }
+
+    Ok((payload, record_len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn create_block(index: u64) -> Block {
+        Block::new(index, 0, 0, None, vec![])
+    }
+
+    fn open_storage(data_dir: &TempDir) -> FileStorage {
+        FileStorage::open(data_dir.path(), SyncPolicy::Always).unwrap()
+    }
+
+    fn indexes_of(blocks: &BlockVec) -> Vec<u64> {
+        blocks.iter().map(|block| block.header.index).collect()
+    }
+
+    // Stores blocks 1 and 2 and returns the length of the data file after block 1
+    fn store_two_blocks(data_dir: &TempDir) -> u64 {
+        let mut storage = open_storage(data_dir);
+        storage.append_block(&create_block(1)).unwrap();
+        let first_record_len = storage.data_len;
+        storage.append_block(&create_block(2)).unwrap();
+        first_record_len
+    }
+
+    #[test]
+    fn stored_blocks_survive_reopening() {
+        let data_dir = TempDir::new().unwrap();
+
+        let mut storage = open_storage(&data_dir);
+        assert!(storage.load_blocks().unwrap().is_empty());
+        drop(storage);
+        store_two_blocks(&data_dir);
+
+        let mut storage = open_storage(&data_dir);
+        let blocks = storage.load_blocks().unwrap();
+        assert_eq!(indexes_of(&blocks), vec![1, 2]);
+        assert_eq!(
+            storage.data_len,
+            fs::metadata(data_dir.path().join(DATA_FILE)).unwrap().len()
+        );
+    }
+
+    #[test]
+    fn index_is_rebuilt_from_data_file() {
+        let data_dir = TempDir::new().unwrap();
+        store_two_blocks(&data_dir);
+        fs::write(data_dir.path().join(INDEX_FILE), []).unwrap();
+
+        let mut storage = open_storage(&data_dir);
+        assert_eq!(storage.load_blocks().unwrap().len(), 2);
+        let index = fs::read(data_dir.path().join(INDEX_FILE)).unwrap();
+        assert_eq!(index.len(), 2 * INDEX_ENTRY_SIZE);
+    }
+
+    #[test]
+    fn torn_trailing_record_is_truncated() {
+        let data_dir = TempDir::new().unwrap();
+        let first_record_len = store_two_blocks(&data_dir);
+
+        // cut the second record in the middle, as a crash during the write would
+        let data_path = data_dir.path().join(DATA_FILE);
+        let data = fs::read(&data_path).unwrap();
+        fs::write(&data_path, &data[..data.len() - 10]).unwrap();
+
+        let mut storage = open_storage(&data_dir);
+        assert_eq!(fs::metadata(&data_path).unwrap().len(), first_record_len);
+        assert_eq!(indexes_of(&storage.load_blocks().unwrap()), vec![1]);
+
+        // new blocks are appended right after the last valid one
+        storage.append_block(&create_block(2)).unwrap();
+        drop(storage);
+        let mut storage = open_storage(&data_dir);
+        assert_eq!(indexes_of(&storage.load_blocks().unwrap()), vec![1, 2]);
+    }
+
+    #[test]
+    fn corrupt_trailing_record_is_truncated() {
+        let data_dir = TempDir::new().unwrap();
+        let first_record_len = store_two_blocks(&data_dir);
+
+        // flip the last byte of the last payload
+        let data_path = data_dir.path().join(DATA_FILE);
+        let mut data = fs::read(&data_path).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        fs::write(&data_path, data).unwrap();
+
+        let mut storage = open_storage(&data_dir);
+        assert_eq!(indexes_of(&storage.load_blocks().unwrap()), vec![1]);
+        assert_eq!(
+            fs::metadata(&data_path).unwrap().len(),
+            first_record_len
+        );
+    }
+
+    #[test]
+    fn corrupt_record_before_the_end_is_an_error() {
+        let data_dir = TempDir::new().unwrap();
+        store_two_blocks(&data_dir);
+
+        // flip a byte of the first payload, the second record is still intact
+        let data_path = data_dir.path().join(DATA_FILE);
+        let mut data = fs::read(&data_path).unwrap();
+        data[HEADER_SIZE] ^= 1;
+        fs::write(&data_path, data).unwrap();
+
+        let result = FileStorage::open(data_dir.path(), SyncPolicy::Always);
+        assert!(matches!(result, Err(StorageError::Corrupt { offset: 0, .. })));
+    }
+
+    #[test]
+    fn corrupt_length_before_the_last_indexed_record_is_an_error() {
+        let data_dir = TempDir::new().unwrap();
+        store_two_blocks(&data_dir);
+
+        // the first record now seems to reach past the end, like a torn one would
+        let data_path = data_dir.path().join(DATA_FILE);
+        let mut data = fs::read(&data_path).unwrap();
+        data[..LENGTH_SIZE].copy_from_slice(&u32::MAX.to_le_bytes());
+        fs::write(&data_path, &data).unwrap();
+
+        let result = FileStorage::open(data_dir.path(), SyncPolicy::Always);
+        assert!(matches!(result, Err(StorageError::Corrupt { offset: 0, .. })));
+        assert_eq!(fs::read(&data_path).unwrap(), data);
+    }
+
+    #[test]
+    fn read_only_storage_reports_damage_without_fixing_it() {
+        let data_dir = TempDir::new().unwrap();
+        let first_record_len = store_two_blocks(&data_dir);
+
+        let data_path = data_dir.path().join(DATA_FILE);
+        let index_path = data_dir.path().join(INDEX_FILE);
+        let data = fs::read(&data_path).unwrap();
+        fs::write(&data_path, &data[..data.len() - 10]).unwrap();
+        fs::write(&index_path, []).unwrap();
+
+        let mut storage = FileStorage::open_read_only(data_dir.path()).unwrap();
+        assert_eq!(indexes_of(&storage.load_blocks().unwrap()), vec![1]);
+        let damaged = storage.damaged_record().unwrap();
+        assert_eq!(damaged.offset, first_record_len);
+        assert!(damaged.trailing);
+        assert!(storage.append_block(&create_block(2)).is_err());
+
+        assert_eq!(fs::read(&data_path).unwrap(), &data[..data.len() - 10]);
+        assert!(fs::read(&index_path).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,49 @@
+pub(crate) mod file_storage;
+
+use crate::types::block::Block;
+use crate::types::blockchain::BlockVec;
+use std::fmt::Debug;
+use std::io;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum StorageError {
+    #[error("storage i/o error: {0}")]
+    Io(#[from] io::Error),
+    #[error("could not encode block: {0}")]
+    Encoding(#[from] serde_json::Error),
+    #[error("corrupt record at offset {offset}: {reason}")]
+    Corrupt { offset: u64, reason: String },
+}
+
+// Keeps the blocks of the chain after the genesis block, in order
+// The blockchain appends every block it accepts and replays all of them on startup
+pub trait BlockStorage: Debug + Send {
+    fn append_block(&mut self, block: &Block) -> Result<(), StorageError>;
+
+    fn load_blocks(&mut self) -> Result<BlockVec, StorageError>;
+
+    // Makes the appended blocks durable before the node exits, as far as the storage promises to
+    fn flush(&mut self) -> Result<(), StorageError>;
+}
+
+// Storage that only lives as long as the process, used when no data directory is configured
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    blocks: BlockVec,
+}
+
+impl BlockStorage for MemoryStorage {
+    fn append_block(&mut self, block: &Block) -> Result<(), StorageError> {
+        self.blocks.push(block.clone());
+        Ok(())
+    }
+
+    fn load_blocks(&mut self) -> Result<BlockVec, StorageError> {
+        Ok(self.blocks.clone())
+    }
+
+    fn flush(&mut self) -> Result<(), StorageError> {
+        Ok(())
+    }
+}
@@ -1,50 +1,102 @@
+use crate::types::hash::Hash256;
+use crate::types::merkle::transactions_root;
+use crate::types::target::CompactTarget;
 use crate::types::transaction::Transaction;
 use chrono::prelude::*;
 use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha256};
 
-pub type BlockHash = String;
+pub type BlockHash = Hash256;
 
+// Version of the header encoding the block hash is computed from, changed whenever the encoding changes
+pub const HEADER_VERSION: u32 = 1;
+
+// Length of the encoded header: version, index, timestamp, nonce, previous hash, transactions root, target
+pub const HEADER_SIZE: usize = 4 + 8 + 8 + 8 + 32 + 32 + 4;
+
+// The fields the hash of a block is computed from, committing to the transactions through their
+// Merkle root, plus the hash itself
+// Enough to follow a chain and check its seal before downloading the blocks,
+// and the only data hashed again for every nonce while mining
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Block {
+pub struct BlockHeader {
     pub index: u64,
     pub timestamp: i64,
+    // Target the hash must not exceed, set by the retargeting rule of the chain
+    pub target: CompactTarget,
     pub nonce: u64,
     pub previous_hash: Option<BlockHash>,
+    // Root of the Merkle tree over the hashes of the transactions of the block
+    pub transactions_root: Hash256,
     pub hash: BlockHash,
+    // Proof the consensus engine adds on top of the hash, like the signature of an authority,
+    // empty for proof-of-work where the hash itself is the proof
+    // It is not hashed, since it may sign the hash
+    #[serde(default)]
+    pub seal: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Block {
+    pub header: BlockHeader,
     pub transactions: Vec<Transaction>,
 }
 
+impl BlockHeader {
+    // The stored hash is not part of what is hashed
+    pub fn calculate_hash(&self) -> BlockHash {
+        Hash256::digest(&self.encode())
+    }
+
+    // Canonical encoding of the header, integers are little endian and the genesis block
+    // has a zero previous hash:
+    // version (u32) | index (u64) | timestamp (i64) | nonce (u64) | previous hash (32 bytes)
+    // | transactions root (32 bytes) | target (u32)
+    pub fn encode(&self) -> Vec<u8> {
+        let previous_hash = self.previous_hash.clone().unwrap_or_default();
+
+        let mut header = Vec::with_capacity(HEADER_SIZE);
+        header.extend_from_slice(&HEADER_VERSION.to_le_bytes());
+        header.extend_from_slice(&self.index.to_le_bytes());
+        header.extend_from_slice(&self.timestamp.to_le_bytes());
+        header.extend_from_slice(&self.nonce.to_le_bytes());
+        header.extend_from_slice(&previous_hash.0);
+        header.extend_from_slice(&self.transactions_root.0);
+        header.extend_from_slice(&self.target.to_le_bytes());
+
+        header
+    }
+}
+
 impl Block {
-    // Create a new block. The hash value will be calculated and set automatically.
+    // Create a new block. The transactions root and the hash will be calculated and set automatically.
     pub fn new(
         index: u64,
+        target: CompactTarget,
         nonce: u64,
         previous_hash: Option<BlockHash>,
         transactions: Vec<Transaction>,
     ) -> Block {
-        let mut block = Block {
+        let mut header = BlockHeader {
             index,
             timestamp: Utc::now().timestamp_millis(),
+            target,
             nonce,
             previous_hash,
+            transactions_root: transactions_root(&transactions),
             hash: BlockHash::default(),
-            transactions,
+            seal: String::new(),
         };
-        block.hash = block.calculate_hash();
+        header.hash = header.calculate_hash();
 
-        block
+        Block {
+            header,
+            transactions,
+        }
     }
 
-    pub fn calculate_hash(&self) -> BlockHash {
-        let mut block_data = self.clone();
-        block_data.hash = String::default();
-        let serialized_block_data = serde_json::to_string(&block_data).unwrap();
-        // Calculate and return SHA-256 hash value.
-        let mut hasher = Sha256::new();
-        hasher.update(serialized_block_data);
-        let result = hasher.finalize();
-        format!("{:x}", result)
+    // Whether the transactions are the ones the header commits to
+    pub fn has_valid_transactions_root(&self) -> bool {
+        self.header.transactions_root == transactions_root(&self.transactions)
     }
 }
 
@@ -53,11 +105,7 @@ mod tests {
     use super::*;
 
     fn create_mock_transaction(sender: String, recipient: String, amount: u64) -> Transaction {
-        Transaction {
-            sender,
-            recipient,
-            amount,
-        }
+        Transaction::new(sender, recipient, amount, 0, 0)
     }
 
     #[test]
@@ -67,9 +115,100 @@ mod tests {
         let transaction_2 =
             create_mock_transaction("bob.near".to_owned(), "alice.near".to_owned(), 5);
 
-        let block = Block::new(0, 10, None, vec![transaction_1, transaction_2]);
+        let block = Block::new(0, 0, 10, None, vec![transaction_1, transaction_2]);
 
-        assert_eq!(block.previous_hash, None);
+        assert_eq!(block.header.previous_hash, None);
+        assert!(block.has_valid_transactions_root());
         assert!(!block.transactions.is_empty());
     }
+
+    #[test]
+    fn block_hash_is_hex_encoded_in_json() {
+        let block = Block::new(1, 0, 0, Some(Hash256([0xab; 32])), vec![]);
+
+        let json = serde_json::to_value(&block).unwrap();
+        assert_eq!(json["header"]["previous_hash"], "ab".repeat(32));
+        assert_eq!(json["header"]["hash"], block.header.hash.to_string());
+
+        let decoded: Block = serde_json::from_value(json).unwrap();
+        assert_eq!(decoded.header.hash, block.header.hash);
+        assert!(serde_json::from_str::<BlockHash>("\"abcd\"").is_err());
+    }
+
+    // Fixed blocks whose encoding and hash other implementations must reproduce
+    fn create_vector_block() -> Block {
+        let transactions = vec![
+            Transaction::new_coinbase("miner".to_string(), 50, 1),
+            Transaction::new("alice.near".to_string(), "bob.near".to_string(), 10, 1, 0),
+        ];
+        let mut block = Block::new(1, 0x1f0f_ffff, 42, Some(Hash256([0x11; 32])), transactions);
+        block.header.timestamp = 1_700_000_000_000;
+        block.header.hash = block.header.calculate_hash();
+        block
+    }
+
+    #[test]
+    fn header_encoding_is_pinned() {
+        let block = create_vector_block();
+
+        let header = block.header.encode();
+        assert_eq!(header.len(), HEADER_SIZE);
+        assert_eq!(
+            hex::encode(&header),
+            concat!(
+                "01000000",
+                "0100000000000000",
+                "0068e5cf8b010000",
+                "2a00000000000000",
+                "1111111111111111111111111111111111111111111111111111111111111111",
+                "73067cf3ea39dbaf8d967b2ee88fe9a158402e01d9f36bf76ff4fa66ceedbed2",
+                "ffff0f1f",
+            )
+        );
+        assert_eq!(
+            block.header.hash.to_string(),
+            "bd87e0a0e627e8c8e95a8213e924c2fc5e808f8d0fc2d6fa34fc3a133bd8cd05"
+        );
+    }
+
+    #[test]
+    fn genesis_header_encoding_is_pinned() {
+        let mut block = Block::new(0, 0x20ff_ffff, 0, None, vec![]);
+        block.header.timestamp = 0;
+
+        assert_eq!(
+            hex::encode(block.header.encode()),
+            format!("01000000{}ffffff20", "00".repeat(24 + 64))
+        );
+        assert_eq!(
+            block.header.calculate_hash().to_string(),
+            "1a22039e36339a72d8405d558ce2820a324f90cdbe0b8fac6dcc411f084df212"
+        );
+    }
+
+    #[test]
+    fn hash_covers_every_header_field() {
+        let block = create_vector_block();
+
+        let mut changed = block.header.clone();
+        changed.nonce += 1;
+        assert_ne!(changed.calculate_hash(), block.header.hash);
+
+        // the stored hash is not part of what is hashed
+        let mut changed = block.header.clone();
+        changed.hash = Hash256::default();
+        assert_eq!(changed.calculate_hash(), block.header.hash);
+    }
+
+    #[test]
+    fn body_must_match_the_transactions_root() {
+        let mut block = create_vector_block();
+        assert!(block.has_valid_transactions_root());
+
+        block.transactions[1].amount += 1;
+        assert!(!block.has_valid_transactions_root());
+
+        block.transactions.pop();
+        assert!(!block.has_valid_transactions_root());
+    }
 }
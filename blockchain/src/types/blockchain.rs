@@ -1,37 +1,249 @@
-use crate::types::block::Block;
+use crate::consensus::{ConsensusEngine, ConsensusError, SyncedConsensus};
+use crate::storage::{BlockStorage, MemoryStorage, StorageError};
+use crate::types::block::{Block, BlockHash, BlockHeader};
+use crate::types::merkle::{build_path, InclusionProof};
+use crate::types::target::{CompactTarget, Target, Work};
+use crate::types::transaction::{Address, Transaction, TransactionError, TransactionHash};
+use crate::types::transaction_pool::TransactionVec;
+use crate::types::world_state::{Balances, BlockUndo, WorldState, WorldStateError};
 use anyhow::Result;
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
 use crate::util::response::Response;
+use thiserror::Error;
 
 pub type BlockVec = Vec<Block>;
 
 type SyncedBlockVec = Arc<Mutex<BlockVec>>;
 
+// Every valid block known to the node by hash, on the main chain or on a side branch
+type BlockTree = HashMap<BlockHash, TreeNode>;
+
+type SyncedBlockTree = Arc<Mutex<BlockTree>>;
+
+type SyncedWorldState = Arc<Mutex<WorldState>>;
+
+// Hashes of the blocks that were sealed but turned out to have invalid transactions
+type SyncedInvalidBlocks = Arc<Mutex<HashSet<BlockHash>>>;
+
+type SyncedStorage = Arc<Mutex<Box<dyn BlockStorage>>>;
+
+// Retargeting scales the target by how long the blocks took compared to the target block time,
+// but never by more than this factor at once
+const RETARGET_FACTOR: u64 = 4;
+
+// Blocks with a timestamp further in the future are rejected
+const MAX_FUTURE_BLOCK_TIME_MS: i64 = 2 * 60 * 60 * 1000;
+
+#[derive(Error, Debug)]
+pub enum BlockchainError {
+    #[error("block is already known")]
+    KnownBlock,
+    #[error("block or one of its ancestors is known to be invalid")]
+    KnownInvalidBlock,
+    #[error("previous block is unknown")]
+    UnknownParent,
+    #[error("invalid index")]
+    WrongIndex,
+    #[error("invalid previous hash")]
+    WrongPreviousHash,
+    #[error("invalid hash")]
+    HashMismatch,
+    #[error("transactions do not match the transactions root of the header")]
+    TransactionsRootMismatch,
+    #[error("invalid seal: {0}")]
+    InvalidSeal(#[from] ConsensusError),
+    #[error("target must be {expected:#010x} but is {target:#010x}")]
+    WrongTarget {
+        expected: CompactTarget,
+        target: CompactTarget,
+    },
+    #[error("timestamp is before the previous block or too far in the future")]
+    WrongTimestamp,
+    #[error("the first transaction of the block must be a coinbase")]
+    MissingCoinbase,
+    #[error("only the first transaction of the block can be a coinbase")]
+    UnexpectedCoinbase,
+    #[error("coinbase must pay {expected} but pays {amount}")]
+    WrongCoinbaseAmount { expected: u64, amount: u64 },
+    #[error("coinbase nonce must be the block index")]
+    WrongCoinbaseNonce,
+    #[error("coinbase can not carry a fee, a public key or a signature")]
+    MalformedCoinbase,
+    #[error("only the coinbase can set an extra_nonce")]
+    UnexpectedExtraNonce,
+    #[error("block has {count} transactions but at most {max} are allowed")]
+    TooManyTransactions { count: usize, max: usize },
+    #[error("transactions of the block take {size} bytes but at most {max} are allowed")]
+    BlockTooLarge { size: usize, max: usize },
+    #[error("invalid signature: {0}")]
+    InvalidSignature(#[from] TransactionError),
+    #[error("invalid transaction: {0}")]
+    InvalidTransaction(#[from] WorldStateError),
+    #[error("stored block {index} is invalid: {source}")]
+    InvalidStoredBlock {
+        index: u64,
+        source: Box<BlockchainError>,
+    },
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+}
+
+#[derive(Debug, Clone)]
+struct TreeNode {
+    block: Block,
+    // Work of the block and all its ancestors, as counted by the consensus engine
+    cumulative_work: Work,
+    // Side branch blocks are only written to storage once their transactions were checked
+    stored: bool,
+    // How to take the block off the world state, kept while the block is on the main chain
+    undo: Option<BlockUndo>,
+}
+
+// What adding a valid block did to the chain
+#[derive(Debug)]
+pub enum BlockStatus {
+    // The block extends the main chain
+    Extended,
+    // The block is kept on a side branch that does not have more work than the main chain
+    SideBranch,
+    // The branch of the block has more work and replaced the end of the main chain,
+    // holds the transactions of the abandoned blocks that the new main chain does not include
+    Reorganized(TransactionVec),
+}
+
+// Rules every node of a network must share to agree on the same main chain
+#[derive(Debug, Clone)]
+pub struct ChainParams {
+    // Target of the genesis block, later blocks follow the retargeting rule
+    pub initial_target: CompactTarget,
+    // Number of blocks after which the target is adjusted, 0 to keep it fixed
+    pub retarget_interval: u64,
+    // Time between blocks the retargeting aims for
+    pub target_block_time_ms: u64,
+    pub block_reward: u64,
+    // Number of blocks after which the reward is halved, 0 to never halve it
+    pub halving_interval: u64,
+    // Most transactions a block may hold, coinbase included
+    pub max_block_transactions: usize,
+    // Most bytes the serialized transactions of a block may take, coinbase included
+    pub max_block_size: usize,
+}
+
 // Struct that holds all the blocks in the blockchain
 // Multiple threads can read/write concurrently to the list of blocks
+// `blocks` is the main chain, the branch of the block tree with the most cumulative work
+// The block tree is always locked after the blocks, the invalid blocks and the world state
+// after the block tree, and the storage after the world state, so all of them are updated together
+// The transaction pool is only locked after the world state, while it is lent to the pool
 #[derive(Debug, Clone)]
 pub struct Blockchain {
-    pub difficulty: u32,
+    // Decides who may seal blocks and checks their seals
+    pub consensus: SyncedConsensus,
+    pub params: ChainParams,
     blocks: SyncedBlockVec,
+    tree: SyncedBlockTree,
+    state: SyncedWorldState,
+    // Sealed blocks are only forgotten when their transactions are invalid, so this only grows
+    // with blocks that had more work than the main chain when they were checked
+    invalid_blocks: SyncedInvalidBlocks,
+    storage: SyncedStorage,
+    // Counts the changes of the tip of the main chain, so that work on an older tip can be abandoned
+    tip_changes: Arc<AtomicU64>,
+    // Signaled on every change of the tip, the counter is only increased while holding its lock
+    // so that a thread waiting for a change never misses one
+    tip_signal: Arc<(Mutex<()>, Condvar)>,
 }
 
 impl Blockchain {
-    // Creates a new blockchain with a genesis block
-    pub fn new(difficulty: u32) -> Blockchain {
-        let genesis_block = Blockchain::create_genesis_block();
+    // Creates a new blockchain with a genesis block, kept only in memory
+    // The genesis balances are the initial state that all blocks are applied on top of
+    pub fn new(
+        consensus: SyncedConsensus,
+        params: ChainParams,
+        genesis_balances: Balances,
+    ) -> Blockchain {
+        Blockchain::with_storage(
+            consensus,
+            params,
+            genesis_balances,
+            Box::new(MemoryStorage::default()),
+        )
+    }
+
+    // Loads a blockchain from storage, validating every stored block again
+    // as if it was added for the first time
+    pub fn open(
+        consensus: SyncedConsensus,
+        params: ChainParams,
+        genesis_balances: Balances,
+        mut storage: Box<dyn BlockStorage>,
+    ) -> Result<Blockchain, BlockchainError> {
+        let stored_blocks = storage.load_blocks()?;
+        let blockchain = Blockchain::with_storage(consensus, params, genesis_balances, storage);
+
+        for block in stored_blocks {
+            let index = block.header.index;
+            blockchain
+                .append_block(block, false)
+                .map_err(|error| BlockchainError::InvalidStoredBlock {
+                    index,
+                    source: Box::new(error),
+                })?;
+        }
+
+        Ok(blockchain)
+    }
+
+    fn with_storage(
+        consensus: SyncedConsensus,
+        params: ChainParams,
+        genesis_balances: Balances,
+        storage: Box<dyn BlockStorage>,
+    ) -> Blockchain {
+        let genesis_block = create_genesis_block(params.initial_target);
+
+        // the genesis block is the root of the block tree and carries no work
+        let mut tree = BlockTree::new();
+        tree.insert(
+            genesis_block.header.hash.clone(),
+            TreeNode {
+                block: genesis_block.clone(),
+                cumulative_work: Work::default(),
+                stored: true,
+                undo: None,
+            },
+        );
 
         // add the genesis block to the synced vec of blocks
         let blocks = vec![genesis_block];
 
         let synced_blocks = Arc::new(Mutex::new(blocks));
+        let synced_state = Arc::new(Mutex::new(WorldState::new(genesis_balances)));
 
         Blockchain {
-            difficulty,
+            consensus,
+            params,
             blocks: synced_blocks,
+            tree: Arc::new(Mutex::new(tree)),
+            state: synced_state,
+            invalid_blocks: Arc::new(Mutex::new(HashSet::new())),
+            storage: Arc::new(Mutex::new(storage)),
+            tip_changes: Arc::new(AtomicU64::new(0)),
+            tip_signal: Arc::default(),
         }
     }
 
+    // Flushes the storage once no block is being added, before the node exits
+    pub fn close(&self) -> Result<(), BlockchainError> {
+        let _blocks = self.blocks.lock().unwrap();
+        self.storage.lock().unwrap().flush()?;
+
+        Ok(())
+    }
+
     // Returns a copy of the most recent block in the blockchain
     pub fn get_last_block(&self) -> Block {
         let blocks = self.blocks.lock().unwrap();
@@ -39,6 +251,33 @@ impl Blockchain {
         blocks[blocks.len() - 1].clone()
     }
 
+    // Returns how many times the tip of the main chain changed,
+    // work started on a tip is stale once this number moved on
+    pub fn get_tip_changes(&self) -> u64 {
+        self.tip_changes.load(Ordering::SeqCst)
+    }
+
+    // Blocks until the tip changed more than `seen` times or the timeout passes,
+    // returning how many times the tip changed so far
+    pub fn wait_for_tip_change(&self, seen: u64, timeout_ms: u64) -> u64 {
+        let (lock, signal) = &*self.tip_signal;
+        let guard = lock.lock().unwrap();
+        let _guard = signal
+            .wait_timeout_while(guard, Duration::from_millis(timeout_ms), |_| {
+                self.get_tip_changes() == seen
+            })
+            .unwrap();
+
+        self.get_tip_changes()
+    }
+
+    // Returns the hash of the first block, nodes with another genesis follow another chain
+    pub fn get_genesis_hash(&self) -> BlockHash {
+        let blocks = self.blocks.lock().unwrap();
+
+        blocks[0].header.hash.clone()
+    }
+
     // Returns a copy of the whole list of blocks
     pub fn get_all_blocks(&self) -> BlockVec {
         let blocks = self.blocks.lock().unwrap();
@@ -46,6 +285,150 @@ impl Blockchain {
         blocks.clone()
     }
 
+    // Returns whether a block is in the block tree, on the main chain or on a side branch
+    pub fn contains_block(&self, hash: &BlockHash) -> bool {
+        self.tree.lock().unwrap().contains_key(hash)
+    }
+
+    // Returns the known blocks among the given hashes, in the same order
+    pub fn get_blocks_by_hash(&self, hashes: &[BlockHash]) -> BlockVec {
+        let tree = self.tree.lock().unwrap();
+
+        hashes
+            .iter()
+            .filter_map(|hash| tree.get(hash))
+            .map(|node| node.block.clone())
+            .collect()
+    }
+
+    // Returns the header of a known block, on the main chain or a side branch, followed by
+    // up to `count` of its ancestors, with the cumulative work of the block
+    pub fn get_branch_headers(&self, hash: &BlockHash, count: u64) -> Option<(Vec<BlockHeader>, Work)> {
+        let tree = self.tree.lock().unwrap();
+
+        let node = tree.get(hash)?;
+        let mut headers = vec![node.block.header.clone()];
+        let mut previous_hash = node.block.header.previous_hash.as_ref();
+        while let Some(parent) = previous_hash.and_then(|hash| tree.get(hash)) {
+            if headers.len() as u64 > count {
+                break;
+            }
+            headers.push(parent.block.header.clone());
+            previous_hash = parent.block.header.previous_hash.as_ref();
+        }
+
+        Some((headers, node.cumulative_work))
+    }
+
+    // Returns the cumulative work of the main chain
+    pub fn get_chain_work(&self) -> Work {
+        let blocks = self.blocks.lock().unwrap();
+        let tree = self.tree.lock().unwrap();
+
+        tree[&blocks[blocks.len() - 1].header.hash].cumulative_work
+    }
+
+    // Returns hashes of the main chain from the tip back to the genesis, dense near the tip
+    // and then exponentially sparser, so that a peer can find the last block both chains share
+    pub fn get_locator(&self) -> Vec<BlockHash> {
+        let blocks = self.blocks.lock().unwrap();
+
+        build_locator(blocks.len() - 1, |index| blocks[index].header.hash.clone())
+    }
+
+    // Returns up to `max_headers` headers of the main chain following the first block
+    // of the locator that is on the main chain, or following the genesis if none is
+    pub fn get_headers_after(&self, locator: &[BlockHash], max_headers: usize) -> Vec<BlockHeader> {
+        let blocks = self.blocks.lock().unwrap();
+        let tree = self.tree.lock().unwrap();
+
+        let fork_index = locator
+            .iter()
+            .find_map(|hash| {
+                let index = tree.get(hash)?.block.header.index as usize;
+                (blocks.get(index)?.header.hash == *hash).then_some(index)
+            })
+            .unwrap_or(0);
+
+        blocks
+            .iter()
+            .skip(fork_index + 1)
+            .take(max_headers)
+            .map(|block| block.header.clone())
+            .collect()
+    }
+
+    // Returns the proof that a transaction is included in the main chain, searching from the tip
+    pub fn get_transaction_proof(&self, transaction_hash: &str) -> Option<InclusionProof> {
+        let blocks = self.blocks.lock().unwrap();
+
+        blocks.iter().rev().find_map(|block| {
+            let position = block
+                .transactions
+                .iter()
+                .position(|transaction| transaction.hash() == transaction_hash)?;
+            Some(InclusionProof {
+                transaction: block.transactions[position].clone(),
+                header: block.header.clone(),
+                path: build_path(&block.transactions, position),
+            })
+        })
+    }
+
+    // Returns the target the block following the given one must have
+    pub fn get_next_target(&self, parent: &Block) -> CompactTarget {
+        let tree = self.tree.lock().unwrap();
+
+        self.next_target(&tree, parent)
+    }
+
+    fn next_target(&self, tree: &BlockTree, parent: &Block) -> CompactTarget {
+        next_target(
+            self.params.retarget_interval,
+            self.params.target_block_time_ms,
+            &parent.header,
+            |hash| tree.get(hash).map(|node| &node.block.header),
+        )
+    }
+
+    // Returns a copy of the world state at the current tip
+    pub fn get_world_state(&self) -> WorldState {
+        let state = self.state.lock().unwrap();
+
+        state.clone()
+    }
+
+    // Lends the world state at the current tip without copying it, such as to the transaction pool
+    // No block can be added meanwhile, so `f` must not call back into the blockchain
+    pub fn with_world_state<R>(&self, f: impl FnOnce(&WorldState) -> R) -> R {
+        let state = self.state.lock().unwrap();
+
+        f(&state)
+    }
+
+    // Returns how many pool transactions, and how many bytes of them, fit in the block
+    // at the given index next to the coinbase paying the miner
+    // The coinbase is counted at its largest, since the fees and the extra_nonce are not known yet
+    pub fn get_pool_room(&self, miner_address: &Address, index: u64) -> (usize, usize) {
+        let mut coinbase = Transaction::new_coinbase(miner_address.clone(), u64::MAX, index);
+        coinbase.extra_nonce = u64::MAX;
+
+        (
+            self.params.max_block_transactions.saturating_sub(1),
+            self.params.max_block_size.saturating_sub(coinbase.size()),
+        )
+    }
+
+    // Returns the amount the coinbase of the block at the given index must mint
+    pub fn get_block_reward(&self, index: u64) -> u64 {
+        if self.params.halving_interval == 0 {
+            return self.params.block_reward;
+        }
+
+        let halvings = index / self.params.halving_interval;
+        self.params.block_reward.checked_shr(halvings as u32).unwrap_or(0)
+    }
+
     // Returns a block by index
     pub(crate) fn get_block_by_index(&self, index: u64) -> Response {
         let blocks = self.get_all_blocks();
@@ -62,68 +445,1194 @@ impl Blockchain {
     }
 
     // adding new block into blockchain
-    pub fn add_block(&self, block: Block) -> Result<(), &str> {
+    // The block may extend the main chain, start or extend a side branch,
+    // or make its branch the main chain when that branch has more work
+    pub fn add_block(&self, block: Block) -> Result<BlockStatus, BlockchainError> {
+        self.append_block(block, true)
+    }
+
+    // Validates a block and adds it to the block tree, reorganizing the main chain if needed
+    // The block is written to storage first unless it is being loaded from there
+    // Blocks on side branches are only stored once their branch becomes the main chain,
+    // so a branch with an invalid transaction is never replayed from storage
+    fn append_block(&self, block: Block, persist: bool) -> Result<BlockStatus, BlockchainError> {
         let mut blocks = self.blocks.lock().unwrap();
-        let last = &blocks[blocks.len() - 1];
+        let mut tree = self.tree.lock().unwrap();
+
+        if tree.contains_key(&block.header.hash) {
+            return Err(BlockchainError::KnownBlock);
+        }
+
+        // check that the previous block is known, it does not have to be the tip
+        let previous_hash = block
+            .header
+            .previous_hash
+            .clone()
+            .ok_or(BlockchainError::WrongPreviousHash)?;
+
+        // blocks that were already found invalid, and blocks built on them, are not checked again
+        // the hash of the block is not remembered here since it was not checked yet
+        {
+            let invalid_blocks = self.invalid_blocks.lock().unwrap();
+            if invalid_blocks.contains(&block.header.hash) || invalid_blocks.contains(&previous_hash) {
+                return Err(BlockchainError::KnownInvalidBlock);
+            }
+        }
+        let parent = tree
+            .get(&previous_hash)
+            .ok_or(BlockchainError::UnknownParent)?;
+
+        // check the index, timestamp, target, hash and seal of the header
+        let expected_target = self.next_target(&tree, &parent.block);
+        let work = check_header(
+            &block.header,
+            &parent.block.header,
+            expected_target,
+            self.consensus.as_ref(),
+        )?;
+
+        // check that the transactions are the ones the header commits to
+        if !block.has_valid_transactions_root() {
+            return Err(BlockchainError::TransactionsRootMismatch);
+        }
+
+        // check that the block fits in the limits of the chain
+        let count = block.transactions.len();
+        if count > self.params.max_block_transactions {
+            return Err(BlockchainError::TooManyTransactions {
+                count,
+                max: self.params.max_block_transactions,
+            });
+        }
+        let size = block.transactions.iter().map(Transaction::size).sum();
+        if size > self.params.max_block_size {
+            return Err(BlockchainError::BlockTooLarge {
+                size,
+                max: self.params.max_block_size,
+            });
+        }
+
+        // check that the block mints exactly the reward through its coinbase
+        self.check_coinbase(&block)?;
 
-        // check that the index is valid
-        if block.index != last.index + 1 {
-            return Err("invalid index");
+        // check that every other transaction is signed by the owner of the funds
+        for transaction in block.transactions.iter().skip(1) {
+            if transaction.extra_nonce != 0 {
+                return Err(BlockchainError::UnexpectedExtraNonce);
+            }
+            transaction.verify_signature()?;
         }
 
-        // check that the previous_hash is valid
-        if block.previous_hash.as_ref().unwrap().clone() != last.hash {
-            return Err("invalid previous hash");
+        let cumulative_work = parent.cumulative_work.saturating_add(work);
+        let tip = &blocks[blocks.len() - 1];
+        let tip_work = tree[&tip.header.hash].cumulative_work;
+
+        let mut state = self.state.lock().unwrap();
+        let (status, undo) = if previous_hash == tip.header.hash {
+            // check that every transfer is funded, a rejected block leaves the state untouched
+            let undo = state.apply_block(&block)?;
+
+            if persist {
+                if let Err(error) = self.storage.lock().unwrap().append_block(&block) {
+                    state.undo_block(&undo);
+                    return Err(error.into());
+                }
+            }
+
+            // append the block to the end
+            blocks.push(block.clone());
+            (BlockStatus::Extended, Some(undo))
+        } else if cumulative_work <= tip_work {
+            // the transactions of a side branch are only checked if it becomes the main chain
+            (BlockStatus::SideBranch, None)
+        } else {
+            let (orphaned, undo) =
+                self.reorganize(&mut blocks, &mut tree, &mut state, &block, persist)?;
+            (BlockStatus::Reorganized(orphaned), Some(undo))
+        };
+
+        // a block loaded from storage is already stored
+        let stored = !persist || !matches!(status, BlockStatus::SideBranch);
+        tree.insert(
+            block.header.hash.clone(),
+            TreeNode {
+                block,
+                cumulative_work,
+                stored,
+                undo,
+            },
+        );
+        if !matches!(status, BlockStatus::SideBranch) {
+            let (lock, signal) = &*self.tip_signal;
+            let _guard = lock.lock().unwrap();
+            self.tip_changes.fetch_add(1, Ordering::SeqCst);
+            signal.notify_all();
         }
 
-        // check that the hash matches the data
-        if block.hash != block.calculate_hash() {
-            return Err("invalid hash");
+        Ok(status)
+    }
+
+    // Makes the branch ending with the block the main chain
+    // The abandoned blocks are taken off a copy of the state back to the fork point and the branch
+    // is applied from there, and if a block of the branch turns out to be invalid,
+    // that block and every block built on it are forgotten and remembered as invalid
+    // The blocks of the branch that were not stored yet are stored once the branch is valid
+    // Returns the orphaned transactions and how to undo the new block
+    fn reorganize(
+        &self,
+        blocks: &mut BlockVec,
+        tree: &mut BlockTree,
+        state: &mut WorldState,
+        block: &Block,
+        persist: bool,
+    ) -> Result<(TransactionVec, BlockUndo), BlockchainError> {
+        // walk back from the block until reaching the main chain
+        let mut branch = vec![block.clone()];
+        let mut hash = block.header.previous_hash.clone().unwrap();
+        while blocks.get(tree[&hash].block.header.index as usize).map(|block| &block.header.hash) != Some(&hash) {
+            let node = &tree[&hash];
+            branch.push(node.block.clone());
+            // the genesis block is on the main chain, so every block here has a previous one
+            hash = node.block.header.previous_hash.clone().unwrap();
+        }
+        branch.reverse();
+        let fork_index = tree[&hash].block.header.index as usize;
+
+        // every block after the fork point is on the main chain, so it was applied with an undo
+        let mut next_state = state.clone();
+        for main_block in blocks[fork_index + 1..].iter().rev() {
+            let undo = tree[&main_block.header.hash].undo.as_ref().unwrap();
+            next_state.undo_block(undo);
+        }
+        let mut undos = Vec::with_capacity(branch.len());
+        for branch_block in &branch {
+            match next_state.apply_block(branch_block) {
+                Ok(undo) => undos.push(undo),
+                Err(error) => {
+                    let removed = remove_subtree(tree, &branch_block.header.hash);
+                    self.invalid_blocks.lock().unwrap().extend(removed);
+                    return Err(error.into());
+                }
+            }
+        }
+
+        if persist {
+            let mut storage = self.storage.lock().unwrap();
+            for branch_block in &branch {
+                // the new block is not in the tree yet and is stored with it
+                match tree.get_mut(&branch_block.header.hash) {
+                    Some(node) if node.stored => {}
+                    Some(node) => {
+                        storage.append_block(branch_block)?;
+                        node.stored = true;
+                    }
+                    None => storage.append_block(branch_block)?,
+                }
+            }
+        }
+
+        // the transactions of the abandoned blocks are orphaned unless the new branch includes them
+        let detached = blocks.split_off(fork_index + 1);
+        let included: HashSet<TransactionHash> = branch
+            .iter()
+            .flat_map(|branch_block| branch_block.transactions.iter())
+            .map(|transaction| transaction.hash())
+            .collect();
+        let orphaned = detached
+            .iter()
+            .flat_map(|detached_block| detached_block.transactions.iter().skip(1))
+            .filter(|transaction| !included.contains(&transaction.hash()))
+            .cloned()
+            .collect();
+
+        // only the blocks of the main chain keep their undo
+        for detached_block in &detached {
+            if let Some(node) = tree.get_mut(&detached_block.header.hash) {
+                node.undo = None;
+            }
+        }
+        // the new block is not in the tree yet and gets its undo when inserted
+        let undo = undos.pop().unwrap();
+        for (branch_block, undo) in branch.iter().zip(undos) {
+            if let Some(node) = tree.get_mut(&branch_block.header.hash) {
+                node.undo = Some(undo);
+            }
         }
 
-        // check that the target is correct
-        if !block
-            .hash
-            .starts_with(&"0".repeat(self.difficulty as usize))
+        blocks.extend(branch);
+        *state = next_state;
+
+        Ok((orphaned, undo))
+    }
+
+    // A valid block starts with a single coinbase that pays the reward for its index
+    // plus the fees of all the other transactions, and has no fee, public key or signature
+    fn check_coinbase(&self, block: &Block) -> Result<(), BlockchainError> {
+        let coinbase = match block.transactions.first() {
+            Some(transaction) if transaction.is_coinbase() => transaction,
+            _ => return Err(BlockchainError::MissingCoinbase),
+        };
+
+        if block
+            .transactions
+            .iter()
+            .skip(1)
+            .any(|transaction| transaction.is_coinbase())
         {
-            return Err("invalid target");
+            return Err(BlockchainError::UnexpectedCoinbase);
         }
 
-        // append the block to the end
-        blocks.push(block);
+        let expected = block
+            .transactions
+            .iter()
+            .skip(1)
+            .fold(self.get_block_reward(block.header.index), |expected, transaction| {
+                expected.saturating_add(transaction.fee)
+            });
+        if coinbase.amount != expected {
+            return Err(BlockchainError::WrongCoinbaseAmount {
+                expected,
+                amount: coinbase.amount,
+            });
+        }
+
+        if coinbase.nonce != block.header.index {
+            return Err(BlockchainError::WrongCoinbaseNonce);
+        }
+
+        // nothing is spent or signed by a coinbase, so these fields would only hide data
+        if coinbase.fee != 0 || !coinbase.public_key.is_empty() || !coinbase.signature.is_empty() {
+            return Err(BlockchainError::MalformedCoinbase);
+        }
 
         Ok(())
     }
+}
+
+// Removes a block and all its descendants from the block tree, returning their hashes
+fn remove_subtree(tree: &mut BlockTree, root: &BlockHash) -> Vec<BlockHash> {
+    let mut children: HashMap<&BlockHash, Vec<BlockHash>> = HashMap::new();
+    for (hash, node) in tree.iter() {
+        if let Some(previous_hash) = &node.block.header.previous_hash {
+            children.entry(previous_hash).or_default().push(hash.clone());
+        }
+    }
+
+    let mut removed = vec![root.clone()];
+    let mut next = 0;
+    while let Some(hash) = removed.get(next) {
+        removed.extend(children.get(hash).into_iter().flatten().cloned());
+        next += 1;
+    }
+    for hash in &removed {
+        tree.remove(hash);
+    }
+
+    removed
+}
+
+// The genesis block only depends on the initial target, so every node builds the same one
+pub fn create_genesis_block(target: CompactTarget) -> Block {
+    let mut block = Block::new(0, target, 0, None, vec![]);
+
+    block.header.timestamp = 0;
+    block.header.hash = block.header.calculate_hash();
+
+    block
+}
+
+// Checks everything about a header that does not need the transactions of its block:
+// that it follows its parent, is not from the future, has the expected target,
+// matches its hash and carries a valid seal
+// Returns the work of the header, as counted by the consensus engine
+pub fn check_header(
+    header: &BlockHeader,
+    parent: &BlockHeader,
+    expected_target: CompactTarget,
+    consensus: &dyn ConsensusEngine,
+) -> Result<Work, BlockchainError> {
+    // check that the index is valid
+    if header.index != parent.index + 1 {
+        return Err(BlockchainError::WrongIndex);
+    }
+
+    // check that the block does not pretend to be older than its parent or from the future,
+    // which would skew the retargeting
+    let now = chrono::Utc::now().timestamp_millis();
+    if header.timestamp < parent.timestamp
+        || header.timestamp > now.saturating_add(MAX_FUTURE_BLOCK_TIME_MS)
+    {
+        return Err(BlockchainError::WrongTimestamp);
+    }
+
+    // check that the target follows the retargeting rule
+    if header.target != expected_target {
+        return Err(BlockchainError::WrongTarget {
+            expected: expected_target,
+            target: header.target,
+        });
+    }
 
-    fn create_genesis_block() -> Block {
-        let mut block = Block::new(0, 0, None, vec![]);
+    // check that the hash matches the header
+    if header.hash != header.calculate_hash() {
+        return Err(BlockchainError::HashMismatch);
+    }
+
+    // check that the header was sealed the way the consensus engine demands
+    Ok(consensus.verify_seal(header)?)
+}
 
-        block.timestamp = 0;
-        block.hash = block.calculate_hash();
+// Target the header following `parent` must have
+// The target only changes on the first block of every retarget interval,
+// scaled by how long the blocks of the previous interval took on the branch of the parent,
+// whose ancestors are looked up by hash
+// The genesis block is left out of the measure since it has no real timestamp
+pub fn next_target<'a>(
+    retarget_interval: u64,
+    target_block_time_ms: u64,
+    parent: &'a BlockHeader,
+    get_header: impl Fn(&BlockHash) -> Option<&'a BlockHeader>,
+) -> CompactTarget {
+    let index = parent.index + 1;
+    if retarget_interval == 0 || !index.is_multiple_of(retarget_interval) {
+        return parent.target;
+    }
 
-        block
+    let first_index = index.saturating_sub(retarget_interval).max(1);
+    let mut first = parent;
+    while first.index > first_index {
+        match first.previous_hash.as_ref().and_then(&get_header) {
+            Some(header) => first = header,
+            None => return parent.target,
+        }
     }
+    let intervals = parent.index - first.index;
+    let target = match Target::from_compact(parent.target) {
+        Some(target) if intervals > 0 => target,
+        _ => return parent.target,
+    };
+
+    // blocks that came faster than expected shrink the target, slower ones grow it
+    let expected = intervals.saturating_mul(target_block_time_ms);
+    let actual = (parent.timestamp.saturating_sub(first.timestamp).max(0) as u64)
+        .clamp(expected / RETARGET_FACTOR, expected.saturating_mul(RETARGET_FACTOR))
+        .max(1);
+    target.scale(actual, expected).to_compact()
+}
+
+// Hashes of a chain of the given height from the tip back to the genesis, dense near the tip
+// and then exponentially sparser, with the hash at each height given by `hash_at`
+pub fn build_locator(height: usize, hash_at: impl Fn(usize) -> BlockHash) -> Vec<BlockHash> {
+    let mut locator = Vec::new();
+    let mut index = height;
+    let mut step = 1;
+    while index > 0 {
+        locator.push(hash_at(index));
+        if locator.len() >= 10 {
+            step *= 2;
+        }
+        index = index.saturating_sub(step);
+    }
+    locator.push(hash_at(0));
+
+    locator
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    const NO_TARGET: u32 = 0;
+    use crate::storage::file_storage::{FileStorage, SyncPolicy};
+    use crate::types::merkle::verify_proof;
+    use crate::types::transaction::address_from_public_key;
+    use crate::util::test_helpers::{
+        chain_params, create_pool, create_signing_key, mine, proof_of_work, EASIEST_TARGET,
+    };
+    use ed25519_dalek::SigningKey;
+
+    const ALICE: u8 = 1;
+    const BOB: u8 = 2;
+    const CAROL: u8 = 3;
+    const MINER: u8 = 4;
+
+    const BLOCK_REWARD: u64 = 50;
+    const HALVING_INTERVAL: u64 = 2;
+
+    fn address_of(signing_key: &SigningKey) -> Address {
+        address_from_public_key(&signing_key.verifying_key())
+    }
+
+    // The reward halves every other block at a fixed target
+    fn halving_params() -> ChainParams {
+        ChainParams {
+            halving_interval: HALVING_INTERVAL,
+            ..chain_params(EASIEST_TARGET, BLOCK_REWARD)
+        }
+    }
+
+    // alice is the only account with funds
+    fn create_blockchain() -> Blockchain {
+        let mut genesis_balances = Balances::new();
+        genesis_balances.insert(address_of(&create_signing_key(ALICE)), 10);
+        Blockchain::new(proof_of_work(), halving_params(), genesis_balances)
+    }
+
+    // Creates the next block paying the correct reward and fees to the miner
+    fn create_next_block(blockchain: &Blockchain, transactions: Vec<Transaction>) -> Block {
+        let index = blockchain.get_last_block().header.index + 1;
+        let fees: u64 = transactions.iter().map(|transaction| transaction.fee).sum();
+        let reward = blockchain.get_block_reward(index) + fees;
+        let coinbase = Transaction::new_coinbase(address_of(&create_signing_key(MINER)), reward, index);
+        create_next_block_with_coinbase(blockchain, coinbase, transactions)
+    }
+
+    fn create_next_block_with_coinbase(
+        blockchain: &Blockchain,
+        coinbase: Transaction,
+        transactions: Vec<Transaction>,
+    ) -> Block {
+        let last_block = blockchain.get_last_block();
+        let mut block_transactions = vec![coinbase];
+        block_transactions.extend(transactions);
+        mine(Block::new(last_block.header.index + 1, EASIEST_TARGET, 0, Some(last_block.header.hash), block_transactions))
+    }
+
+    // Creates a block on top of any known block, paying the given miner
+    // so that the blocks of competing branches differ
+    fn create_block_on(
+        blockchain: &Blockchain,
+        parent: &Block,
+        miner: u8,
+        transactions: Vec<Transaction>,
+    ) -> Block {
+        let index = parent.header.index + 1;
+        let reward = blockchain.get_block_reward(index);
+        let coinbase = Transaction::new_coinbase(address_of(&create_signing_key(miner)), reward, index);
+        let mut block_transactions = vec![coinbase];
+        block_transactions.extend(transactions);
+        mine(Block::new(index, EASIEST_TARGET, 0, Some(parent.header.hash.clone()), block_transactions))
+    }
+
+    // Mines a block on top of the tip with the given timestamp and target
+    fn mine_next_block(blockchain: &Blockchain, timestamp: i64, target: CompactTarget) -> Block {
+        let mut block = create_next_block(blockchain, vec![]);
+        block.header.timestamp = timestamp;
+        block.header.target = target;
+        mine(block)
+    }
+
+    // Builds a chain that retargets every 4 blocks aiming at one block per second
+    // and adds blocks with the given timestamps at the initial target
+    fn create_retargeting_blockchain(target: CompactTarget, timestamps: &[i64]) -> Blockchain {
+        let params = ChainParams {
+            retarget_interval: 4,
+            target_block_time_ms: 1_000,
+            ..chain_params(target, BLOCK_REWARD)
+        };
+        let blockchain = Blockchain::new(proof_of_work(), params, Balances::new());
+        for &timestamp in timestamps {
+            let block = mine_next_block(&blockchain, timestamp, target);
+            blockchain.add_block(block).unwrap();
+        }
+        blockchain
+    }
+
+    fn scale(target: CompactTarget, numerator: u64, denominator: u64) -> CompactTarget {
+        Target::from_compact(target)
+            .unwrap()
+            .scale(numerator, denominator)
+            .to_compact()
+    }
+
+    fn hashes_of(blocks: &BlockVec) -> Vec<BlockHash> {
+        blocks.iter().map(|block| block.header.hash.clone()).collect()
+    }
+
+    fn open_blockchain(data_dir: &tempfile::TempDir) -> Result<Blockchain, BlockchainError> {
+        let storage = FileStorage::open(data_dir.path(), SyncPolicy::Always).unwrap();
+        let mut genesis_balances = Balances::new();
+        genesis_balances.insert(address_of(&create_signing_key(ALICE)), 10);
+        Blockchain::open(proof_of_work(), halving_params(), genesis_balances, Box::new(storage))
+    }
+
+    fn create_mock_transaction(sender: u8, recipient: u8, amount: u64, nonce: u64) -> Transaction {
+        create_transaction_with_fee(sender, recipient, amount, 0, nonce)
+    }
+
+    fn create_transaction_with_fee(
+        sender: u8,
+        recipient: u8,
+        amount: u64,
+        fee: u64,
+        nonce: u64,
+    ) -> Transaction {
+        let recipient = address_of(&create_signing_key(recipient));
+        Transaction::new_signed(&create_signing_key(sender), recipient, amount, fee, nonce)
+    }
+
+    fn balance_of(blockchain: &Blockchain, seed: u8) -> u64 {
+        blockchain
+            .get_world_state()
+            .get_balance(&address_of(&create_signing_key(seed)))
+    }
 
     #[test]
     fn is_valid_genesis_block() {
-        let blockchain = Blockchain::new(NO_TARGET);
+        let blockchain = create_blockchain();
 
         let blocks = blockchain.get_all_blocks();
         assert_eq!(blocks.len(), 1);
 
         let block = blockchain.get_last_block();
-        assert_eq!(block.hash, blocks[0].hash);
+        assert_eq!(block.header.hash, blocks[0].header.hash);
 
-        assert_eq!(block.index, 0);
-        assert_eq!(block.nonce, 0);
-        assert_eq!(block.previous_hash, None);
+        assert_eq!(block.header.index, 0);
+        assert_eq!(block.header.nonce, 0);
+        assert_eq!(block.header.previous_hash, None);
         assert!(block.transactions.is_empty());
     }
+
+    #[test]
+    fn funded_block_updates_balances() {
+        let blockchain = create_blockchain();
+
+        let transaction = create_mock_transaction(ALICE, BOB, 4, 0);
+        let block = create_next_block(&blockchain, vec![transaction]);
+        blockchain.add_block(block).unwrap();
+
+        assert_eq!(blockchain.get_all_blocks().len(), 2);
+        assert_eq!(balance_of(&blockchain, ALICE), 6);
+        assert_eq!(balance_of(&blockchain, BOB), 4);
+    }
+
+    #[test]
+    fn overspending_block_is_rejected() {
+        let blockchain = create_blockchain();
+
+        // the first transfer is funded but the second one is not
+        let block = create_next_block(
+            &blockchain,
+            vec![
+                create_mock_transaction(ALICE, BOB, 4, 0),
+                create_mock_transaction(BOB, CAROL, 5, 0),
+            ],
+        );
+        let result = blockchain.add_block(block);
+
+        assert!(matches!(
+            result,
+            Err(BlockchainError::InvalidTransaction(
+                WorldStateError::InsufficientBalance { .. }
+            ))
+        ));
+
+        // neither the blocks nor the balances must change
+        assert_eq!(blockchain.get_all_blocks().len(), 1);
+        assert_eq!(balance_of(&blockchain, ALICE), 10);
+        assert_eq!(balance_of(&blockchain, BOB), 0);
+    }
+
+    #[test]
+    fn body_not_matching_the_header_is_rejected() {
+        let blockchain = create_blockchain();
+
+        // the header still commits to the block without the transfer
+        let mut block = create_next_block(&blockchain, vec![]);
+        block.transactions.push(create_mock_transaction(ALICE, BOB, 4, 0));
+        let result = blockchain.add_block(block);
+
+        assert!(matches!(result, Err(BlockchainError::TransactionsRootMismatch)));
+        assert_eq!(balance_of(&blockchain, BOB), 0);
+    }
+
+    #[test]
+    fn tampered_transaction_is_rejected() {
+        let blockchain = create_blockchain();
+
+        let mut transaction = create_mock_transaction(ALICE, BOB, 4, 0);
+        transaction.recipient = address_of(&create_signing_key(CAROL));
+        let block = create_next_block(&blockchain, vec![transaction]);
+        let result = blockchain.add_block(block);
+
+        assert!(matches!(result, Err(BlockchainError::InvalidSignature(_))));
+        assert_eq!(blockchain.get_all_blocks().len(), 1);
+    }
+
+    #[test]
+    fn signed_transaction_with_an_extra_nonce_is_rejected() {
+        let blockchain = create_blockchain();
+
+        let mut transaction = create_mock_transaction(ALICE, BOB, 4, 0);
+        transaction.extra_nonce = 1;
+        transaction.sign(&create_signing_key(ALICE));
+        let block = create_next_block(&blockchain, vec![transaction]);
+        let result = blockchain.add_block(block);
+
+        assert!(matches!(result, Err(BlockchainError::UnexpectedExtraNonce)));
+        assert_eq!(blockchain.get_all_blocks().len(), 1);
+    }
+
+    #[test]
+    fn replayed_transaction_is_rejected() {
+        let blockchain = create_blockchain();
+
+        let transaction = create_mock_transaction(ALICE, BOB, 4, 0);
+        let block = create_next_block(&blockchain, vec![transaction.clone()]);
+        blockchain.add_block(block).unwrap();
+
+        // the same signed transaction can not be mined again in a later block
+        let block = create_next_block(&blockchain, vec![transaction]);
+        let result = blockchain.add_block(block);
+
+        assert!(matches!(
+            result,
+            Err(BlockchainError::InvalidTransaction(
+                WorldStateError::NonceMismatch { .. }
+            ))
+        ));
+        assert_eq!(balance_of(&blockchain, BOB), 4);
+    }
+
+    #[test]
+    fn coinbase_pays_the_miner() {
+        let blockchain = create_blockchain();
+
+        let block = create_next_block(&blockchain, vec![]);
+        blockchain.add_block(block).unwrap();
+
+        assert_eq!(balance_of(&blockchain, MINER), BLOCK_REWARD);
+    }
+
+    #[test]
+    fn block_without_coinbase_is_rejected() {
+        let blockchain = create_blockchain();
+
+        let transaction = create_mock_transaction(ALICE, BOB, 4, 0);
+        let block = create_next_block_with_coinbase(&blockchain, transaction, vec![]);
+        let result = blockchain.add_block(block);
+
+        assert!(matches!(result, Err(BlockchainError::MissingCoinbase)));
+    }
+
+    #[test]
+    fn block_with_two_coinbases_is_rejected() {
+        let blockchain = create_blockchain();
+
+        let miner = address_of(&create_signing_key(MINER));
+        let extra_coinbase = Transaction::new_coinbase(miner, BLOCK_REWARD, 1);
+        let block = create_next_block(&blockchain, vec![extra_coinbase]);
+        let result = blockchain.add_block(block);
+
+        assert!(matches!(result, Err(BlockchainError::UnexpectedCoinbase)));
+        assert_eq!(balance_of(&blockchain, MINER), 0);
+    }
+
+    #[test]
+    fn coinbase_minting_too_much_is_rejected() {
+        let blockchain = create_blockchain();
+
+        let miner = address_of(&create_signing_key(MINER));
+        let coinbase = Transaction::new_coinbase(miner, BLOCK_REWARD + 1, 1);
+        let block = create_next_block_with_coinbase(&blockchain, coinbase, vec![]);
+        let result = blockchain.add_block(block);
+
+        assert!(matches!(
+            result,
+            Err(BlockchainError::WrongCoinbaseAmount {
+                expected: BLOCK_REWARD,
+                amount: 51
+            })
+        ));
+    }
+
+    #[test]
+    fn block_reward_halves_on_schedule() {
+        let blockchain = create_blockchain();
+
+        assert_eq!(blockchain.get_block_reward(1), BLOCK_REWARD);
+        assert_eq!(blockchain.get_block_reward(2), BLOCK_REWARD / 2);
+        assert_eq!(blockchain.get_block_reward(4), BLOCK_REWARD / 4);
+        assert_eq!(blockchain.get_block_reward(200), 0);
+
+        let no_halving = Blockchain::new(proof_of_work(), chain_params(EASIEST_TARGET, BLOCK_REWARD), Balances::new());
+        assert_eq!(no_halving.get_block_reward(200), BLOCK_REWARD);
+    }
+
+    #[test]
+    fn fees_are_paid_to_the_miner() {
+        let blockchain = create_blockchain();
+
+        let transaction = create_transaction_with_fee(ALICE, BOB, 4, 3, 0);
+        let block = create_next_block(&blockchain, vec![transaction]);
+        blockchain.add_block(block).unwrap();
+
+        assert_eq!(balance_of(&blockchain, ALICE), 3);
+        assert_eq!(balance_of(&blockchain, BOB), 4);
+        assert_eq!(balance_of(&blockchain, MINER), BLOCK_REWARD + 3);
+    }
+
+    #[test]
+    fn coinbase_without_fees_is_rejected() {
+        let blockchain = create_blockchain();
+
+        let miner = address_of(&create_signing_key(MINER));
+        let coinbase = Transaction::new_coinbase(miner, BLOCK_REWARD, 1);
+        let transaction = create_transaction_with_fee(ALICE, BOB, 4, 3, 0);
+        let block = create_next_block_with_coinbase(&blockchain, coinbase, vec![transaction]);
+        let result = blockchain.add_block(block);
+
+        assert!(matches!(
+            result,
+            Err(BlockchainError::WrongCoinbaseAmount {
+                expected: 53,
+                amount: BLOCK_REWARD
+            })
+        ));
+    }
+
+    #[test]
+    fn coinbase_with_a_fee_or_a_signature_is_rejected() {
+        let blockchain = create_blockchain();
+        let miner = address_of(&create_signing_key(MINER));
+
+        let mut with_fee = Transaction::new_coinbase(miner.clone(), BLOCK_REWARD, 1);
+        with_fee.fee = 1;
+        let mut with_public_key = Transaction::new_coinbase(miner.clone(), BLOCK_REWARD, 1);
+        with_public_key.public_key = hex::encode([1; 32]);
+        let mut signed = Transaction::new_coinbase(miner, BLOCK_REWARD, 1);
+        signed.sign(&create_signing_key(MINER));
+
+        for coinbase in [with_fee, with_public_key, signed] {
+            let block = create_next_block_with_coinbase(&blockchain, coinbase, vec![]);
+            assert!(matches!(
+                blockchain.add_block(block),
+                Err(BlockchainError::MalformedCoinbase)
+            ));
+        }
+    }
+
+    #[test]
+    fn block_over_the_limits_is_rejected() {
+        let mut genesis_balances = Balances::new();
+        genesis_balances.insert(address_of(&create_signing_key(ALICE)), 10);
+        let to_bob = create_mock_transaction(ALICE, BOB, 1, 0);
+        let to_carol = create_mock_transaction(ALICE, CAROL, 1, 1);
+
+        let params = ChainParams {
+            max_block_transactions: 2,
+            ..halving_params()
+        };
+        let blockchain = Blockchain::new(proof_of_work(), params, genesis_balances.clone());
+        let block = create_next_block(&blockchain, vec![to_bob.clone(), to_carol.clone()]);
+        assert!(matches!(
+            blockchain.add_block(block),
+            Err(BlockchainError::TooManyTransactions { count: 3, max: 2 })
+        ));
+
+        let block = create_next_block(&blockchain, vec![to_bob.clone()]);
+        let size: usize = block.transactions.iter().map(Transaction::size).sum();
+        let params = ChainParams {
+            max_block_size: size - 1,
+            ..halving_params()
+        };
+        let blockchain = Blockchain::new(proof_of_work(), params, genesis_balances);
+        assert!(matches!(
+            blockchain.add_block(block.clone()),
+            Err(BlockchainError::BlockTooLarge { .. })
+        ));
+    }
+
+    #[test]
+    fn pool_room_leaves_space_for_the_coinbase() {
+        let mut genesis_balances = Balances::new();
+        genesis_balances.insert(address_of(&create_signing_key(ALICE)), 10);
+        let to_bob = create_mock_transaction(ALICE, BOB, 1, 0);
+        let to_carol = create_mock_transaction(ALICE, CAROL, 1, 1);
+
+        // the limits fit exactly one transaction next to the largest coinbase
+        let miner = address_of(&create_signing_key(MINER));
+        let mut largest_coinbase = Transaction::new_coinbase(miner.clone(), u64::MAX, 1);
+        largest_coinbase.extra_nonce = u64::MAX;
+        let params = ChainParams {
+            max_block_transactions: 3,
+            max_block_size: largest_coinbase.size() + to_bob.size(),
+            ..halving_params()
+        };
+        let blockchain = Blockchain::new(proof_of_work(), params, genesis_balances);
+        assert_eq!(blockchain.get_pool_room(&miner, 1), (2, to_bob.size()));
+
+        let pool = create_pool();
+        let state = blockchain.get_world_state();
+        pool.add_transaction(to_bob, &state).unwrap();
+        pool.add_transaction(to_carol, &state).unwrap();
+        let (max_transactions, max_size) = blockchain.get_pool_room(&miner, 1);
+        let transactions = pool.pop_best(&state, max_transactions, max_size);
+        assert_eq!(transactions.len(), 1);
+
+        let block = create_next_block(&blockchain, transactions);
+        blockchain.add_block(block).unwrap();
+    }
+
+    #[test]
+    fn blocks_are_reloaded_from_storage() {
+        let data_dir = tempfile::TempDir::new().unwrap();
+
+        let blockchain = open_blockchain(&data_dir).unwrap();
+        let transaction = create_mock_transaction(ALICE, BOB, 4, 0);
+        let block = create_next_block(&blockchain, vec![transaction]);
+        blockchain.add_block(block).unwrap();
+        let last_hash = blockchain.get_last_block().header.hash;
+        drop(blockchain);
+
+        // the block and the balances it produced are back after reopening
+        let blockchain = open_blockchain(&data_dir).unwrap();
+        assert_eq!(blockchain.get_all_blocks().len(), 2);
+        assert_eq!(blockchain.get_last_block().header.hash, last_hash);
+        assert_eq!(balance_of(&blockchain, BOB), 4);
+        assert_eq!(balance_of(&blockchain, MINER), BLOCK_REWARD);
+    }
+
+    #[test]
+    fn invalid_stored_block_is_rejected() {
+        let mut storage = MemoryStorage::default();
+        let blockchain = create_blockchain();
+        let mut block = create_next_block(&blockchain, vec![]);
+        block.header.index = 5;
+        storage.append_block(&block).unwrap();
+
+        let result = Blockchain::open(
+            proof_of_work(),
+            halving_params(),
+            Balances::new(),
+            Box::new(storage),
+        );
+
+        assert!(matches!(
+            result,
+            Err(BlockchainError::InvalidStoredBlock { index: 5, .. })
+        ));
+    }
+
+    #[test]
+    fn competing_block_with_equal_work_is_kept_aside() {
+        let blockchain = create_blockchain();
+        let genesis = blockchain.get_last_block();
+
+        let main_block = create_block_on(&blockchain, &genesis, MINER, vec![]);
+        blockchain.add_block(main_block.clone()).unwrap();
+        let side_block = create_block_on(&blockchain, &genesis, CAROL, vec![]);
+        let status = blockchain.add_block(side_block.clone()).unwrap();
+
+        // the first block seen at a height stays on the main chain
+        assert!(matches!(status, BlockStatus::SideBranch));
+        assert_eq!(blockchain.get_last_block().header.hash, main_block.header.hash);
+        assert_eq!(balance_of(&blockchain, MINER), BLOCK_REWARD);
+        assert_eq!(balance_of(&blockchain, CAROL), 0);
+
+        let result = blockchain.add_block(side_block);
+        assert!(matches!(result, Err(BlockchainError::KnownBlock)));
+    }
+
+    #[test]
+    fn branch_with_more_work_becomes_the_main_chain() {
+        let blockchain = create_blockchain();
+        let genesis = blockchain.get_last_block();
+        let to_bob = create_mock_transaction(ALICE, BOB, 4, 0);
+        let to_carol = create_mock_transaction(ALICE, CAROL, 1, 1);
+
+        let main_block = create_block_on(
+            &blockchain,
+            &genesis,
+            MINER,
+            vec![to_bob.clone(), to_carol.clone()],
+        );
+        blockchain.add_block(main_block).unwrap();
+        assert_eq!(blockchain.get_tip_changes(), 1);
+
+        // the competing branch only includes the payment to bob
+        let side_block = create_block_on(&blockchain, &genesis, CAROL, vec![to_bob]);
+        blockchain.add_block(side_block.clone()).unwrap();
+        assert_eq!(blockchain.get_tip_changes(), 1);
+        let next_block = create_block_on(&blockchain, &side_block, CAROL, vec![]);
+        let status = blockchain.add_block(next_block.clone()).unwrap();
+        assert_eq!(blockchain.get_tip_changes(), 2);
+
+        match status {
+            BlockStatus::Reorganized(orphaned) => {
+                assert_eq!(orphaned.len(), 1);
+                assert_eq!(orphaned[0].hash(), to_carol.hash());
+            }
+            status => panic!("unexpected status {:?}", status),
+        }
+        assert_eq!(
+            hashes_of(&blockchain.get_all_blocks()),
+            vec![genesis.header.hash, side_block.header.hash, next_block.header.hash]
+        );
+
+        // the state follows the new main chain
+        assert_eq!(balance_of(&blockchain, ALICE), 6);
+        assert_eq!(balance_of(&blockchain, BOB), 4);
+        assert_eq!(balance_of(&blockchain, MINER), 0);
+        assert_eq!(balance_of(&blockchain, CAROL), BLOCK_REWARD + BLOCK_REWARD / 2);
+    }
+
+    #[test]
+    fn waiting_threads_wake_up_on_tip_changes() {
+        let blockchain = create_blockchain();
+        let genesis = blockchain.get_last_block();
+        assert_eq!(blockchain.wait_for_tip_change(0, 10), 0);
+
+        let chain = blockchain.clone();
+        let waiter = std::thread::spawn(move || chain.wait_for_tip_change(0, 60_000));
+        let main_block = create_block_on(&blockchain, &genesis, MINER, vec![]);
+        blockchain.add_block(main_block).unwrap();
+        assert_eq!(waiter.join().unwrap(), 1);
+
+        // a block on a side branch leaves the tip as it is
+        let side_block = create_block_on(&blockchain, &genesis, CAROL, vec![]);
+        blockchain.add_block(side_block).unwrap();
+        assert_eq!(blockchain.wait_for_tip_change(1, 10), 1);
+    }
+
+    #[test]
+    fn state_follows_the_main_chain_back_and_forth() {
+        let blockchain = create_blockchain();
+        let genesis = blockchain.get_last_block();
+        let to_bob = create_mock_transaction(ALICE, BOB, 4, 0);
+        let to_carol = create_mock_transaction(ALICE, CAROL, 3, 0);
+
+        let main_block = create_block_on(&blockchain, &genesis, MINER, vec![to_bob]);
+        blockchain.add_block(main_block.clone()).unwrap();
+
+        // the side branch spends the same nonce of alice on another payment
+        let side_block = create_block_on(&blockchain, &genesis, CAROL, vec![to_carol]);
+        blockchain.add_block(side_block.clone()).unwrap();
+        let side_block = create_block_on(&blockchain, &side_block, CAROL, vec![]);
+        blockchain.add_block(side_block).unwrap();
+        assert_eq!(balance_of(&blockchain, ALICE), 7);
+        assert_eq!(balance_of(&blockchain, BOB), 0);
+        assert_eq!(balance_of(&blockchain, MINER), 0);
+
+        // the first branch takes over again, undoing the side branch
+        let main_block = create_block_on(&blockchain, &main_block, MINER, vec![]);
+        blockchain.add_block(main_block.clone()).unwrap();
+        let main_block = create_block_on(&blockchain, &main_block, MINER, vec![]);
+        assert!(matches!(
+            blockchain.add_block(main_block.clone()).unwrap(),
+            BlockStatus::Reorganized(_)
+        ));
+        assert_eq!(balance_of(&blockchain, ALICE), 6);
+        assert_eq!(balance_of(&blockchain, BOB), 4);
+        assert_eq!(balance_of(&blockchain, CAROL), 0);
+        assert_eq!(
+            balance_of(&blockchain, MINER),
+            BLOCK_REWARD + BLOCK_REWARD / 2 + BLOCK_REWARD / 2
+        );
+
+        // alice's next nonce follows the payment to bob
+        let next_block = create_block_on(
+            &blockchain,
+            &main_block,
+            MINER,
+            vec![create_mock_transaction(ALICE, CAROL, 1, 1)],
+        );
+        blockchain.add_block(next_block).unwrap();
+        assert_eq!(balance_of(&blockchain, CAROL), 1);
+    }
+
+    #[test]
+    fn invalid_branch_does_not_replace_the_main_chain() {
+        let blockchain = create_blockchain();
+        let genesis = blockchain.get_last_block();
+
+        let main_block = create_block_on(&blockchain, &genesis, MINER, vec![]);
+        blockchain.add_block(main_block.clone()).unwrap();
+
+        // bob has no funds, which is only noticed once the branch would become the main chain
+        let overspending = create_mock_transaction(BOB, CAROL, 5, 0);
+        let side_block = create_block_on(&blockchain, &genesis, CAROL, vec![overspending]);
+        blockchain.add_block(side_block.clone()).unwrap();
+        let next_block = create_block_on(&blockchain, &side_block, CAROL, vec![]);
+        let result = blockchain.add_block(next_block.clone());
+
+        assert!(matches!(
+            result,
+            Err(BlockchainError::InvalidTransaction(
+                WorldStateError::InsufficientBalance { .. }
+            ))
+        ));
+        assert_eq!(blockchain.get_last_block().header.hash, main_block.header.hash);
+        assert_eq!(balance_of(&blockchain, MINER), BLOCK_REWARD);
+
+        // the invalid branch is forgotten and rejected without being checked again
+        let result = blockchain.add_block(next_block);
+        assert!(matches!(result, Err(BlockchainError::KnownInvalidBlock)));
+        let result = blockchain.add_block(side_block);
+        assert!(matches!(result, Err(BlockchainError::KnownInvalidBlock)));
+    }
+
+    #[test]
+    fn invalid_branch_is_forgotten_with_all_its_descendants() {
+        let data_dir = tempfile::TempDir::new().unwrap();
+
+        let blockchain = open_blockchain(&data_dir).unwrap();
+        let genesis = blockchain.get_last_block();
+        let main_block = create_block_on(&blockchain, &genesis, MINER, vec![]);
+        blockchain.add_block(main_block.clone()).unwrap();
+        let main_block = create_block_on(&blockchain, &main_block, MINER, vec![]);
+        blockchain.add_block(main_block.clone()).unwrap();
+
+        // two side branches grow from a block spending funds bob does not have
+        let overspending = create_mock_transaction(BOB, CAROL, 5, 0);
+        let side_block = create_block_on(&blockchain, &genesis, CAROL, vec![overspending]);
+        blockchain.add_block(side_block.clone()).unwrap();
+        let first_child = create_block_on(&blockchain, &side_block, CAROL, vec![]);
+        blockchain.add_block(first_child.clone()).unwrap();
+        let second_child = create_block_on(&blockchain, &side_block, BOB, vec![]);
+        blockchain.add_block(second_child.clone()).unwrap();
+        let next_block = create_block_on(&blockchain, &first_child, CAROL, vec![]);
+        assert!(blockchain.add_block(next_block).is_err());
+
+        // nothing can build on the other branch anymore
+        for block in [&side_block, &first_child, &second_child] {
+            assert!(!blockchain.contains_block(&block.header.hash));
+        }
+        let orphan = create_block_on(&blockchain, &second_child, BOB, vec![]);
+        assert!(matches!(blockchain.add_block(orphan), Err(BlockchainError::KnownInvalidBlock)));
+        drop(blockchain);
+
+        // the unchecked side blocks were never stored
+        let blockchain = open_blockchain(&data_dir).unwrap();
+        assert_eq!(blockchain.get_last_block().header.hash, main_block.header.hash);
+        assert!(!blockchain.contains_block(&side_block.header.hash));
+    }
+
+    #[test]
+    fn reorganized_chain_is_reloaded_from_storage() {
+        let data_dir = tempfile::TempDir::new().unwrap();
+
+        let blockchain = open_blockchain(&data_dir).unwrap();
+        let genesis = blockchain.get_last_block();
+        let main_block = create_block_on(&blockchain, &genesis, MINER, vec![]);
+        blockchain.add_block(main_block).unwrap();
+        let side_block = create_block_on(&blockchain, &genesis, CAROL, vec![]);
+        blockchain.add_block(side_block.clone()).unwrap();
+        let next_block = create_block_on(&blockchain, &side_block, CAROL, vec![]);
+        blockchain.add_block(next_block).unwrap();
+        let blocks = blockchain.get_all_blocks();
+        drop(blockchain);
+
+        let blockchain = open_blockchain(&data_dir).unwrap();
+        assert_eq!(hashes_of(&blockchain.get_all_blocks()), hashes_of(&blocks));
+        assert_eq!(balance_of(&blockchain, MINER), 0);
+    }
+
+    #[test]
+    fn headers_follow_the_last_shared_block() {
+        let blockchain = create_blockchain();
+        for _ in 0..30 {
+            blockchain.add_block(create_next_block(&blockchain, vec![])).unwrap();
+        }
+        let blocks = blockchain.get_all_blocks();
+
+        // the locator goes from the tip back to the genesis
+        let locator = blockchain.get_locator();
+        assert_eq!(locator[0], blocks[30].header.hash);
+        assert_eq!(locator[locator.len() - 1], blocks[0].header.hash);
+        assert!(locator.len() < blocks.len());
+
+        // a peer that stopped at block 12 on the same chain gets the headers after it
+        let peer_locator = vec![BlockHash::default(), blocks[12].header.hash.clone()];
+        let headers = blockchain.get_headers_after(&peer_locator, 5);
+        let indexes: Vec<u64> = headers.iter().map(|header| header.index).collect();
+        assert_eq!(indexes, vec![13, 14, 15, 16, 17]);
+
+        // and a peer with nothing in common starts after the genesis
+        let headers = blockchain.get_headers_after(&[], 100);
+        assert_eq!(headers.len(), 30);
+        assert_eq!(headers[0].hash, blocks[1].header.hash);
+    }
+
+    #[test]
+    fn included_transactions_can_be_proven() {
+        let blockchain = create_blockchain();
+        let transactions = vec![
+            create_mock_transaction(ALICE, BOB, 1, 0),
+            create_mock_transaction(ALICE, BOB, 1, 1),
+            create_mock_transaction(ALICE, BOB, 1, 2),
+        ];
+        blockchain.add_block(create_next_block(&blockchain, transactions.clone())).unwrap();
+        blockchain.add_block(create_next_block(&blockchain, vec![])).unwrap();
+
+        let proof = blockchain.get_transaction_proof(&transactions[1].hash()).unwrap();
+        assert_eq!(proof.header.index, 1);
+        assert!(verify_proof(&proof));
+
+        let pending = create_mock_transaction(ALICE, BOB, 1, 3);
+        assert!(blockchain.get_transaction_proof(&pending.hash()).is_none());
+    }
+
+    #[test]
+    fn fast_blocks_shrink_the_target() {
+        let initial = Target::from_difficulty(16).to_compact();
+        // two intervals of 10ms instead of a second, the target shrinks as much as allowed
+        let blockchain = create_retargeting_blockchain(initial, &[1_000, 1_010, 1_020]);
+        let expected = scale(initial, 1, 4);
+        assert_eq!(blockchain.get_next_target(&blockchain.get_last_block()), expected);
+
+        let block = mine_next_block(&blockchain, 1_030, initial);
+        let result = blockchain.add_block(block);
+        assert!(matches!(
+            result,
+            Err(BlockchainError::WrongTarget { expected: e, target: t }) if e == expected && t == initial
+        ));
+
+        blockchain.add_block(mine_next_block(&blockchain, 1_030, expected)).unwrap();
+
+        // the target only changes at the start of an interval
+        assert_eq!(blockchain.get_next_target(&blockchain.get_last_block()), expected);
+    }
+
+    #[test]
+    fn slow_blocks_grow_the_target() {
+        let initial = Target::from_difficulty(16).to_compact();
+        // two intervals of 10s instead of a second, the target grows as much as allowed
+        let blockchain = create_retargeting_blockchain(initial, &[1_000, 11_000, 21_000]);
+        assert_eq!(blockchain.get_next_target(&blockchain.get_last_block()), scale(initial, 4, 1));
+
+        // but never beyond the easiest target
+        let blockchain = create_retargeting_blockchain(EASIEST_TARGET, &[1_000, 11_000, 21_000]);
+        assert_eq!(blockchain.get_next_target(&blockchain.get_last_block()), EASIEST_TARGET);
+
+        // blocks close to the target time adjust it in proportion
+        let blockchain = create_retargeting_blockchain(initial, &[1_000, 2_000, 3_500]);
+        assert_eq!(blockchain.get_next_target(&blockchain.get_last_block()), scale(initial, 2_500, 2_000));
+    }
+
+    #[test]
+    fn block_older_than_its_parent_is_rejected() {
+        let blockchain = create_retargeting_blockchain(EASIEST_TARGET, &[1_000]);
+
+        let block = mine_next_block(&blockchain, 999, EASIEST_TARGET);
+        let result = blockchain.add_block(block);
+
+        assert!(matches!(result, Err(BlockchainError::WrongTimestamp)));
+    }
+
+    #[test]
+    fn hash_above_the_target_is_rejected() {
+        // only one hash in 2^64 meets the target
+        let target = Target::from_difficulty(u64::MAX).to_compact();
+        let blockchain = Blockchain::new(proof_of_work(), chain_params(target, BLOCK_REWARD), Balances::new());
+
+        let genesis = blockchain.get_last_block();
+        let coinbase = Transaction::new_coinbase(address_of(&create_signing_key(MINER)), BLOCK_REWARD, 1);
+        let block = Block::new(1, target, 0, Some(genesis.header.hash), vec![coinbase]);
+        let result = blockchain.add_block(block);
+
+        assert!(matches!(
+            result,
+            Err(BlockchainError::InvalidSeal(ConsensusError::TargetNotMet))
+        ));
+    }
 }
@@ -0,0 +1,43 @@
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use std::fmt;
+
+// SHA-256 hash, hex encoded in JSON
+// Block hashes are read as big endian numbers when compared to a target
+#[derive(Clone, Default, PartialEq, Eq, Hash)]
+pub struct Hash256(pub [u8; 32]);
+
+impl Hash256 {
+    pub fn digest(data: &[u8]) -> Hash256 {
+        Hash256(Sha256::digest(data).into())
+    }
+}
+
+impl fmt::Display for Hash256 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex::encode(self.0))
+    }
+}
+
+impl fmt::Debug for Hash256 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl Serialize for Hash256 {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(self.0))
+    }
+}
+
+impl<'de> Deserialize<'de> for Hash256 {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Hash256, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        let bytes = hex::decode(encoded).map_err(de::Error::custom)?;
+        let hash = bytes
+            .try_into()
+            .map_err(|_| de::Error::custom("a hash is 32 bytes long"))?;
+        Ok(Hash256(hash))
+    }
+}
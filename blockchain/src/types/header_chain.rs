@@ -0,0 +1,255 @@
+use crate::consensus::SyncedConsensus;
+use crate::types::block::{BlockHash, BlockHeader};
+use crate::types::blockchain::{
+    build_locator, check_header, create_genesis_block, next_target, BlockchainError, ChainParams,
+};
+use crate::types::target::Work;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone)]
+struct HeaderNode {
+    header: BlockHeader,
+    // Work of the header and all its ancestors, as counted by the consensus engine
+    cumulative_work: Work,
+}
+
+type HeaderTree = HashMap<BlockHash, HeaderNode>;
+
+// What adding a valid header did to the chain
+#[derive(Debug, PartialEq, Eq)]
+pub enum HeaderStatus {
+    // The header extends the main chain
+    Extended,
+    // The header is kept on a side branch that does not have more work than the main chain
+    SideBranch,
+    // The branch of the header has more work and replaced the end of the main chain
+    Reorganized,
+}
+
+// Chain of block headers without the transactions, for nodes that do not keep the blocks
+// Headers go through the same checks as the headers of `Blockchain::add_block`
+// and the main chain is the branch with the most cumulative work, so a transaction proven
+// against a header of the main chain is as final as on a full node, without checking its funds
+// The tree is always locked after the headers
+#[derive(Debug, Clone)]
+pub struct HeaderChain {
+    pub consensus: SyncedConsensus,
+    pub retarget_interval: u64,
+    pub target_block_time_ms: u64,
+    headers: Arc<Mutex<Vec<BlockHeader>>>,
+    tree: Arc<Mutex<HeaderTree>>,
+}
+
+impl HeaderChain {
+    // Creates a chain of headers starting with the same genesis as `Blockchain::new`
+    // Only the parameters that decide the headers are used, the rewards are not checked
+    pub fn new(consensus: SyncedConsensus, params: &ChainParams) -> HeaderChain {
+        let genesis = create_genesis_block(params.initial_target).header;
+
+        // the genesis header is the root of the tree and carries no work
+        let mut tree = HeaderTree::new();
+        tree.insert(
+            genesis.hash.clone(),
+            HeaderNode {
+                header: genesis.clone(),
+                cumulative_work: Work::default(),
+            },
+        );
+
+        HeaderChain {
+            consensus,
+            retarget_interval: params.retarget_interval,
+            target_block_time_ms: params.target_block_time_ms,
+            headers: Arc::new(Mutex::new(vec![genesis])),
+            tree: Arc::new(Mutex::new(tree)),
+        }
+    }
+
+    // Returns a copy of the last header of the main chain
+    pub fn get_last_header(&self) -> BlockHeader {
+        let headers = self.headers.lock().unwrap();
+
+        headers[headers.len() - 1].clone()
+    }
+
+    pub fn get_genesis_hash(&self) -> BlockHash {
+        self.headers.lock().unwrap()[0].hash.clone()
+    }
+
+    // Same locator as `Blockchain::get_locator`, over the headers of the main chain
+    pub fn get_locator(&self) -> Vec<BlockHash> {
+        let headers = self.headers.lock().unwrap();
+
+        build_locator(headers.len() - 1, |index| headers[index].hash.clone())
+    }
+
+    // Returns how many headers of the main chain are at or above the given one,
+    // 1 for the tip, or `None` if the header is not on the main chain
+    pub fn get_confirmations(&self, hash: &BlockHash) -> Option<u64> {
+        let headers = self.headers.lock().unwrap();
+        let tree = self.tree.lock().unwrap();
+
+        let index = tree.get(hash)?.header.index as usize;
+        if headers.get(index)?.hash != *hash {
+            return None;
+        }
+
+        Some((headers.len() - index) as u64)
+    }
+
+    // Validates a header and adds it to the tree,
+    // switching the main chain to its branch if that branch has more work
+    pub fn add_header(&self, header: BlockHeader) -> Result<HeaderStatus, BlockchainError> {
+        let mut headers = self.headers.lock().unwrap();
+        let mut tree = self.tree.lock().unwrap();
+
+        if tree.contains_key(&header.hash) {
+            return Err(BlockchainError::KnownBlock);
+        }
+
+        // check that the previous header is known, it does not have to be the tip
+        let previous_hash = header
+            .previous_hash
+            .clone()
+            .ok_or(BlockchainError::WrongPreviousHash)?;
+        let parent = tree
+            .get(&previous_hash)
+            .ok_or(BlockchainError::UnknownParent)?;
+
+        let expected_target = next_target(
+            self.retarget_interval,
+            self.target_block_time_ms,
+            &parent.header,
+            |hash| tree.get(hash).map(|node| &node.header),
+        );
+        let work = check_header(
+            &header,
+            &parent.header,
+            expected_target,
+            self.consensus.as_ref(),
+        )?;
+
+        let cumulative_work = parent.cumulative_work.saturating_add(work);
+        let tip = &headers[headers.len() - 1];
+        let tip_work = tree[&tip.hash].cumulative_work;
+
+        let status = if previous_hash == tip.hash {
+            headers.push(header.clone());
+            HeaderStatus::Extended
+        } else if cumulative_work <= tip_work {
+            HeaderStatus::SideBranch
+        } else {
+            // walk back from the header until reaching the main chain
+            let mut branch = vec![header.clone()];
+            let mut hash = previous_hash;
+            while headers.get(tree[&hash].header.index as usize).map(|header| &header.hash) != Some(&hash) {
+                let node = &tree[&hash];
+                branch.push(node.header.clone());
+                // the genesis header is on the main chain, so every header here has a previous one
+                hash = node.header.previous_hash.clone().unwrap();
+            }
+            branch.reverse();
+
+            headers.truncate(tree[&hash].header.index as usize + 1);
+            headers.extend(branch);
+            HeaderStatus::Reorganized
+        };
+
+        tree.insert(
+            header.hash.clone(),
+            HeaderNode {
+                header,
+                cumulative_work,
+            },
+        );
+
+        Ok(status)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::blockchain::Blockchain;
+    use crate::types::world_state::Balances;
+    use crate::util::test_helpers::{chain_params, mine_block_on, proof_of_work, EASIEST_TARGET};
+
+    const BLOCK_REWARD: u64 = 50;
+
+    fn create_header(parent: &BlockHeader, miner: &str) -> BlockHeader {
+        mine_block_on(parent, miner, BLOCK_REWARD, vec![]).header
+    }
+
+    #[test]
+    fn headers_follow_the_chain_of_a_full_node() {
+        let blockchain = Blockchain::new(proof_of_work(), chain_params(EASIEST_TARGET, BLOCK_REWARD), Balances::new());
+        let header_chain = HeaderChain::new(proof_of_work(), &chain_params(EASIEST_TARGET, BLOCK_REWARD));
+        assert_eq!(header_chain.get_genesis_hash(), blockchain.get_genesis_hash());
+
+        let genesis = header_chain.get_last_header();
+        let first = create_header(&genesis, "miner");
+        let second = create_header(&first, "miner");
+        assert_eq!(header_chain.add_header(first.clone()).unwrap(), HeaderStatus::Extended);
+        assert_eq!(header_chain.add_header(second.clone()).unwrap(), HeaderStatus::Extended);
+        assert!(matches!(
+            header_chain.add_header(second.clone()),
+            Err(BlockchainError::KnownBlock)
+        ));
+
+        assert_eq!(header_chain.get_last_header().hash, second.hash);
+        assert_eq!(header_chain.get_confirmations(&first.hash), Some(2));
+        assert_eq!(header_chain.get_confirmations(&second.hash), Some(1));
+        assert_eq!(
+            header_chain.get_locator(),
+            vec![second.hash.clone(), first.hash.clone(), genesis.hash]
+        );
+    }
+
+    #[test]
+    fn invalid_headers_are_rejected() {
+        let header_chain = HeaderChain::new(proof_of_work(), &chain_params(EASIEST_TARGET, BLOCK_REWARD));
+        let genesis = header_chain.get_last_header();
+
+        let mut orphan = create_header(&genesis, "miner");
+        orphan.previous_hash = Some(orphan.hash.clone());
+        assert!(matches!(
+            header_chain.add_header(orphan),
+            Err(BlockchainError::UnknownParent)
+        ));
+
+        let mut tampered = create_header(&genesis, "miner");
+        tampered.nonce += 1;
+        assert!(matches!(
+            header_chain.add_header(tampered),
+            Err(BlockchainError::HashMismatch)
+        ));
+
+        let mut wrong_target = create_header(&genesis, "miner");
+        wrong_target.target = 0x2000_ffff;
+        wrong_target.hash = wrong_target.calculate_hash();
+        assert!(matches!(
+            header_chain.add_header(wrong_target),
+            Err(BlockchainError::WrongTarget { .. })
+        ));
+    }
+
+    #[test]
+    fn branch_with_more_work_becomes_the_main_chain() {
+        let header_chain = HeaderChain::new(proof_of_work(), &chain_params(EASIEST_TARGET, BLOCK_REWARD));
+        let genesis = header_chain.get_last_header();
+
+        let main = create_header(&genesis, "alice");
+        header_chain.add_header(main.clone()).unwrap();
+
+        let fork = create_header(&genesis, "bob");
+        assert_eq!(header_chain.add_header(fork.clone()).unwrap(), HeaderStatus::SideBranch);
+        assert_eq!(header_chain.get_confirmations(&fork.hash), None);
+
+        let fork_tip = create_header(&fork, "bob");
+        assert_eq!(header_chain.add_header(fork_tip.clone()).unwrap(), HeaderStatus::Reorganized);
+        assert_eq!(header_chain.get_last_header().hash, fork_tip.hash);
+        assert_eq!(header_chain.get_confirmations(&fork.hash), Some(2));
+        assert_eq!(header_chain.get_confirmations(&main.hash), None);
+    }
+}
@@ -0,0 +1,202 @@
+use crate::types::block::BlockHeader;
+use crate::types::hash::Hash256;
+use crate::types::transaction::Transaction;
+use serde::{Deserialize, Serialize};
+
+// Prefixes that keep a leaf from ever hashing like an inner node
+const LEAF_PREFIX: u8 = 0;
+const NODE_PREFIX: u8 = 1;
+
+// Which side of the path a sibling hash is on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Side {
+    Left,
+    Right,
+}
+
+// Sibling of a node on the path from a leaf up to the root
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProofStep {
+    pub side: Side,
+    pub hash: Hash256,
+}
+
+// Proof that a transaction is included in a block, checked without the other transactions:
+// hashing the transaction up the path of siblings must give the transactions root of the header
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InclusionProof {
+    pub transaction: Transaction,
+    pub header: BlockHeader,
+    pub path: Vec<ProofStep>,
+}
+
+// Root of the Merkle tree over the encoded transactions, in block order
+// A node without a sibling moves up to the next level unchanged, and no transactions give a zero root
+pub fn transactions_root(transactions: &[Transaction]) -> Hash256 {
+    let mut level = hash_leaves(transactions);
+    if level.is_empty() {
+        return Hash256::default();
+    }
+
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+
+    level.remove(0)
+}
+
+// Siblings on the path from the transaction at the given position up to the root,
+// levels where the node has no sibling add no step
+pub fn build_path(transactions: &[Transaction], mut position: usize) -> Vec<ProofStep> {
+    let mut level = hash_leaves(transactions);
+
+    let mut path = Vec::new();
+    while level.len() > 1 {
+        let sibling = position ^ 1;
+        if let Some(hash) = level.get(sibling) {
+            let side = if sibling < position {
+                Side::Left
+            } else {
+                Side::Right
+            };
+            path.push(ProofStep {
+                side,
+                hash: hash.clone(),
+            });
+        }
+        level = next_level(&level);
+        position /= 2;
+    }
+
+    path
+}
+
+// Checks that the proof leads from the transaction to the transactions root of a header
+// that matches its own hash
+// Whether that block is on the main chain is up to the caller, for example by following the headers
+pub fn verify_proof(proof: &InclusionProof) -> bool {
+    if proof.header.hash != proof.header.calculate_hash() {
+        return false;
+    }
+
+    let root = proof
+        .path
+        .iter()
+        .fold(hash_leaf(&proof.transaction), |hash, step| match step.side {
+            Side::Left => hash_node(&step.hash, &hash),
+            Side::Right => hash_node(&hash, &step.hash),
+        });
+
+    root == proof.header.transactions_root
+}
+
+fn hash_leaves(transactions: &[Transaction]) -> Vec<Hash256> {
+    transactions.iter().map(hash_leaf).collect()
+}
+
+fn next_level(level: &[Hash256]) -> Vec<Hash256> {
+    level
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => hash_node(left, right),
+            [single] => single.clone(),
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+// Leaves cover the whole transaction, so its public key and signature are committed to as well
+fn hash_leaf(transaction: &Transaction) -> Hash256 {
+    let mut data = vec![LEAF_PREFIX];
+    data.extend(transaction.encode());
+    Hash256::digest(&data)
+}
+
+fn hash_node(left: &Hash256, right: &Hash256) -> Hash256 {
+    let mut data = Vec::with_capacity(1 + 64);
+    data.push(NODE_PREFIX);
+    data.extend_from_slice(&left.0);
+    data.extend_from_slice(&right.0);
+    Hash256::digest(&data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::Block;
+
+    fn create_transactions(count: u64) -> Vec<Transaction> {
+        (0..count)
+            .map(|nonce| Transaction::new("alice".to_string(), "bob".to_string(), 1, 0, nonce))
+            .collect()
+    }
+
+    #[test]
+    fn root_commits_to_every_transaction_and_their_order() {
+        assert_eq!(transactions_root(&[]), Hash256::default());
+
+        let transactions = create_transactions(3);
+        let root = transactions_root(&transactions);
+        // the last transaction has no sibling and moves up unchanged
+        let left = hash_node(&hash_leaf(&transactions[0]), &hash_leaf(&transactions[1]));
+        assert_eq!(root, hash_node(&left, &hash_leaf(&transactions[2])));
+
+        let mut reordered = transactions.clone();
+        reordered.swap(0, 1);
+        assert_ne!(transactions_root(&reordered), root);
+        assert_ne!(transactions_root(&transactions[..2]), root);
+
+        // the signature is committed to, not only the signed fields
+        let mut signed = transactions.clone();
+        signed[0].signature = "00".to_string();
+        assert_eq!(signed[0].hash(), transactions[0].hash());
+        assert_ne!(transactions_root(&signed), root);
+    }
+
+    fn create_proof(transactions: &[Transaction], position: usize) -> InclusionProof {
+        let block = Block::new(1, 0x20ff_ffff, 0, None, transactions.to_vec());
+        InclusionProof {
+            transaction: transactions[position].clone(),
+            header: block.header,
+            path: build_path(transactions, position),
+        }
+    }
+
+    #[test]
+    fn every_transaction_has_a_valid_proof() {
+        for count in 1..=9 {
+            let transactions = create_transactions(count);
+            for position in 0..count as usize {
+                assert!(verify_proof(&create_proof(&transactions, position)));
+            }
+        }
+    }
+
+    #[test]
+    fn proof_for_another_transaction_or_block_is_rejected() {
+        let transactions = create_transactions(5);
+
+        let mut proof = create_proof(&transactions, 1);
+        proof.transaction = transactions[2].clone();
+        assert!(!verify_proof(&proof));
+
+        let mut proof = create_proof(&transactions, 1);
+        proof.transaction.public_key = "00".to_string();
+        assert!(!verify_proof(&proof));
+
+        let mut proof = create_proof(&transactions, 1);
+        proof.path[0].side = Side::Right;
+        assert!(!verify_proof(&proof));
+
+        // the root must belong to a header that matches its hash
+        let mut proof = create_proof(&transactions, 1);
+        proof.header.index += 1;
+        assert!(!verify_proof(&proof));
+
+        let other_block = create_proof(&create_transactions(4), 1);
+        let mut proof = create_proof(&transactions, 1);
+        proof.header = other_block.header;
+        assert!(!verify_proof(&proof));
+    }
+}
@@ -1,4 +1,9 @@
 pub(crate) mod block;
 pub(crate) mod blockchain;
+pub(crate) mod hash;
+pub(crate) mod header_chain;
+pub(crate) mod merkle;
+pub(crate) mod target;
 pub(crate) mod transaction;
 pub(crate) mod transaction_pool;
+pub(crate) mod world_state;
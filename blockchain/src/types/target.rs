@@ -0,0 +1,267 @@
+use crate::types::block::BlockHash;
+
+// Compact encoding of a 256-bit target, like `nBits` in Bitcoin but without a sign bit:
+// the high byte is the length of the target in bytes and the 3 low bytes are its most significant bytes
+pub type CompactTarget = u32;
+
+// Largest number a block hash must not exceed, stored big endian like the hash,
+// so that comparing the bytes compares the numbers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Target([u8; 32]);
+
+// Easiest possible target, the target of difficulty 1
+pub const MAX_TARGET: Target = Target([0xff; 32]);
+
+// Expected number of hashes behind a block or a chain, a 256-bit number saturating at its maximum
+// The limbs are most significant first, so the derived ordering compares the numbers
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Work([u64; 4]);
+
+const SIZE: usize = 32;
+const MANTISSA_SIZE: usize = 3;
+
+impl Target {
+    // Decodes a compact target, `None` if it does not fit in 256 bits
+    pub fn from_compact(compact: CompactTarget) -> Option<Target> {
+        let size = (compact >> 24) as usize;
+        if size > SIZE {
+            return None;
+        }
+
+        // bytes of the mantissa that fall after the last byte of the target are dropped
+        let mantissa = compact.to_be_bytes();
+        let mut bytes = [0; SIZE];
+        for (offset, byte) in mantissa[1..].iter().enumerate() {
+            if let Some(target_byte) = bytes.get_mut(SIZE - size + offset) {
+                *target_byte = *byte;
+            }
+        }
+
+        Some(Target(bytes))
+    }
+
+    // Encodes the target, keeping only its 3 most significant bytes
+    pub fn to_compact(self) -> CompactTarget {
+        let size = SIZE - self.0.iter().take_while(|byte| **byte == 0).count();
+
+        let mut mantissa = [0; 4];
+        for offset in 0..MANTISSA_SIZE {
+            if let Some(byte) = self.0.get(SIZE - size + offset) {
+                mantissa[1 + offset] = *byte;
+            }
+        }
+
+        ((size as u32) << 24) | u32::from_be_bytes(mantissa)
+    }
+
+    // Target at which finding a block takes `difficulty` times more hashes than at the easiest one
+    pub fn from_difficulty(difficulty: u64) -> Target {
+        MAX_TARGET.scale(1, difficulty)
+    }
+
+    pub fn is_met_by(&self, hash: &BlockHash) -> bool {
+        hash.0 <= self.0
+    }
+
+    // Multiplies the target by `numerator / denominator`, saturating at the easiest target
+    pub fn scale(&self, numerator: u64, denominator: u64) -> Target {
+        let denominator = denominator.max(1) as u128;
+
+        // multiply into five 64-bit limbs, most significant first
+        let mut product = [0u64; 5];
+        let mut carry = 0u128;
+        for (position, limb) in self.limbs().iter().enumerate().rev() {
+            let value = *limb as u128 * numerator as u128 + carry;
+            product[position + 1] = value as u64;
+            carry = value >> 64;
+        }
+        product[0] = carry as u64;
+
+        // then divide limb by limb, carrying the remainder down
+        let mut quotient = [0u64; 5];
+        let mut remainder = 0u128;
+        for (position, limb) in product.iter().enumerate() {
+            let value = (remainder << 64) | *limb as u128;
+            quotient[position] = (value / denominator) as u64;
+            remainder = value % denominator;
+        }
+
+        if quotient[0] != 0 {
+            return MAX_TARGET;
+        }
+        let mut bytes = [0; SIZE];
+        for (chunk, limb) in bytes.chunks_mut(8).zip(&quotient[1..]) {
+            chunk.copy_from_slice(&limb.to_be_bytes());
+        }
+        Target(bytes)
+    }
+
+    // Expected number of hashes needed to find a block, 2^256 / (target + 1),
+    // computed as !target / (target + 1) + 1 so that every number fits in 256 bits
+    pub fn work(&self) -> Work {
+        let target = self.limbs();
+        let divisor = match add_one(target) {
+            Some(divisor) => divisor,
+            // only the easiest target overflows, and every hash meets it
+            None => return Work::from(1),
+        };
+
+        Work(divide(target.map(|limb| !limb), divisor)).saturating_add(Work::from(1))
+    }
+
+    fn limbs(&self) -> [u64; 4] {
+        let mut limbs = [0; 4];
+        for (limb, chunk) in limbs.iter_mut().zip(self.0.chunks(8)) {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(chunk);
+            *limb = u64::from_be_bytes(bytes);
+        }
+        limbs
+    }
+}
+
+impl Work {
+    pub fn saturating_add(self, other: Work) -> Work {
+        let mut sum = [0; 4];
+        let mut carry = false;
+        for position in (0..4).rev() {
+            let (value, overflow) = self.0[position].overflowing_add(other.0[position]);
+            let (value, carry_overflow) = value.overflowing_add(carry as u64);
+            sum[position] = value;
+            carry = overflow || carry_overflow;
+        }
+
+        if carry {
+            Work([u64::MAX; 4])
+        } else {
+            Work(sum)
+        }
+    }
+}
+
+impl From<u64> for Work {
+    fn from(value: u64) -> Work {
+        Work([0, 0, 0, value])
+    }
+}
+
+// Adds one to a 256-bit number, `None` if it overflows
+fn add_one(mut number: [u64; 4]) -> Option<[u64; 4]> {
+    for limb in number.iter_mut().rev() {
+        let (value, overflow) = limb.overflowing_add(1);
+        *limb = value;
+        if !overflow {
+            return Some(number);
+        }
+    }
+    None
+}
+
+// Divides two 256-bit numbers by long division, one bit of the quotient at a time
+fn divide(dividend: [u64; 4], divisor: [u64; 4]) -> [u64; 4] {
+    let mut quotient = [0; 4];
+    let mut remainder = [0u64; 4];
+    for bit in 0..256 {
+        let (position, shift) = (bit / 64, 63 - bit % 64);
+
+        // shift the next bit of the dividend into the remainder, which can carry out of
+        // the 256 bits when the divisor is above 2^255
+        let mut carry = (dividend[position] >> shift) & 1;
+        for limb in remainder.iter_mut().rev() {
+            let next_carry = *limb >> 63;
+            *limb = (*limb << 1) | carry;
+            carry = next_carry;
+        }
+
+        if carry == 1 || remainder >= divisor {
+            let mut borrow = false;
+            for (limb, subtrahend) in remainder.iter_mut().zip(divisor).rev() {
+                let (value, overflow) = limb.overflowing_sub(subtrahend);
+                let (value, borrow_overflow) = value.overflowing_sub(borrow as u64);
+                *limb = value;
+                borrow = overflow || borrow_overflow;
+            }
+            quotient[position] |= 1 << shift;
+        }
+    }
+    quotient
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::hash::Hash256;
+
+    fn target_from_u64(value: u64) -> Target {
+        let mut bytes = [0; SIZE];
+        bytes[SIZE - 8..].copy_from_slice(&value.to_be_bytes());
+        Target(bytes)
+    }
+
+    #[test]
+    fn compact_encoding_keeps_the_most_significant_bytes() {
+        assert_eq!(Target::from_compact(0x0312_3456), Some(target_from_u64(0x12_3456)));
+        assert_eq!(Target::from_compact(0x0512_3456), Some(target_from_u64(0x12_3456_0000)));
+        // a short target drops the low bytes of the mantissa
+        assert_eq!(Target::from_compact(0x0212_3456), Some(target_from_u64(0x1234)));
+        assert_eq!(Target::from_compact(0x2112_3456), None);
+
+        assert_eq!(target_from_u64(0x12_3456_789a).to_compact(), 0x0512_3456);
+        assert_eq!(target_from_u64(0x12).to_compact(), 0x0112_0000);
+        assert_eq!(MAX_TARGET.to_compact(), 0x20ff_ffff);
+
+        let target = Target::from_difficulty(1_000);
+        let compact = target.to_compact();
+        assert_eq!(Target::from_compact(compact).unwrap().to_compact(), compact);
+    }
+
+    #[test]
+    fn hash_meets_the_target_up_to_its_value() {
+        let target = Target::from_compact(0x2000_ffff).unwrap();
+
+        let mut hash = Hash256([0; 32]);
+        hash.0[1] = 0xff;
+        hash.0[2] = 0xff;
+        assert!(target.is_met_by(&hash));
+
+        hash.0[3] = 1;
+        assert!(!target.is_met_by(&hash));
+    }
+
+    #[test]
+    fn scaling_is_proportional_and_saturates() {
+        let target = target_from_u64(1_000);
+        assert_eq!(target.scale(5, 4), target_from_u64(1_250));
+        assert_eq!(target.scale(1, 4), target_from_u64(250));
+        assert_eq!(MAX_TARGET.scale(4, 1), MAX_TARGET);
+        // carries go across limbs
+        assert_eq!(target_from_u64(u64::MAX).scale(2, 2), target_from_u64(u64::MAX));
+    }
+
+    #[test]
+    fn harder_targets_need_more_work() {
+        assert_eq!(MAX_TARGET.work(), Work::from(1));
+        let work = Target::from_difficulty(1 << 20).work();
+        assert!(work > Work::from(1 << 19) && work <= Work::from(1 << 20));
+        assert!(Target::from_difficulty(1 << 21).work() > work);
+
+        // a target of 2^k - 1 takes 2^(256 - k) hashes
+        assert_eq!(target_from_u64(u64::MAX).work(), Work([1, 0, 0, 0]));
+        assert_eq!(Target([0; 32]).work(), Work([u64::MAX; 4]));
+    }
+
+    #[test]
+    fn targets_below_2_pow_128_are_told_apart() {
+        let easier = target_from_u64(2_000);
+        let harder = target_from_u64(1_000);
+        assert!(harder.work() > easier.work());
+        assert!(harder.work() < Target([0; 32]).work());
+    }
+
+    #[test]
+    fn work_saturates_when_added() {
+        let most = Work([u64::MAX; 4]);
+        assert_eq!(most.saturating_add(Work::from(1)), most);
+        assert_eq!(Work([0, 0, 0, u64::MAX]).saturating_add(Work::from(1)), Work([0, 0, 1, 0]));
+    }
+}
@@ -1,8 +1,266 @@
+use ed25519_dalek::{Signature as Ed25519Signature, Signer, SigningKey, Verifier, VerifyingKey};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+// Hex encoded first 20 bytes of the SHA-256 hash of a public key
+pub type Address = String;
+
+// Hex encoded Ed25519 public key
+pub type PublicKey = String;
+
+// Hex encoded Ed25519 signature
+pub type Signature = String;
+
+// Hex encoded SHA-256 hash of the signing payload
+pub type TransactionHash = String;
+
+const ADDRESS_LENGTH: usize = 20;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum TransactionError {
+    #[error("transaction is not signed")]
+    MissingSignature,
+    #[error("malformed public key")]
+    MalformedPublicKey,
+    #[error("malformed signature")]
+    MalformedSignature,
+    #[error("sender `{0}` is not the address of the public key")]
+    SenderMismatch(Address),
+    #[error("signature does not match the transaction")]
+    BadSignature,
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Transaction {
-    pub sender: String,
-    pub recipient: String,
+    pub sender: Address,
+    pub recipient: Address,
     pub amount: u64,
+    // Paid by the sender on top of the amount and collected by the miner of the block
+    pub fee: u64,
+    // Position of the transaction among all the transactions of the sender, starting at 0
+    pub nonce: u64,
+    // Number a miner changes in its coinbase to get new block hashes once every nonce was tried,
+    // 0 in other transactions
+    #[serde(default)]
+    pub extra_nonce: u64,
+    #[serde(default)]
+    pub public_key: PublicKey,
+    #[serde(default)]
+    pub signature: Signature,
+}
+
+// Derives the address that owns the funds of a public key
+pub fn address_from_public_key(public_key: &VerifyingKey) -> Address {
+    let hash = Sha256::digest(public_key.as_bytes());
+    hex::encode(&hash[..ADDRESS_LENGTH])
+}
+
+impl Transaction {
+    // Creates an unsigned transaction
+    pub fn new(
+        sender: Address,
+        recipient: Address,
+        amount: u64,
+        fee: u64,
+        nonce: u64,
+    ) -> Transaction {
+        Transaction {
+            sender,
+            recipient,
+            amount,
+            fee,
+            nonce,
+            extra_nonce: 0,
+            public_key: PublicKey::default(),
+            signature: Signature::default(),
+        }
+    }
+
+    // Creates the transaction that mints the block reward for the miner
+    // The block index is used as nonce so that every coinbase is unique
+    pub fn new_coinbase(recipient: Address, amount: u64, block_index: u64) -> Transaction {
+        Transaction::new(Address::default(), recipient, amount, 0, block_index)
+    }
+
+    // Coinbase transactions are the only ones without a sender
+    pub fn is_coinbase(&self) -> bool {
+        self.sender.is_empty()
+    }
+
+    // Creates a transaction sent from the address of the key and signs it
+    pub fn new_signed(
+        signing_key: &SigningKey,
+        recipient: Address,
+        amount: u64,
+        fee: u64,
+        nonce: u64,
+    ) -> Transaction {
+        let sender = address_from_public_key(&signing_key.verifying_key());
+        let mut transaction = Transaction::new(sender, recipient, amount, fee, nonce);
+        transaction.sign(signing_key);
+
+        transaction
+    }
+
+    // Canonical encoding of the fields covered by the signature
+    // Strings are length prefixed and integers are little endian, so no two payloads collide
+    pub fn signing_payload(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        for field in [&self.sender, &self.recipient] {
+            payload.extend_from_slice(&(field.len() as u64).to_le_bytes());
+            payload.extend_from_slice(field.as_bytes());
+        }
+        payload.extend_from_slice(&self.amount.to_le_bytes());
+        payload.extend_from_slice(&self.fee.to_le_bytes());
+        payload.extend_from_slice(&self.nonce.to_le_bytes());
+        payload.extend_from_slice(&self.extra_nonce.to_le_bytes());
+
+        payload
+    }
+
+    // Encoding of the whole transaction: the signing payload followed by the length prefixed
+    // public key and signature, which the transactions root of a block commits to
+    pub fn encode(&self) -> Vec<u8> {
+        let mut encoded = self.signing_payload();
+        for field in [&self.public_key, &self.signature] {
+            encoded.extend_from_slice(&(field.len() as u64).to_le_bytes());
+            encoded.extend_from_slice(field.as_bytes());
+        }
+
+        encoded
+    }
+
+    // Identifies the transaction, two transactions with the same content have the same hash
+    pub fn hash(&self) -> TransactionHash {
+        hex::encode(Sha256::digest(self.signing_payload()))
+    }
+
+    // Total amount taken from the balance of the sender, `None` if the amount and the fee overflow
+    pub fn total_cost(&self) -> Option<u64> {
+        self.amount.checked_add(self.fee)
+    }
+
+    // Number of bytes the transaction takes in a serialized block, used to rank fees
+    pub fn size(&self) -> usize {
+        serde_json::to_vec(self)
+            .map(|bytes| bytes.len())
+            .unwrap_or_default()
+    }
+
+    // Sets the public key and the signature of the transaction
+    pub fn sign(&mut self, signing_key: &SigningKey) {
+        let signature = signing_key.sign(&self.signing_payload());
+
+        self.public_key = hex::encode(signing_key.verifying_key().as_bytes());
+        self.signature = hex::encode(signature.to_bytes());
+    }
+
+    // Checks that the transaction was signed by the owner of the sender address
+    pub fn verify_signature(&self) -> Result<(), TransactionError> {
+        if self.public_key.is_empty() || self.signature.is_empty() {
+            return Err(TransactionError::MissingSignature);
+        }
+
+        let public_key = hex::decode(&self.public_key)
+            .ok()
+            .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+            .and_then(|bytes| VerifyingKey::from_bytes(&bytes).ok())
+            .ok_or(TransactionError::MalformedPublicKey)?;
+
+        let signature = hex::decode(&self.signature)
+            .ok()
+            .and_then(|bytes| Ed25519Signature::from_slice(&bytes).ok())
+            .ok_or(TransactionError::MalformedSignature)?;
+
+        if address_from_public_key(&public_key) != self.sender {
+            return Err(TransactionError::SenderMismatch(self.sender.clone()));
+        }
+
+        public_key
+            .verify(&self.signing_payload(), &signature)
+            .map_err(|_| TransactionError::BadSignature)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::test_helpers::create_signing_key;
+
+    #[test]
+    fn signed_transaction_is_valid() {
+        let transaction = Transaction::new_signed(&create_signing_key(1), "bob".to_string(), 10, 1, 0);
+
+        assert_eq!(transaction.sender.len(), ADDRESS_LENGTH * 2);
+        assert_eq!(transaction.verify_signature(), Ok(()));
+    }
+
+    #[test]
+    fn unsigned_transaction_is_rejected() {
+        let transaction = Transaction::new("alice".to_string(), "bob".to_string(), 10, 1, 0);
+
+        assert_eq!(
+            transaction.verify_signature(),
+            Err(TransactionError::MissingSignature)
+        );
+    }
+
+    #[test]
+    fn tampered_transaction_is_rejected() {
+        let mut transaction =
+            Transaction::new_signed(&create_signing_key(1), "bob".to_string(), 10, 1, 0);
+        transaction.amount = 1_000;
+
+        assert_eq!(
+            transaction.verify_signature(),
+            Err(TransactionError::BadSignature)
+        );
+    }
+
+    #[test]
+    fn spending_from_another_address_is_rejected() {
+        // a valid signature by a key that does not own the sender address
+        let mut transaction = Transaction::new("alice".to_string(), "bob".to_string(), 10, 1, 0);
+        transaction.sign(&create_signing_key(1));
+
+        assert_eq!(
+            transaction.verify_signature(),
+            Err(TransactionError::SenderMismatch("alice".to_string()))
+        );
+    }
+
+    #[test]
+    fn nonce_is_covered_by_the_signature() {
+        let mut transaction =
+            Transaction::new_signed(&create_signing_key(1), "bob".to_string(), 10, 1, 0);
+        transaction.nonce = 1;
+
+        assert_eq!(
+            transaction.verify_signature(),
+            Err(TransactionError::BadSignature)
+        );
+    }
+
+    #[test]
+    fn fee_is_covered_by_the_signature() {
+        let mut transaction =
+            Transaction::new_signed(&create_signing_key(1), "bob".to_string(), 10, 1, 0);
+        transaction.fee = 0;
+
+        assert_eq!(
+            transaction.verify_signature(),
+            Err(TransactionError::BadSignature)
+        );
+    }
+
+    #[test]
+    fn extra_nonce_is_always_hashed() {
+        let coinbase = Transaction::new_coinbase("miner".to_string(), 50, 1);
+        let mut rolled = coinbase.clone();
+        rolled.extra_nonce = 1;
+
+        assert_eq!(coinbase.signing_payload().len(), 8 + 8 + 5 + 4 * 8);
+        assert_ne!(rolled.hash(), coinbase.hash());
+    }
 }
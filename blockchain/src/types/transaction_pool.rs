@@ -1,53 +1,470 @@
-use crate::types::transaction::Transaction;
-use std::sync::{Arc, Mutex};
+use crate::types::transaction::{Address, Transaction, TransactionError, TransactionHash};
+use crate::types::world_state::{WorldState, WorldStateError};
+use chrono::prelude::*;
+use log::{debug, info, warn};
+use serde::Serialize;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+use thiserror::Error;
 
 pub type TransactionVec = Vec<Transaction>;
 
-type SyncedTransactionVec = Arc<Mutex<TransactionVec>>;
+// Read-only view of the pool, with the pending transactions of each sender in nonce order
+#[derive(Debug, Clone, Serialize)]
+pub struct PoolSnapshot {
+    pub count: usize,
+    pub total_fees: u64,
+    pub by_sender: BTreeMap<Address, TransactionVec>,
+}
+
+// A transaction waiting in the pool and the time in milliseconds when it was added
+#[derive(Debug, Clone)]
+struct PendingTransaction {
+    transaction: Transaction,
+    added_at: i64,
+}
+
+// Pending transactions of a single sender, ordered by nonce
+type SenderTransactions = BTreeMap<u64, PendingTransaction>;
+
+type SyncedTransactionMap = Arc<Mutex<HashMap<Address, SenderTransactions>>>;
+
+// Number of transactions ever added to the pool, signaled on every change
+type SyncedAddedCount = Arc<(Mutex<u64>, Condvar)>;
 
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum TransactionPoolError {
+    #[error("invalid signature: {0}")]
+    InvalidSignature(#[from] TransactionError),
+    #[error("only the coinbase can set an extra_nonce")]
+    UnexpectedExtraNonce,
+    #[error("amount and fee overflow")]
+    CostOverflow,
+    #[error("insufficient funds: {0}")]
+    InsufficientFunds(#[from] WorldStateError),
+    #[error("nonce {nonce} was already used, the next expected nonce is {expected}")]
+    StaleNonce { expected: u64, nonce: u64 },
+    #[error("transaction `{0}` is already pending")]
+    AlreadyKnown(TransactionHash),
+    #[error("a different transaction with nonce {0} is already pending")]
+    DuplicateNonce(u64),
+    #[error("the sender already has {0} pending transactions")]
+    SenderLimitReached(usize),
+    #[error("the pool is full and the fee is too low to replace a pending transaction")]
+    PoolFull,
+}
+
+// Pending transactions grouped by sender
+// Each sender's transactions are executed in nonce order, and among the transactions
+// that are next in line for their sender, the miner picks the highest fee per byte first
+// The pool holds at most `capacity` transactions, `max_per_sender` per sender,
+// and forgets transactions that waited longer than `ttl_ms`
+// Threads can wait for new transactions, the count of added transactions is always locked
+// after the transactions
 #[derive(Debug, Clone)]
 pub struct TransactionPool {
-    transactions: SyncedTransactionVec,
+    capacity: usize,
+    max_per_sender: usize,
+    ttl_ms: i64,
+    transactions: SyncedTransactionMap,
+    added: SyncedAddedCount,
+}
+
+// A pending transaction ranked by fee per byte
+#[derive(Debug)]
+struct Candidate {
+    fee: u64,
+    size: usize,
+    sender: Address,
+    nonce: u64,
 }
 
+impl Candidate {
+    fn new(transaction: &Transaction) -> Candidate {
+        Candidate {
+            fee: transaction.fee,
+            size: transaction.size(),
+            sender: transaction.sender.clone(),
+            nonce: transaction.nonce,
+        }
+    }
+}
+
+impl Ord for Candidate {
+    // Compares fee / size without rounding by cross multiplying
+    fn cmp(&self, other: &Self) -> Ordering {
+        let fee_rate = self.fee as u128 * other.size as u128;
+        let other_fee_rate = other.fee as u128 * self.size as u128;
+
+        fee_rate
+            .cmp(&other_fee_rate)
+            .then_with(|| other.sender.cmp(&self.sender))
+    }
+}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate {}
+
 impl TransactionPool {
     // Creates a empty transaction pool
-    pub fn new() -> TransactionPool {
+    pub fn new(capacity: usize, max_per_sender: usize, ttl_ms: i64) -> TransactionPool {
         TransactionPool {
-            transactions: SyncedTransactionVec::default(),
+            capacity,
+            max_per_sender,
+            ttl_ms,
+            transactions: SyncedTransactionMap::default(),
+            added: SyncedAddedCount::default(),
         }
     }
 
     // Adds a new transaction to the pool
-    pub fn add_transaction(&self, transaction: Transaction) {
+    // It must be signed by the sender, who must be able to pay for it
+    // on top of all its transactions already pending
+    // Transactions with a future nonce are accepted and held until the gap is filled
+    // When the pool is full, the transaction replaces the pending one with the lowest fee per byte
+    pub fn add_transaction(
+        &self,
+        transaction: Transaction,
+        state: &WorldState,
+    ) -> Result<(), TransactionPoolError> {
+        if transaction.extra_nonce != 0 {
+            return Err(TransactionPoolError::UnexpectedExtraNonce);
+        }
+        transaction.verify_signature()?;
+        let total_cost = transaction
+            .total_cost()
+            .ok_or(TransactionPoolError::CostOverflow)?;
+
+        let expected_nonce = state.get_nonce(&transaction.sender);
+        if transaction.nonce < expected_nonce {
+            return Err(TransactionPoolError::StaleNonce {
+                expected: expected_nonce,
+                nonce: transaction.nonce,
+            });
+        }
+
+        let now = Utc::now().timestamp_millis();
         let mut transactions = self.transactions.lock().unwrap();
-        transactions.push(transaction);
+        self.remove_expired(&mut transactions, now);
+        Self::remove_executed_by(&mut transactions, &transaction.sender, expected_nonce);
+
+        let pending_cost = match transactions.get(&transaction.sender) {
+            Some(pending) => {
+                if let Some(existing) = pending.get(&transaction.nonce) {
+                    let hash = transaction.hash();
+                    if existing.transaction.hash() == hash {
+                        return Err(TransactionPoolError::AlreadyKnown(hash));
+                    }
+                    return Err(TransactionPoolError::DuplicateNonce(transaction.nonce));
+                }
+                if pending.len() >= self.max_per_sender {
+                    return Err(TransactionPoolError::SenderLimitReached(pending.len()));
+                }
+                // pending transactions were checked for an overflowing cost when they were added
+                pending
+                    .values()
+                    .filter_map(|pending| pending.transaction.total_cost())
+                    .fold(0u64, u64::saturating_add)
+            }
+            None => 0,
+        };
+        state.check_can_spend(
+            &transaction.sender,
+            pending_cost.saturating_add(total_cost),
+        )?;
+
+        let pending_count: usize = transactions.values().map(|pending| pending.len()).sum();
+        if pending_count >= self.capacity {
+            Self::evict_for(&mut transactions, &transaction)?;
+        }
+
+        transactions
+            .entry(transaction.sender.clone())
+            .or_default()
+            .insert(
+                transaction.nonce,
+                PendingTransaction {
+                    transaction,
+                    added_at: now,
+                },
+            );
+
+        let (added, signal) = &*self.added;
+        *added.lock().unwrap() += 1;
+        signal.notify_all();
+
+        Ok(())
     }
 
-    // Returns a copy of all transactions
+    // Puts back transactions that left the main chain in a reorganization or were mined
+    // in a block that did not make it to the main chain
+    // Transactions that are no longer valid on top of the state are dropped
+    pub fn add_orphaned(&self, transactions: TransactionVec, state: &WorldState) {
+        for transaction in transactions {
+            if let Err(error) = self.add_transaction(transaction, state) {
+                debug!("dropping orphaned transaction: {}", error);
+            }
+        }
+    }
+
+    // Forgets the pending transactions whose nonce was already used on top of the state,
+    // such as the transactions of a block that was just added to the chain
+    pub fn remove_executed(&self, state: &WorldState) {
+        let mut transactions = self.transactions.lock().unwrap();
+        for (sender, pending) in transactions.iter_mut() {
+            *pending = pending.split_off(&state.get_nonce(sender));
+        }
+        transactions.retain(|_, pending| !pending.is_empty());
+    }
+
+    // Returns how many transactions were added to the pool so far
+    pub fn get_added(&self) -> u64 {
+        *self.added.0.lock().unwrap()
+    }
+
+    // Blocks until more than `seen` transactions were added to the pool or the timeout passes,
+    // returning how many transactions were added so far
+    pub fn wait_for_added(&self, seen: u64, timeout_ms: u64) -> u64 {
+        let (added, signal) = &*self.added;
+        let added = added.lock().unwrap();
+        let (added, _) = signal
+            .wait_timeout_while(added, Duration::from_millis(timeout_ms), |added| *added <= seen)
+            .unwrap();
+
+        *added
+    }
+
+    // Returns a copy of all pending transactions without removing them
+    pub fn snapshot(&self) -> PoolSnapshot {
+        let transactions = self.transactions.lock().unwrap();
+
+        let by_sender: BTreeMap<Address, TransactionVec> = transactions
+            .iter()
+            .map(|(sender, pending)| {
+                let sender_transactions = pending
+                    .values()
+                    .map(|pending| pending.transaction.clone())
+                    .collect();
+                (sender.clone(), sender_transactions)
+            })
+            .collect();
+        let all_transactions = by_sender.values().flatten();
+
+        PoolSnapshot {
+            count: all_transactions.clone().count(),
+            total_fees: all_transactions
+                .fold(0u64, |fees, transaction| fees.saturating_add(transaction.fee)),
+            by_sender,
+        }
+    }
+
+    // Removes and returns all transactions
     pub fn pop(&self) -> TransactionVec {
         let mut transactions = self.transactions.lock().unwrap();
-        let cloned_transaction = transactions.clone();
-        transactions.clear();
-        cloned_transaction
+
+        std::mem::take(&mut *transactions)
+            .into_values()
+            .flat_map(|pending| pending.into_values())
+            .map(|pending| pending.transaction)
+            .collect()
+    }
+
+    // Removes and returns the best transactions that can be executed in order on top of the state,
+    // up to a number of transactions and a total size in bytes
+    // Transactions that do not fit or that wait for a missing nonce stay in the pool,
+    // while transactions that can never execute are dropped
+    pub fn pop_best(
+        &self,
+        state: &WorldState,
+        max_transactions: usize,
+        max_size: usize,
+    ) -> TransactionVec {
+        let mut transactions = self.transactions.lock().unwrap();
+        self.remove_expired(&mut transactions, Utc::now().timestamp_millis());
+
+        select_best(&mut transactions, state, max_transactions, max_size)
+    }
+
+    // Returns the transactions `pop_best` would take, leaving the pool untouched
+    pub fn peek_best(
+        &self,
+        state: &WorldState,
+        max_transactions: usize,
+        max_size: usize,
+    ) -> TransactionVec {
+        let mut transactions = self.transactions.lock().unwrap().clone();
+        self.remove_expired(&mut transactions, Utc::now().timestamp_millis());
+
+        select_best(&mut transactions, state, max_transactions, max_size)
+    }
+
+    // Forgets the transactions that waited in the pool for longer than the time to live
+    fn remove_expired(&self, transactions: &mut HashMap<Address, SenderTransactions>, now: i64) {
+        for pending in transactions.values_mut() {
+            pending.retain(|_, pending| now - pending.added_at <= self.ttl_ms);
+        }
+        transactions.retain(|_, pending| !pending.is_empty());
+    }
+
+    // Forgets the transactions of a sender whose nonce was already used on top of the state,
+    // which are left in the pool when a block mined by someone else includes them
+    fn remove_executed_by(
+        transactions: &mut HashMap<Address, SenderTransactions>,
+        sender: &Address,
+        expected_nonce: u64,
+    ) {
+        if let Some(pending) = transactions.get_mut(sender) {
+            *pending = pending.split_off(&expected_nonce);
+            if pending.is_empty() {
+                transactions.remove(sender);
+            }
+        }
+    }
+
+    // Makes room for a new transaction by evicting the pending transaction with the lowest fee per byte,
+    // along with the later transactions of its sender that could never be mined without it
+    // Among equal fees per byte the latest nonce goes first, so fewer transactions follow it out
+    fn evict_for(
+        transactions: &mut HashMap<Address, SenderTransactions>,
+        transaction: &Transaction,
+    ) -> Result<(), TransactionPoolError> {
+        // the sender of the new transaction is left out, evicting one of its own transactions
+        // could leave a gap before the new one that could never be mined
+        let lowest = transactions
+            .iter()
+            .filter(|(sender, _)| **sender != transaction.sender)
+            .flat_map(|(_, pending)| pending.values())
+            .map(|pending| Candidate::new(&pending.transaction))
+            .min_by(|candidate, other| {
+                candidate
+                    .cmp(other)
+                    .then_with(|| other.nonce.cmp(&candidate.nonce))
+            })
+            .ok_or(TransactionPoolError::PoolFull)?;
+
+        if Candidate::new(transaction) <= lowest {
+            return Err(TransactionPoolError::PoolFull);
+        }
+
+        let pending = transactions.get_mut(&lowest.sender).unwrap();
+        let evicted = pending.split_off(&lowest.nonce);
+        if pending.is_empty() {
+            transactions.remove(&lowest.sender);
+        }
+        for evicted in evicted.values() {
+            info!("evicted transaction {} from the full pool", evicted.transaction.hash());
+        }
+
+        Ok(())
+    }
+}
+
+// Takes the best transactions out of the pending ones, see `TransactionPool::pop_best`
+fn select_best(
+    transactions: &mut HashMap<Address, SenderTransactions>,
+    state: &WorldState,
+    max_transactions: usize,
+    max_size: usize,
+) -> TransactionVec {
+    // nonces already used on chain will never be executable again
+    for (sender, pending) in transactions.iter_mut() {
+        *pending = pending.split_off(&state.get_nonce(sender));
+    }
+
+    let mut state = state.clone();
+    let mut candidates: BinaryHeap<Candidate> = transactions
+        .iter()
+        .filter_map(|(sender, pending)| pending.get(&state.get_nonce(sender)))
+        .map(|pending| Candidate::new(&pending.transaction))
+        .collect();
+
+    let mut selected = TransactionVec::new();
+    let mut selected_size = 0;
+    while let Some(candidate) = candidates.pop() {
+        if selected.len() >= max_transactions {
+            break;
+        }
+        if selected_size + candidate.size > max_size {
+            continue;
+        }
+
+        let pending = transactions.get_mut(&candidate.sender).unwrap();
+        let transaction = pending.remove(&candidate.nonce).unwrap().transaction;
+        if let Err(error) = state.apply_transaction(&transaction) {
+            warn!("dropping transaction {:?}: {}", transaction, error);
+            continue;
+        }
+
+        // the following transaction of the sender is now executable
+        if let Some(next) = pending.get(&(candidate.nonce + 1)) {
+            candidates.push(Candidate::new(&next.transaction));
+        }
+        selected_size += candidate.size;
+        selected.push(transaction);
     }
+
+    transactions.retain(|_, pending| !pending.is_empty());
+    selected
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::types::transaction::address_from_public_key;
+    use crate::types::world_state::Balances;
+    use crate::util::test_helpers::create_signing_key;
 
-    fn create_mock_transaction(amount: u64) -> Transaction {
-        Transaction {
-            sender: "alice.near".to_string(),
-            recipient: "bob.near".to_string(),
-            amount,
+    const ALICE: u8 = 1;
+    const BOB: u8 = 2;
+    const CAROL: u8 = 3;
+
+    const CAPACITY: usize = 4;
+    const MAX_PER_SENDER: usize = 3;
+    const TTL_MS: i64 = 60_000;
+
+    fn create_transaction_pool() -> TransactionPool {
+        TransactionPool::new(CAPACITY, MAX_PER_SENDER, TTL_MS)
+    }
+
+    // alice, bob and carol all start with 100 coins
+    fn create_world_state() -> WorldState {
+        let mut genesis_balances = Balances::new();
+        for seed in [ALICE, BOB, CAROL] {
+            let sender = address_from_public_key(&create_signing_key(seed).verifying_key());
+            genesis_balances.insert(sender, 100);
         }
+        WorldState::new(genesis_balances)
+    }
+
+    fn create_signed_transaction(sender: u8, amount: u64, fee: u64, nonce: u64) -> Transaction {
+        let signing_key = create_signing_key(sender);
+        Transaction::new_signed(&signing_key, "carol".to_string(), amount, fee, nonce)
+    }
+
+    fn create_mock_transaction(amount: u64, nonce: u64) -> Transaction {
+        create_signed_transaction(ALICE, amount, 0, nonce)
+    }
+
+    fn nonces_of(transactions: &TransactionVec) -> Vec<u64> {
+        transactions.iter().map(|transaction| transaction.nonce).collect()
     }
 
     #[test]
     fn transaction_pool_should_be_empty() {
-        let transaction_pool = TransactionPool::new();
+        let transaction_pool = create_transaction_pool();
 
         let transactions = transaction_pool.pop();
         assert!(transactions.is_empty());
@@ -55,11 +472,13 @@ mod tests {
 
     #[test]
     fn transaction_pool_contains_one_transaction() {
-        let transaction_pool = TransactionPool::new();
+        let transaction_pool = create_transaction_pool();
 
         // add a new transaction to the pool
-        let transaction = create_mock_transaction(1);
-        transaction_pool.add_transaction(transaction.clone());
+        let transaction = create_mock_transaction(1, 0);
+        transaction_pool
+            .add_transaction(transaction.clone(), &create_world_state())
+            .unwrap();
 
         // pop the values and check that the transaction is included
         let transactions = transaction_pool.pop();
@@ -69,13 +488,18 @@ mod tests {
 
     #[test]
     fn transaction_pool_contains_several_transaction() {
-        let transaction_pool = TransactionPool::new();
+        let transaction_pool = create_transaction_pool();
 
         // add a new transaction to the pool
-        let transaction_a = create_mock_transaction(10);
-        let transaction_b = create_mock_transaction(12);
-        transaction_pool.add_transaction(transaction_a.clone());
-        transaction_pool.add_transaction(transaction_b.clone());
+        let transaction_a = create_mock_transaction(10, 0);
+        let transaction_b = create_mock_transaction(12, 1);
+        let state = create_world_state();
+        transaction_pool
+            .add_transaction(transaction_a.clone(), &state)
+            .unwrap();
+        transaction_pool
+            .add_transaction(transaction_b.clone(), &state)
+            .unwrap();
 
         // pop the values and check that the transactions are included
         let transactions = transaction_pool.pop();
@@ -83,4 +507,416 @@ mod tests {
         assert_eq!(transactions[0].amount, transaction_a.amount);
         assert_eq!(transactions[1].amount, transaction_b.amount);
     }
+
+    #[test]
+    fn transaction_pool_rejects_overspending() {
+        let transaction_pool = create_transaction_pool();
+        let state = create_world_state();
+
+        // each transaction is funded on its own, but not both together
+        let transaction_a = create_mock_transaction(60, 0);
+        let transaction_b = create_mock_transaction(50, 1);
+        transaction_pool
+            .add_transaction(transaction_a, &state)
+            .unwrap();
+        let result = transaction_pool.add_transaction(transaction_b, &state);
+        assert!(matches!(
+            result,
+            Err(TransactionPoolError::InsufficientFunds(_))
+        ));
+
+        let transactions = transaction_pool.pop();
+        assert_eq!(transactions.len(), 1);
+        assert_eq!(transactions[0].amount, 60);
+    }
+
+    #[test]
+    fn transaction_pool_rejects_an_overflowing_cost() {
+        let transaction_pool = create_transaction_pool();
+        let state = create_world_state();
+
+        let transaction = create_signed_transaction(ALICE, u64::MAX, 1, 0);
+        let result = transaction_pool.add_transaction(transaction, &state);
+
+        assert_eq!(result, Err(TransactionPoolError::CostOverflow));
+        assert!(transaction_pool.pop().is_empty());
+    }
+
+    #[test]
+    fn transaction_pool_rejects_unsigned_transaction() {
+        let transaction_pool = create_transaction_pool();
+        let state = create_world_state();
+
+        let mut transaction = create_mock_transaction(10, 0);
+        transaction.signature.clear();
+        let result = transaction_pool.add_transaction(transaction, &state);
+
+        assert_eq!(
+            result,
+            Err(TransactionPoolError::InvalidSignature(
+                TransactionError::MissingSignature
+            ))
+        );
+        assert!(transaction_pool.pop().is_empty());
+    }
+
+    #[test]
+    fn transaction_pool_rejects_an_extra_nonce() {
+        let transaction_pool = create_transaction_pool();
+        let state = create_world_state();
+
+        // the signature covers the extra_nonce, only the coinbase may set it
+        let mut transaction = create_mock_transaction(10, 0);
+        transaction.extra_nonce = 1;
+        transaction.sign(&create_signing_key(ALICE));
+        let result = transaction_pool.add_transaction(transaction, &state);
+
+        assert_eq!(result, Err(TransactionPoolError::UnexpectedExtraNonce));
+        assert!(transaction_pool.pop().is_empty());
+    }
+
+    #[test]
+    fn transaction_pool_rejects_used_and_duplicate_nonces() {
+        let transaction_pool = create_transaction_pool();
+        let mut state = create_world_state();
+        state
+            .apply_transaction(&create_mock_transaction(1, 0))
+            .unwrap();
+
+        // nonce 0 was already mined
+        let result = transaction_pool.add_transaction(create_mock_transaction(1, 0), &state);
+        assert_eq!(
+            result,
+            Err(TransactionPoolError::StaleNonce {
+                expected: 1,
+                nonce: 0
+            })
+        );
+
+        // nonce 1 can only be pending once
+        transaction_pool
+            .add_transaction(create_mock_transaction(1, 1), &state)
+            .unwrap();
+        let result = transaction_pool.add_transaction(create_mock_transaction(1, 1), &state);
+        assert!(matches!(result, Err(TransactionPoolError::AlreadyKnown(_))));
+        let result = transaction_pool.add_transaction(create_mock_transaction(2, 1), &state);
+        assert_eq!(result, Err(TransactionPoolError::DuplicateNonce(1)));
+    }
+
+    #[test]
+    fn transaction_pool_holds_future_nonces() {
+        let transaction_pool = create_transaction_pool();
+        let state = create_world_state();
+
+        // nonce 2 arrives before nonce 1, so only nonce 0 can be executed
+        transaction_pool
+            .add_transaction(create_mock_transaction(1, 2), &state)
+            .unwrap();
+        transaction_pool
+            .add_transaction(create_mock_transaction(1, 0), &state)
+            .unwrap();
+        let transactions = transaction_pool.pop_best(&state, usize::MAX, usize::MAX);
+        assert_eq!(nonces_of(&transactions), vec![0]);
+
+        // once nonce 1 arrives, nonces 1 and 2 are executable in order
+        let mut state = state;
+        state.apply_transaction(&transactions[0]).unwrap();
+        transaction_pool
+            .add_transaction(create_mock_transaction(1, 1), &state)
+            .unwrap();
+        let transactions = transaction_pool.pop_best(&state, usize::MAX, usize::MAX);
+        assert_eq!(nonces_of(&transactions), vec![1, 2]);
+        assert!(transaction_pool.pop().is_empty());
+    }
+
+    #[test]
+    fn transaction_pool_pops_highest_fee_first() {
+        let transaction_pool = create_transaction_pool();
+        let state = create_world_state();
+
+        transaction_pool
+            .add_transaction(create_signed_transaction(ALICE, 1, 1, 0), &state)
+            .unwrap();
+        transaction_pool
+            .add_transaction(create_signed_transaction(BOB, 1, 5, 0), &state)
+            .unwrap();
+
+        let transactions = transaction_pool.pop_best(&state, usize::MAX, usize::MAX);
+        let fees: Vec<u64> = transactions.iter().map(|transaction| transaction.fee).collect();
+        assert_eq!(fees, vec![5, 1]);
+    }
+
+    #[test]
+    fn transaction_pool_peeks_without_removing() {
+        let transaction_pool = create_transaction_pool();
+        let state = create_world_state();
+
+        transaction_pool
+            .add_transaction(create_signed_transaction(ALICE, 1, 1, 0), &state)
+            .unwrap();
+        transaction_pool
+            .add_transaction(create_signed_transaction(BOB, 1, 5, 0), &state)
+            .unwrap();
+
+        let peeked = transaction_pool.peek_best(&state, usize::MAX, usize::MAX);
+        assert_eq!(transaction_pool.snapshot().count, 2);
+
+        let popped = transaction_pool.pop_best(&state, usize::MAX, usize::MAX);
+        let hashes = |transactions: &TransactionVec| -> Vec<TransactionHash> {
+            transactions.iter().map(|transaction| transaction.hash()).collect()
+        };
+        assert_eq!(hashes(&peeked), hashes(&popped));
+        assert_eq!(transaction_pool.snapshot().count, 0);
+    }
+
+    #[test]
+    fn waiting_threads_wake_up_on_new_transactions() {
+        let transaction_pool = create_transaction_pool();
+        let state = create_world_state();
+        assert_eq!(transaction_pool.wait_for_added(0, 10), 0);
+
+        let pool = transaction_pool.clone();
+        let waiter = std::thread::spawn(move || pool.wait_for_added(0, 60_000));
+        transaction_pool
+            .add_transaction(create_signed_transaction(ALICE, 1, 1, 0), &state)
+            .unwrap();
+        assert_eq!(waiter.join().unwrap(), 1);
+
+        // rejected transactions wake nobody up
+        let _ = transaction_pool.add_transaction(create_signed_transaction(ALICE, 1, 1, 0), &state);
+        assert_eq!(transaction_pool.wait_for_added(1, 10), 1);
+    }
+
+    #[test]
+    fn transaction_pool_keeps_nonce_order_of_a_sender() {
+        let transaction_pool = create_transaction_pool();
+        let state = create_world_state();
+
+        // the high fee of nonce 1 can not make it go before nonce 0
+        transaction_pool
+            .add_transaction(create_signed_transaction(ALICE, 1, 1, 0), &state)
+            .unwrap();
+        transaction_pool
+            .add_transaction(create_signed_transaction(ALICE, 1, 9, 1), &state)
+            .unwrap();
+        transaction_pool
+            .add_transaction(create_signed_transaction(BOB, 1, 5, 0), &state)
+            .unwrap();
+
+        let transactions = transaction_pool.pop_best(&state, usize::MAX, usize::MAX);
+        let fees: Vec<u64> = transactions.iter().map(|transaction| transaction.fee).collect();
+        assert_eq!(fees, vec![5, 1, 9]);
+    }
+
+    #[test]
+    fn transaction_pool_leaves_what_does_not_fit() {
+        let transaction_pool = create_transaction_pool();
+        let state = create_world_state();
+
+        let transaction_a = create_signed_transaction(ALICE, 1, 1, 0);
+        let transaction_b = create_signed_transaction(BOB, 1, 5, 0);
+        let size = transaction_a.size();
+        transaction_pool.add_transaction(transaction_a, &state).unwrap();
+        transaction_pool.add_transaction(transaction_b, &state).unwrap();
+
+        // only one transaction fits in the block
+        let transactions = transaction_pool.pop_best(&state, 1, usize::MAX);
+        assert_eq!(transactions.len(), 1);
+        assert_eq!(transactions[0].fee, 5);
+
+        // the remaining one is still pending, and must fit in the size limit
+        assert!(transaction_pool.pop_best(&state, 1, size - 1).is_empty());
+        let transactions = transaction_pool.pop_best(&state, 1, size);
+        assert_eq!(transactions.len(), 1);
+        assert_eq!(transactions[0].fee, 1);
+    }
+
+    #[test]
+    fn transaction_pool_limits_transactions_per_sender() {
+        let transaction_pool = create_transaction_pool();
+        let state = create_world_state();
+
+        for nonce in 0..MAX_PER_SENDER as u64 {
+            transaction_pool
+                .add_transaction(create_mock_transaction(1, nonce), &state)
+                .unwrap();
+        }
+        let result = transaction_pool.add_transaction(create_mock_transaction(1, 3), &state);
+
+        assert_eq!(
+            result,
+            Err(TransactionPoolError::SenderLimitReached(MAX_PER_SENDER))
+        );
+    }
+
+    #[test]
+    fn transactions_included_in_a_block_do_not_count_against_the_sender() {
+        let transaction_pool = create_transaction_pool();
+        let mut state = create_world_state();
+
+        let pooled: TransactionVec = (0..MAX_PER_SENDER as u64)
+            .map(|nonce| create_mock_transaction(30, nonce))
+            .collect();
+        for transaction in &pooled {
+            transaction_pool
+                .add_transaction(transaction.clone(), &state)
+                .unwrap();
+        }
+
+        // a block from a peer includes the pooled transactions, leaving alice 10 coins
+        for transaction in &pooled {
+            state.apply_transaction(transaction).unwrap();
+        }
+        transaction_pool
+            .add_transaction(create_mock_transaction(5, 3), &state)
+            .unwrap();
+
+        let snapshot = transaction_pool.snapshot();
+        assert_eq!(snapshot.count, 1);
+        assert_eq!(nonces_of(snapshot.by_sender.values().next().unwrap()), vec![3]);
+    }
+
+    #[test]
+    fn full_transaction_pool_evicts_the_lowest_fee() {
+        let transaction_pool = create_transaction_pool();
+        let state = create_world_state();
+
+        // fill the pool, the lowest fee is the last transaction of bob
+        for (sender, fee, nonce) in [(ALICE, 5, 0), (ALICE, 5, 1), (BOB, 3, 0), (BOB, 2, 1)] {
+            transaction_pool
+                .add_transaction(create_signed_transaction(sender, 1, fee, nonce), &state)
+                .unwrap();
+        }
+
+        // a transaction that does not pay more is rejected
+        let result =
+            transaction_pool.add_transaction(create_signed_transaction(CAROL, 1, 2, 0), &state);
+        assert_eq!(result, Err(TransactionPoolError::PoolFull));
+
+        // a transaction paying more takes the place of the lowest fee
+        transaction_pool
+            .add_transaction(create_signed_transaction(CAROL, 1, 4, 0), &state)
+            .unwrap();
+        let mut fees: Vec<u64> = transaction_pool
+            .pop()
+            .iter()
+            .map(|transaction| transaction.fee)
+            .collect();
+        fees.sort();
+        assert_eq!(fees, vec![3, 4, 5, 5]);
+    }
+
+    #[test]
+    fn full_transaction_pool_evicts_a_low_fee_with_the_transactions_after_it() {
+        let transaction_pool = create_transaction_pool();
+        let state = create_world_state();
+
+        // the lowest fee is the first transaction of alice, which her next one waits for
+        for (sender, fee, nonce) in [(ALICE, 1, 0), (ALICE, 9, 1), (BOB, 5, 0), (BOB, 5, 1)] {
+            transaction_pool
+                .add_transaction(create_signed_transaction(sender, 1, fee, nonce), &state)
+                .unwrap();
+        }
+
+        transaction_pool
+            .add_transaction(create_signed_transaction(CAROL, 1, 4, 0), &state)
+            .unwrap();
+        let snapshot = transaction_pool.snapshot();
+        assert_eq!(snapshot.count, 3);
+        let alice = address_from_public_key(&create_signing_key(ALICE).verifying_key());
+        assert!(!snapshot.by_sender.contains_key(&alice));
+    }
+
+    #[test]
+    fn full_transaction_pool_does_not_evict_the_previous_nonce_of_the_sender() {
+        let transaction_pool = create_transaction_pool();
+        let state = create_world_state();
+
+        // the lowest fee is the last transaction of bob
+        for (sender, fee, nonce) in [(ALICE, 5, 0), (ALICE, 5, 1), (BOB, 3, 0), (BOB, 1, 1)] {
+            transaction_pool
+                .add_transaction(create_signed_transaction(sender, 1, fee, nonce), &state)
+                .unwrap();
+        }
+
+        // the next transaction of bob pays more, but can only evict the ones of other senders
+        let result =
+            transaction_pool.add_transaction(create_signed_transaction(BOB, 1, 4, 2), &state);
+        assert_eq!(result, Err(TransactionPoolError::PoolFull));
+        assert_eq!(transaction_pool.snapshot().count, CAPACITY);
+
+        // paying more than alice evicts her last transaction instead
+        transaction_pool
+            .add_transaction(create_signed_transaction(BOB, 1, 6, 2), &state)
+            .unwrap();
+        let snapshot = transaction_pool.snapshot();
+        assert_eq!(snapshot.count, CAPACITY);
+        let bob = address_from_public_key(&create_signing_key(BOB).verifying_key());
+        assert_eq!(nonces_of(&snapshot.by_sender[&bob]), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn transaction_pool_forgets_expired_transactions() {
+        let transaction_pool = create_transaction_pool();
+        let state = create_world_state();
+
+        transaction_pool
+            .add_transaction(create_mock_transaction(1, 0), &state)
+            .unwrap();
+
+        // nothing expires before the time to live is over
+        let now = Utc::now().timestamp_millis();
+        let mut transactions = transaction_pool.transactions.lock().unwrap();
+        transaction_pool.remove_expired(&mut transactions, now);
+        assert_eq!(transactions.len(), 1);
+
+        // and peeking leaves out what popping would drop
+        for pending in transactions.values_mut().flat_map(|pending| pending.values_mut()) {
+            pending.added_at = now - TTL_MS - 1;
+        }
+        drop(transactions);
+        assert!(transaction_pool.peek_best(&state, usize::MAX, usize::MAX).is_empty());
+
+        let mut transactions = transaction_pool.transactions.lock().unwrap();
+        transaction_pool.remove_expired(&mut transactions, now + TTL_MS + 1);
+        assert!(transactions.is_empty());
+    }
+
+    #[test]
+    fn transaction_pool_snapshot_keeps_transactions() {
+        let transaction_pool = create_transaction_pool();
+        let state = create_world_state();
+
+        for (sender, fee, nonce) in [(ALICE, 1, 0), (ALICE, 2, 1), (BOB, 3, 0)] {
+            transaction_pool
+                .add_transaction(create_signed_transaction(sender, 1, fee, nonce), &state)
+                .unwrap();
+        }
+
+        let snapshot = transaction_pool.snapshot();
+        assert_eq!(snapshot.count, 3);
+        assert_eq!(snapshot.total_fees, 6);
+        assert_eq!(snapshot.by_sender.len(), 2);
+        let alice = address_from_public_key(&create_signing_key(ALICE).verifying_key());
+        assert_eq!(nonces_of(&snapshot.by_sender[&alice]), vec![0, 1]);
+
+        // looking at the pool must not remove anything
+        assert_eq!(transaction_pool.snapshot().count, 3);
+        assert_eq!(transaction_pool.pop().len(), 3);
+    }
+
+    #[test]
+    fn orphaned_transactions_return_to_the_pool() {
+        let transaction_pool = create_transaction_pool();
+        let mut state = create_world_state();
+
+        // nonce 0 is also mined on the new main chain, nonce 1 is not
+        state
+            .apply_transaction(&create_mock_transaction(1, 0))
+            .unwrap();
+        let orphaned = vec![create_mock_transaction(1, 0), create_mock_transaction(1, 1)];
+        transaction_pool.add_orphaned(orphaned, &state);
+
+        assert_eq!(nonces_of(&transaction_pool.pop()), vec![1]);
+    }
 }
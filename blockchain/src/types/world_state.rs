@@ -0,0 +1,361 @@
+use crate::types::block::Block;
+use crate::types::transaction::Transaction;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use thiserror::Error;
+
+pub type AccountId = String;
+
+pub type Balances = HashMap<AccountId, u64>;
+
+pub type Nonces = HashMap<AccountId, u64>;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum WorldStateError {
+    #[error("account `{account}` has balance {balance} but tried to spend {amount}")]
+    InsufficientBalance {
+        account: AccountId,
+        balance: u64,
+        amount: u64,
+    },
+    #[error("balance of account `{0}` would overflow")]
+    BalanceOverflow(AccountId),
+    #[error("amount and fee of a transaction from `{0}` overflow")]
+    CostOverflow(AccountId),
+    #[error("account `{account}` expected nonce {expected} but got {nonce}")]
+    NonceMismatch {
+        account: AccountId,
+        expected: u64,
+        nonce: u64,
+    },
+}
+
+// The balance and the next expected nonce of every account,
+// derived by replaying the blocks of the chain on top of the genesis allocation
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WorldState {
+    balances: Balances,
+    nonces: Nonces,
+}
+
+// Balances and nonces of the accounts a block touched, as they were before the block,
+// so that the block can be taken off the state again during a reorganization
+#[derive(Debug, Clone, Default)]
+pub struct BlockUndo {
+    balances: Vec<(AccountId, Option<u64>)>,
+    nonces: Vec<(AccountId, Option<u64>)>,
+}
+
+impl WorldState {
+    // Creates a world state holding only the genesis allocation
+    pub fn new(genesis_balances: Balances) -> WorldState {
+        WorldState {
+            balances: genesis_balances,
+            nonces: Nonces::new(),
+        }
+    }
+
+    // Returns the balance of an account, accounts never seen before have a zero balance
+    pub fn get_balance(&self, account: &str) -> u64 {
+        self.balances.get(account).copied().unwrap_or_default()
+    }
+
+    // Returns the nonce the next transaction of an account must carry
+    pub fn get_nonce(&self, account: &str) -> u64 {
+        self.nonces.get(account).copied().unwrap_or_default()
+    }
+
+    // Checks that an account holds at least the given amount
+    pub fn check_can_spend(&self, account: &str, amount: u64) -> Result<(), WorldStateError> {
+        let balance = self.get_balance(account);
+        if balance < amount {
+            return Err(WorldStateError::InsufficientBalance {
+                account: account.to_string(),
+                balance,
+                amount,
+            });
+        }
+
+        Ok(())
+    }
+
+    // Moves the amount of a transaction from the sender to the recipient, takes the fee
+    // from the sender and advances its nonce, a coinbase only credits the recipient
+    // Fees are not credited here, the miner collects them through the coinbase
+    // The state is left untouched if the transfer is not valid
+    pub fn apply_transaction(&mut self, transaction: &Transaction) -> Result<(), WorldStateError> {
+        if transaction.is_coinbase() {
+            return self.credit(&transaction.recipient, transaction.amount);
+        }
+
+        let expected_nonce = self.get_nonce(&transaction.sender);
+        if transaction.nonce != expected_nonce {
+            return Err(WorldStateError::NonceMismatch {
+                account: transaction.sender.clone(),
+                expected: expected_nonce,
+                nonce: transaction.nonce,
+            });
+        }
+        let total_cost = transaction
+            .total_cost()
+            .ok_or_else(|| WorldStateError::CostOverflow(transaction.sender.clone()))?;
+        self.check_can_spend(&transaction.sender, total_cost)?;
+        if transaction.sender != transaction.recipient {
+            self.get_balance(&transaction.recipient)
+                .checked_add(transaction.amount)
+                .ok_or_else(|| WorldStateError::BalanceOverflow(transaction.recipient.clone()))?;
+        }
+
+        let sender_balance = self.get_balance(&transaction.sender) - total_cost;
+        self.balances
+            .insert(transaction.sender.clone(), sender_balance);
+
+        let recipient_balance = self.get_balance(&transaction.recipient) + transaction.amount;
+        self.balances
+            .insert(transaction.recipient.clone(), recipient_balance);
+
+        self.nonces
+            .insert(transaction.sender.clone(), expected_nonce + 1);
+
+        Ok(())
+    }
+
+    // Adds newly minted funds to an account
+    fn credit(&mut self, account: &str, amount: u64) -> Result<(), WorldStateError> {
+        let balance = self
+            .get_balance(account)
+            .checked_add(amount)
+            .ok_or_else(|| WorldStateError::BalanceOverflow(account.to_string()))?;
+        self.balances.insert(account.to_string(), balance);
+
+        Ok(())
+    }
+
+    // Applies all the transactions of a block in order, returning what is needed to undo it
+    // The state is left untouched if any transaction is not valid
+    pub fn apply_block(&mut self, block: &Block) -> Result<BlockUndo, WorldStateError> {
+        let mut undo = BlockUndo::default();
+        let mut touched = HashSet::new();
+        for transaction in &block.transactions {
+            for account in [&transaction.sender, &transaction.recipient] {
+                if touched.insert(account.as_str()) {
+                    undo.balances.push((account.clone(), self.balances.get(account).copied()));
+                    undo.nonces.push((account.clone(), self.nonces.get(account).copied()));
+                }
+            }
+        }
+
+        for transaction in &block.transactions {
+            if let Err(error) = self.apply_transaction(transaction) {
+                self.undo_block(&undo);
+                return Err(error);
+            }
+        }
+
+        Ok(undo)
+    }
+
+    // Takes a block back off the state, which must be the state right after the block
+    pub fn undo_block(&mut self, undo: &BlockUndo) {
+        for (account, balance) in &undo.balances {
+            match balance {
+                Some(balance) => self.balances.insert(account.clone(), *balance),
+                None => self.balances.remove(account),
+            };
+        }
+        for (account, nonce) in &undo.nonces {
+            match nonce {
+                Some(nonce) => self.nonces.insert(account.clone(), *nonce),
+                None => self.nonces.remove(account),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_mock_transaction(
+        sender: &str,
+        recipient: &str,
+        amount: u64,
+        nonce: u64,
+    ) -> Transaction {
+        Transaction::new(sender.to_string(), recipient.to_string(), amount, 0, nonce)
+    }
+
+    fn create_world_state() -> WorldState {
+        let mut genesis_balances = Balances::new();
+        genesis_balances.insert("alice.near".to_string(), 10);
+        WorldState::new(genesis_balances)
+    }
+
+    #[test]
+    fn unknown_account_has_zero_balance() {
+        let state = create_world_state();
+
+        assert_eq!(state.get_balance("bob.near"), 0);
+    }
+
+    #[test]
+    fn transfer_moves_balance() {
+        let mut state = create_world_state();
+
+        let transaction = create_mock_transaction("alice.near", "bob.near", 4, 0);
+        state.apply_transaction(&transaction).unwrap();
+
+        assert_eq!(state.get_balance("alice.near"), 6);
+        assert_eq!(state.get_balance("bob.near"), 4);
+        assert_eq!(state.get_nonce("alice.near"), 1);
+        assert_eq!(state.get_nonce("bob.near"), 0);
+    }
+
+    #[test]
+    fn overspending_is_rejected() {
+        let mut state = create_world_state();
+
+        let transaction = create_mock_transaction("alice.near", "bob.near", 11, 0);
+        let result = state.apply_transaction(&transaction);
+
+        assert_eq!(
+            result,
+            Err(WorldStateError::InsufficientBalance {
+                account: "alice.near".to_string(),
+                balance: 10,
+                amount: 11,
+            })
+        );
+        // a rejected transfer must not change any balance
+        assert_eq!(state.get_balance("alice.near"), 10);
+        assert_eq!(state.get_balance("bob.near"), 0);
+    }
+
+    #[test]
+    fn received_funds_can_be_spent_in_the_same_block() {
+        let mut state = create_world_state();
+
+        let block = Block::new(
+            1,
+            0,
+            0,
+            None,
+            vec![
+                create_mock_transaction("alice.near", "bob.near", 10, 0),
+                create_mock_transaction("bob.near", "carol.near", 7, 0),
+            ],
+        );
+        state.apply_block(&block).unwrap();
+
+        assert_eq!(state.get_balance("alice.near"), 0);
+        assert_eq!(state.get_balance("bob.near"), 3);
+        assert_eq!(state.get_balance("carol.near"), 7);
+    }
+
+    #[test]
+    fn rejected_block_leaves_the_state_untouched() {
+        let mut state = create_world_state();
+
+        let block = Block::new(
+            1,
+            0,
+            0,
+            None,
+            vec![
+                create_mock_transaction("alice.near", "bob.near", 4, 0),
+                create_mock_transaction("alice.near", "carol.near", 7, 1),
+            ],
+        );
+        assert!(state.apply_block(&block).is_err());
+
+        assert_eq!(state.get_balance("alice.near"), 10);
+        assert_eq!(state.get_balance("bob.near"), 0);
+        assert_eq!(state.get_nonce("alice.near"), 0);
+    }
+
+    #[test]
+    fn undone_block_restores_the_previous_state() {
+        let mut state = create_world_state();
+        state
+            .apply_transaction(&create_mock_transaction("alice.near", "bob.near", 2, 0))
+            .unwrap();
+        let before = state.clone();
+
+        let block = Block::new(
+            1,
+            0,
+            0,
+            None,
+            vec![
+                Transaction::new_coinbase("miner.near".to_string(), 50, 1),
+                create_mock_transaction("alice.near", "carol.near", 3, 1),
+                create_mock_transaction("bob.near", "alice.near", 1, 0),
+            ],
+        );
+        let undo = state.apply_block(&block).unwrap();
+        assert_eq!(state.get_balance("miner.near"), 50);
+
+        state.undo_block(&undo);
+        assert_eq!(state.balances, before.balances);
+        assert_eq!(state.nonces, before.nonces);
+    }
+
+    #[test]
+    fn replayed_and_skipped_nonces_are_rejected() {
+        let mut state = create_world_state();
+
+        let transaction = create_mock_transaction("alice.near", "bob.near", 1, 0);
+        state.apply_transaction(&transaction).unwrap();
+
+        // the same transaction can not be applied twice
+        let result = state.apply_transaction(&transaction);
+        assert_eq!(
+            result,
+            Err(WorldStateError::NonceMismatch {
+                account: "alice.near".to_string(),
+                expected: 1,
+                nonce: 0,
+            })
+        );
+
+        // nor can a transaction leave a gap
+        let transaction = create_mock_transaction("alice.near", "bob.near", 1, 2);
+        assert!(state.apply_transaction(&transaction).is_err());
+
+        assert_eq!(state.get_balance("alice.near"), 9);
+        assert_eq!(state.get_nonce("alice.near"), 1);
+    }
+
+    #[test]
+    fn coinbase_mints_funds() {
+        let mut state = create_world_state();
+
+        let coinbase = Transaction::new_coinbase("miner.near".to_string(), 50, 1);
+        state.apply_transaction(&coinbase).unwrap();
+
+        assert_eq!(state.get_balance("miner.near"), 50);
+        assert_eq!(state.get_nonce("miner.near"), 0);
+    }
+
+    #[test]
+    fn sender_pays_the_fee() {
+        let mut state = create_world_state();
+
+        let transaction = Transaction::new("alice.near".to_string(), "bob.near".to_string(), 4, 2, 0);
+        state.apply_transaction(&transaction).unwrap();
+
+        assert_eq!(state.get_balance("alice.near"), 4);
+        assert_eq!(state.get_balance("bob.near"), 4);
+
+        // the fee counts towards the balance the sender needs
+        let transaction = Transaction::new("alice.near".to_string(), "bob.near".to_string(), 4, 1, 1);
+        assert!(state.apply_transaction(&transaction).is_err());
+
+        // an amount and a fee that add up past the largest balance are not clamped to it
+        let transaction = Transaction::new("alice.near".to_string(), "bob.near".to_string(), u64::MAX, 1, 1);
+        assert_eq!(
+            state.apply_transaction(&transaction),
+            Err(WorldStateError::CostOverflow("alice.near".to_string()))
+        );
+    }
+}
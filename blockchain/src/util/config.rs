@@ -1,3 +1,7 @@
+use crate::consensus::ConsensusKind;
+use crate::storage::file_storage::SyncPolicy;
+use crate::types::transaction::{Address, PublicKey};
+use crate::types::world_state::Balances;
 use serde::Deserialize;
 use std::env;
 
@@ -10,12 +14,115 @@ use std::path::Path;
 pub struct Config {
     // Networking settings
     pub port: u16,
+    // Bearer token for the admin endpoints, which are disabled when it is missing
+    #[serde(default)]
+    pub admin_token: Option<String>,
+    // Port other nodes connect to
+    #[serde(default = "default_p2p_port")]
+    pub p2p_port: u16,
+    // Addresses ("host:port") of the nodes to connect to on startup,
+    // more peers are discovered through them
+    #[serde(default)]
+    pub peers: Vec<String>,
+    // Only follow the headers of the peers and check transactions with proofs from them,
+    // without mining or keeping the blocks
+    #[serde(default)]
+    pub light_client: bool,
 
     // Miner settings
     pub max_blocks: u64,
     pub max_nonce: u64,
-    pub difficulty: u32,
+    // Number of threads mining in parallel, 0 or missing for one per core
+    #[serde(default)]
+    pub mining_threads: usize,
+    #[serde(default = "default_max_block_transactions")]
+    pub max_block_transactions: usize,
+    #[serde(default = "default_max_block_size")]
+    pub max_block_size: usize,
+    // How many times more hashes than any hash the first blocks need,
+    // their target is adjusted every `retarget_interval` blocks if set
+    // Before 0.2 this was the number of leading zeros of the hash, `n` zeros are `16^n` hashes
+    pub difficulty: u64,
+    #[serde(default)]
+    pub retarget_interval: u64,
+    #[serde(default)]
+    pub target_block_time_ms: u64,
+    // Longest time the miner sleeps without being woken up by a new transaction
     pub tx_waiting_ms: u64,
+    // Executable transactions the miner waits for before mining a block, 0 or 1 to mine any
+    #[serde(default)]
+    pub min_block_transactions: usize,
+    // Longest time pending transactions wait for `min_block_transactions`, 0 or missing for no limit
+    #[serde(default)]
+    pub max_batch_wait_ms: u64,
+    // Address the coinbase pays, the rewards are minted to no one when it is missing
+    #[serde(default)]
+    pub miner_address: Address,
+
+    // Transaction pool settings
+    #[serde(default = "default_pool_capacity")]
+    pub pool_capacity: usize,
+    #[serde(default = "default_pool_max_per_sender")]
+    pub pool_max_per_sender: usize,
+    #[serde(default = "default_pool_ttl_ms")]
+    pub pool_ttl_ms: i64,
+
+    // Storage settings
+    // Directory where the blocks are stored, the chain only lives in memory without it
+    #[serde(default)]
+    pub data_dir: Option<String>,
+    #[serde(default)]
+    pub sync_policy: SyncPolicy,
+
+    // Chain settings
+    #[serde(default)]
+    pub genesis_balances: Balances,
+    // Nothing is minted when it is missing, like before the coinbase existed
+    #[serde(default)]
+    pub block_reward: u64,
+    #[serde(default)]
+    pub halving_interval: u64,
+
+    // Consensus settings
+    // How blocks are sealed, proof-of-work if missing
+    #[serde(default)]
+    pub consensus: ConsensusKind,
+    // Hex encoded public keys of the proof-of-authority signers, in the order they take turns
+    #[serde(default)]
+    pub authorities: Vec<PublicKey>,
+    // Hex encoded secret key this node seals its turns with, following the chain only without it
+    #[serde(default)]
+    pub authority_key: Option<String>,
+
+    // Development settings
+    // Seal a block as soon as a transaction enters the pool or on `POST /dev/mine`,
+    // starting the chain at the easiest target
+    #[serde(default)]
+    pub dev_mode: bool,
+}
+
+fn default_p2p_port() -> u16 {
+    9000
+}
+
+fn default_max_block_transactions() -> usize {
+    100
+}
+
+fn default_max_block_size() -> usize {
+    65_536
+}
+
+fn default_pool_capacity() -> usize {
+    10_000
+}
+
+fn default_pool_max_per_sender() -> usize {
+    64
+}
+
+fn default_pool_ttl_ms() -> i64 {
+    3_600_000
 }
 
 impl Config {
@@ -46,6 +153,25 @@ mod tests {
         assert!(config.difficulty > 0);
     }
 
+    #[test]
+    fn config_without_the_newer_settings_is_read() {
+        let json = r#"{
+            "port": 8000,
+            "max_blocks": 120,
+            "max_nonce": 1000000,
+            "difficulty": 65536,
+            "tx_waiting_ms": 10000
+        }"#;
+
+        let config: Config = serde_json::from_str(json).unwrap();
+
+        assert_eq!(config.p2p_port, 9000);
+        assert_eq!(config.max_block_transactions, 100);
+        assert_eq!(config.pool_capacity, 10_000);
+        assert_eq!(config.block_reward, 0);
+        assert!(config.miner_address.is_empty());
+    }
+
     #[test]
     #[should_panic]
     fn wrong_json_name() {
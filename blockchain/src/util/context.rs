@@ -1,10 +1,15 @@
 use crate::Config;
 
+use crate::p2p::network::Network;
 use crate::types::blockchain::Blockchain;
 use crate::types::transaction_pool::TransactionPool;
+use crate::util::execution::StopSignal;
 
 pub struct Context {
     pub config: Config,
     pub blockchain: Blockchain,
     pub pool: TransactionPool,
+    pub network: Network,
+    // Stops the loops of the node when it shuts down
+    pub stop_signal: StopSignal,
 }
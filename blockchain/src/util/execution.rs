@@ -1,5 +1,8 @@
 use anyhow::Result;
 use crossbeam::thread;
+use log::{error, info};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::time;
 
 pub trait Runnable: Sync + Send {
@@ -17,6 +20,21 @@ pub fn run_in_parallel(things_to_run: Vec<Box<dyn Runnable>>) {
     .unwrap();
 }
 
+// Asks a long running loop to return, the loop checks it between its rounds of work
+// A node shares one signal between its loops, which Ctrl-C stops before the process exits
+#[derive(Debug, Clone, Default)]
+pub struct StopSignal(Arc<AtomicBool>);
+
+impl StopSignal {
+    pub fn stop(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
 // Suspend the execution of the thread by a particular amount of milliseconds
 pub fn sleep_millis(millis: u64) {
     let wait_duration = time::Duration::from_millis(millis);
@@ -24,8 +42,19 @@ pub fn sleep_millis(millis: u64) {
 }
 
 // Quit the program when the user inputs Ctrl-C
-pub fn set_ctrlc_handler() {
+// The loops are stopped first, then `shutdown` runs before the process exits,
+// which fails if `shutdown` does
+pub fn set_ctrlc_handler(
+    stop_signal: StopSignal,
+    shutdown: impl Fn() -> Result<()> + Send + 'static,
+) {
     ctrlc::set_handler(move || {
+        info!("shutting down");
+        stop_signal.stop();
+        if let Err(error) = shutdown() {
+            error!("could not shut down cleanly: {}", error);
+            std::process::exit(1);
+        }
         std::process::exit(0);
     })
     .expect("Error setting Ctrl-C handler");
@@ -2,3 +2,5 @@ pub(crate) mod config;
 pub(crate) mod context;
 pub(crate) mod execution;
 pub(crate) mod response;
+#[cfg(test)]
+pub(crate) mod test_helpers;
@@ -0,0 +1,205 @@
+use crate::consensus::proof_of_work::ProofOfWork;
+use crate::consensus::SyncedConsensus;
+use crate::p2p::network::Network;
+use crate::types::block::{Block, BlockHash, BlockHeader};
+use crate::types::blockchain::{Blockchain, ChainParams};
+use crate::types::target::{CompactTarget, Target};
+use crate::types::transaction::{address_from_public_key, Transaction};
+use crate::types::transaction_pool::TransactionPool;
+use crate::types::world_state::Balances;
+use crate::util::execution::sleep_millis;
+use ed25519_dalek::SigningKey;
+use std::sync::Arc;
+
+// Nearly every hash meets the easiest target, so blocks are found on the first nonces
+pub const EASIEST_TARGET: CompactTarget = 0x20ff_ffff;
+
+// About one hash in 16 is below this target
+pub const EASY_TARGET: CompactTarget = 0x200f_ffff;
+
+// Only hashes of 0 and 1 meet this target, so blocks are never found
+pub const IMPOSSIBLE_TARGET: CompactTarget = 0x0101_0000;
+
+// Reward of the blocks of the test nodes
+pub const BLOCK_REWARD: u64 = 50;
+
+// Genesis balance of the address of `create_signing_key(1)` on the test nodes
+pub const FUNDED_BALANCE: u64 = 10;
+
+// Proof-of-work engine for chains whose blocks the tests mine themselves
+pub fn proof_of_work() -> SyncedConsensus {
+    Arc::new(ProofOfWork::new(1, 1))
+}
+
+// Parameters of a chain that keeps its initial target, never halves the reward
+// and does not limit the blocks
+pub fn chain_params(initial_target: CompactTarget, block_reward: u64) -> ChainParams {
+    ChainParams {
+        initial_target,
+        retarget_interval: 0,
+        target_block_time_ms: 0,
+        block_reward,
+        halving_interval: 0,
+        max_block_transactions: usize::MAX,
+        max_block_size: usize::MAX,
+    }
+}
+
+// The same seed always gives the same key, so its address can be funded in the genesis block
+pub fn create_signing_key(seed: u8) -> SigningKey {
+    SigningKey::from_bytes(&[seed; 32])
+}
+
+// Pool small enough for the tests to fill
+pub fn create_pool() -> TransactionPool {
+    TransactionPool::new(10, 10, 60_000)
+}
+
+// Chain, pool and network of a node the tests run
+#[derive(Debug, Clone)]
+pub struct TestNode {
+    pub blockchain: Blockchain,
+    pub pool: TransactionPool,
+    pub network: Network,
+}
+
+impl TestNode {
+    // By default the node mines with proof-of-work at the easiest target for `BLOCK_REWARD`,
+    // the address of `create_signing_key(1)` holds `FUNDED_BALANCE`, and it has no peers
+    pub fn builder() -> TestNodeBuilder {
+        let mut genesis_balances = Balances::new();
+        genesis_balances.insert(
+            address_from_public_key(&create_signing_key(1).verifying_key()),
+            FUNDED_BALANCE,
+        );
+
+        TestNodeBuilder {
+            consensus: proof_of_work(),
+            params: chain_params(EASIEST_TARGET, BLOCK_REWARD),
+            genesis_balances,
+            listen_port: 0,
+            peers: vec![],
+        }
+    }
+}
+
+pub struct TestNodeBuilder {
+    consensus: SyncedConsensus,
+    params: ChainParams,
+    genesis_balances: Balances,
+    listen_port: u16,
+    peers: Vec<String>,
+}
+
+impl TestNodeBuilder {
+    pub fn consensus(mut self, consensus: SyncedConsensus) -> TestNodeBuilder {
+        self.consensus = consensus;
+        self
+    }
+
+    pub fn params(mut self, params: ChainParams) -> TestNodeBuilder {
+        self.params = params;
+        self
+    }
+
+    pub fn target(mut self, initial_target: CompactTarget) -> TestNodeBuilder {
+        self.params.initial_target = initial_target;
+        self
+    }
+
+    // Port the node tells its peers to connect to, the tests bind the listener themselves
+    pub fn listen_port(mut self, listen_port: u16) -> TestNodeBuilder {
+        self.listen_port = listen_port;
+        self
+    }
+
+    pub fn peers(mut self, peers: Vec<String>) -> TestNodeBuilder {
+        self.peers = peers;
+        self
+    }
+
+    // Builds the chain alone, for the tests that do not need a pool or a network
+    pub fn build_blockchain(&self) -> Blockchain {
+        Blockchain::new(
+            self.consensus.clone(),
+            self.params.clone(),
+            self.genesis_balances.clone(),
+        )
+    }
+
+    // Builds the node around a chain built beforehand, such as one already holding blocks
+    pub fn build_with(self, blockchain: Blockchain) -> TestNode {
+        let pool = create_pool();
+        let network = Network::new(self.listen_port, self.peers, blockchain.clone(), pool.clone());
+
+        TestNode {
+            blockchain,
+            pool,
+            network,
+        }
+    }
+
+    pub fn build(self) -> TestNode {
+        let blockchain = self.build_blockchain();
+        self.build_with(blockchain)
+    }
+}
+
+// Returns whether a hash, read as a number, does not exceed a compact target
+// A target that does not fit in 256 bits is never met
+pub fn meets_target(hash: &BlockHash, target: CompactTarget) -> bool {
+    Target::from_compact(target).is_some_and(|target| target.is_met_by(hash))
+}
+
+// Increments the nonce until the hash meets the target of the block
+pub fn mine(mut block: Block) -> Block {
+    loop {
+        block.header.hash = block.header.calculate_hash();
+        if meets_target(&block.header.hash, block.header.target) {
+            return block;
+        }
+        block.header.nonce += 1;
+    }
+}
+
+// Mines a block on top of `parent` at the easiest target, with a coinbase paying the miner
+// the given reward and the fees of the transactions
+pub fn mine_block_on(
+    parent: &BlockHeader,
+    miner: &str,
+    reward: u64,
+    transactions: Vec<Transaction>,
+) -> Block {
+    let index = parent.index + 1;
+    let fees: u64 = transactions.iter().map(|transaction| transaction.fee).sum();
+    let coinbase = Transaction::new_coinbase(miner.to_string(), reward + fees, index);
+    let mut block_transactions = vec![coinbase];
+    block_transactions.extend(transactions);
+
+    mine(Block::new(
+        index,
+        EASIEST_TARGET,
+        0,
+        Some(parent.hash.clone()),
+        block_transactions,
+    ))
+}
+
+// Mines the block following the tip of the chain, paying the reward the chain expects
+pub fn mine_on_tip(blockchain: &Blockchain, miner: &str, transactions: Vec<Transaction>) -> Block {
+    let parent = blockchain.get_last_block().header;
+    let reward = blockchain.get_block_reward(parent.index + 1);
+
+    mine_block_on(&parent, miner, reward, transactions)
+}
+
+// Polls a condition until it holds, failing the test after a few seconds
+pub fn wait_for(condition: impl Fn() -> bool) {
+    for _ in 0..100 {
+        if condition() {
+            return;
+        }
+        sleep_millis(50);
+    }
+    panic!("condition not reached in time");
+}
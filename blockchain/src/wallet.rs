@@ -0,0 +1,55 @@
+use crate::types::merkle::{verify_proof, InclusionProof};
+use crate::types::transaction::{address_from_public_key, Transaction};
+use anyhow::{anyhow, Result};
+use ed25519_dalek::SigningKey;
+use rand::rngs::OsRng;
+
+const USAGE: &str = "usage: blockchain keygen | blockchain sign <secret_key> <recipient> <amount> <fee> <nonce> \
+                     | blockchain verify-proof <proof>";
+
+// Offline helpers to create keys and sign transactions for the `/tx/new` endpoint,
+// and to check the inclusion proofs returned by `/tx/{hash}/proof`
+pub fn run_wallet_command(args: &[String]) -> Result<()> {
+    match args {
+        [command] if command == "keygen" => {
+            let signing_key = SigningKey::generate(&mut OsRng);
+            println!("secret key: {}", hex::encode(signing_key.to_bytes()));
+            println!(
+                "address: {}",
+                address_from_public_key(&signing_key.verifying_key())
+            );
+            Ok(())
+        }
+        [command, secret_key, recipient, amount, fee, nonce] if command == "sign" => {
+            let signing_key = parse_signing_key(secret_key)?;
+            let amount = amount.parse()?;
+            let fee = fee.parse()?;
+            let nonce = nonce.parse()?;
+            let transaction =
+                Transaction::new_signed(&signing_key, recipient.clone(), amount, fee, nonce);
+            println!("{}", serde_json::to_string(&transaction)?);
+            Ok(())
+        }
+        [command, proof] if command == "verify-proof" => {
+            let proof: InclusionProof = serde_json::from_str(proof)?;
+            if !verify_proof(&proof) {
+                return Err(anyhow!("the proof is invalid"));
+            }
+            println!(
+                "transaction {} is included in block {} with hash {}",
+                proof.transaction.hash(),
+                proof.header.index,
+                proof.header.hash
+            );
+            Ok(())
+        }
+        _ => Err(anyhow!(USAGE)),
+    }
+}
+
+fn parse_signing_key(secret_key: &str) -> Result<SigningKey> {
+    let bytes = <[u8; 32]>::try_from(hex::decode(secret_key)?)
+        .map_err(|_| anyhow!("secret key must be 32 bytes long"))?;
+
+    Ok(SigningKey::from_bytes(&bytes))
+}